@@ -0,0 +1,111 @@
+use std::sync::{Arc, Mutex};
+
+use errors::{DungeonError, DungeonResult};
+
+use crate::{
+    change_feed::WriteBatch,
+    value::{TimeStampedValue, Value},
+    Chest,
+};
+
+/// Chest that can be shared between tokio tasks. Every call runs on the blocking thread pool, so
+/// file I/O, flushes and merges never stall the executor threads. Calls still run one at a time
+#[derive(Clone)]
+pub struct AsyncChest {
+    chest: Arc<Mutex<Chest>>,
+}
+
+impl AsyncChest {
+    pub fn new(chest: Chest) -> Self {
+        Self {
+            chest: Arc::new(Mutex::new(chest)),
+        }
+    }
+    /// Runs `f` on the blocking thread pool once the chest is free
+    pub async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Chest) -> DungeonResult<T> + Send + 'static,
+    ) -> DungeonResult<T> {
+        let chest = self.chest.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut chest = chest
+                .lock()
+                .map_err(|_| DungeonError::new("Chest lock is poisoned"))?;
+            f(&mut chest)
+        })
+        .await
+        .map_err(|_| DungeonError::new("Could not run chest task"))?
+    }
+    pub async fn get(&self, key: &str) -> DungeonResult<Option<TimeStampedValue>> {
+        let key = key.to_owned();
+        self.run(move |chest| chest.get(&key)).await
+    }
+    pub async fn set(&self, key: &str, value: Value) -> DungeonResult<()> {
+        let key = key.to_owned();
+        self.run(move |chest| chest.set(&key, value)).await
+    }
+    pub async fn delete(&self, key: &str) -> DungeonResult<()> {
+        let key = key.to_owned();
+        self.run(move |chest| chest.delete(&key)).await
+    }
+    pub async fn delete_range(&self, start: &str, end: &str) -> DungeonResult<()> {
+        let (start, end) = (start.to_owned(), end.to_owned());
+        self.run(move |chest| chest.delete_range(&start, &end))
+            .await
+    }
+    pub async fn merge(&self, key: &str, operand: Value) -> DungeonResult<()> {
+        let key = key.to_owned();
+        self.run(move |chest| chest.merge(&key, operand)).await
+    }
+    pub async fn write_batch(&self, batch: WriteBatch) -> DungeonResult<()> {
+        self.run(move |chest| chest.write_batch(batch)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        file_system::MemoryFileSystem, filter::bloom::BloomFilter, options::ChestOptions,
+        value::Value, Chest,
+    };
+
+    use super::AsyncChest;
+
+    #[tokio::test]
+    async fn test_concurrent_writes() {
+        let options = ChestOptions {
+            flush_size: 16,
+            max_sstable_count: 2,
+            file_system: Arc::new(MemoryFileSystem::new()),
+            ..Default::default()
+        };
+        let chest =
+            Chest::with_options("/chest", options, Box::new(BloomFilter::default())).unwrap();
+        let chest = AsyncChest::new(chest);
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let chest = chest.clone();
+                tokio::spawn(async move {
+                    for i in 0..32 {
+                        let key = format!("{task}:{i}");
+                        chest.set(&key, Value::Integer(i)).await.unwrap();
+                    }
+                    chest.merge(&format!("{task}:0"), Value::Integer(5)).await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        chest.delete("7:1").await.unwrap();
+        assert_eq!(
+            chest.get("3:0").await.unwrap().unwrap().value,
+            Value::Integer(5)
+        );
+        assert!(chest.get("7:1").await.unwrap().is_none());
+        let count = chest.run(|chest| chest.count()).await.unwrap();
+        assert_eq!(count, 255);
+    }
+}
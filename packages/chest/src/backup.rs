@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+use errors::{DungeonError, DungeonResult};
+
+use crate::{
+    file_system::{sync_parent, FileSystem},
+    ss_table::SSTable,
+    value_log::{self, VALUE_LOG_EXTENSION},
+};
+
+/// Files copied into and removed from a backup directory by `Chest::backup`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BackupReport {
+    pub copied_files: usize,
+    pub removed_files: usize,
+}
+
+/// Creates `target_dir`, refusing to write into a directory that already has files in it
+pub fn create_empty_dir(fs: &dyn FileSystem, target_dir: &Path) -> DungeonResult<()> {
+    if fs.is_dir(target_dir) {
+        let entries = fs
+            .read_dir(target_dir)
+            .map_err(|_| DungeonError::new("Could not read checkpoint dir"))?;
+        if !entries.is_empty() {
+            return Err(DungeonError::new("Checkpoint dir is not empty"));
+        }
+        return Ok(());
+    }
+    fs.create_dir_all(target_dir)
+        .map_err(|_| DungeonError::new("Could not create checkpoint dir"))
+}
+
+/// Hard links `source` into `target`, copying it when the link can't be made, for example across
+/// file systems
+pub fn link_or_copy(fs: &dyn FileSystem, source: &Path, target: &Path) -> DungeonResult<()> {
+    if fs.hard_link(source, target).is_ok() {
+        return Ok(());
+    }
+    copy_file(fs, source, target)
+}
+
+/// Copies through a temporary file, so an interrupted copy never leaves a partial file under the
+/// final name
+pub fn copy_file(fs: &dyn FileSystem, source: &Path, target: &Path) -> DungeonResult<()> {
+    let tmp_path = target.with_extension("tmp");
+    fs.copy(source, &tmp_path)
+        .map_err(|_| DungeonError::new("Could not copy file"))?;
+    fs.sync(&tmp_path)
+        .map_err(|_| DungeonError::new("Could not copy file"))?;
+    fs.rename(&tmp_path, target)
+        .map_err(|_| DungeonError::new("Could not copy file"))?;
+    sync_parent(fs, target).map_err(|_| DungeonError::new("Could not copy file"))
+}
+
+/// Data file first and index last, since a table without its index file is ignored when opening
+pub fn table_files(sstable: &SSTable) -> [PathBuf; 2] {
+    [sstable.get_data_file_path(), sstable.get_index_file_path()]
+}
+
+/// Whether the backed up index file matches the live one. Collecting value logs rewrites tables
+/// under the same name, so the name alone doesn't tell
+pub fn is_backed_up(fs: &dyn FileSystem, index_file_path: &Path, backup_index_path: &Path) -> bool {
+    match (fs.read(index_file_path), fs.read(backup_index_path)) {
+        (Ok(live), Ok(backed_up)) => live == backed_up,
+        _ => false,
+    }
+}
+
+/// Removes the tables in `backup_dir` that aren't in `live_names` anymore, index first so a
+/// half removed table is never opened. Returns the amount of removed files
+pub fn remove_stale_tables(
+    fs: &dyn FileSystem,
+    backup_dir: &Path,
+    live_names: &[String],
+) -> DungeonResult<usize> {
+    let files = fs
+        .read_dir(backup_dir)
+        .map_err(|_| DungeonError::new("Could not read backup dir"))?;
+    let mut removed = 0;
+    for file_path in files {
+        if file_path.extension().and_then(|ext| ext.to_str()) != Some("index") {
+            continue;
+        }
+        let Some(name) = file_path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if live_names.iter().any(|live| live == name) {
+            continue;
+        }
+        fs.remove_file(&file_path)
+            .map_err(|_| DungeonError::new("Could not remove stale index file"))?;
+        removed += 1;
+        let data_file_path = file_path.with_extension("chest");
+        if fs.is_file(&data_file_path) {
+            fs.remove_file(&data_file_path)
+                .map_err(|_| DungeonError::new("Could not remove stale data file"))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Removes the value logs in `backup_dir` that aren't in `live_logs`, once no backed up table
+/// points at them. Returns the amount of removed files
+pub fn remove_stale_logs(
+    fs: &dyn FileSystem,
+    backup_dir: &Path,
+    live_logs: &[String],
+) -> DungeonResult<usize> {
+    let mut removed = 0;
+    for log in value_log::log_names(fs, backup_dir)? {
+        if live_logs.contains(&log) {
+            continue;
+        }
+        fs.remove_file(&backup_dir.join(&log).with_extension(VALUE_LOG_EXTENSION))
+            .map_err(|_| DungeonError::new("Could not remove stale value log"))?;
+        removed += 1;
+    }
+    Ok(removed)
+}
@@ -0,0 +1,120 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use serde::{Deserialize, Serialize};
+
+use crate::value::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation {
+    Set,
+    Delete,
+    Merge,
+    /// Deletes every key from `ChangeEvent::key` up to `ChangeEvent::end`, see
+    /// `Chest::delete_range`
+    DeleteRange,
+}
+
+/// A committed write of a single key
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// Increases by one with every committed write, see `Chest::subscribe_from`
+    pub sequence: u64,
+    pub key: String,
+    pub operation: Operation,
+    /// Value right before the write, None when the key had no value
+    pub old: Option<Value>,
+    /// Value right after the write, with merge operands applied. None after a delete
+    pub new: Option<Value>,
+    pub timestamp: u128,
+    /// End of the deleted range, excluded from it. Only set for `Operation::DeleteRange`, whose
+    /// events have no old or new value
+    #[serde(default)]
+    pub end: Option<String>,
+}
+
+impl ChangeEvent {
+    /// Whether the write may have changed keys starting with `prefix`
+    pub fn touches(&self, prefix: &str) -> bool {
+        match &self.end {
+            None => self.key.starts_with(prefix),
+            Some(end) => {
+                (self.key.as_str() <= prefix || self.key.starts_with(prefix))
+                    && end.as_str() > prefix
+            }
+        }
+    }
+}
+
+/// Writes committed together by `Chest::write_batch`. They are applied in the order they were
+/// added, all of them or none
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) writes: Vec<(String, Operation, Value)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn set(&mut self, key: &str, value: Value) -> &mut Self {
+        self.writes.push((key.to_owned(), Operation::Set, value));
+        self
+    }
+    pub fn delete(&mut self, key: &str) -> &mut Self {
+        self.writes
+            .push((key.to_owned(), Operation::Delete, Value::Null));
+        self
+    }
+    /// Deletes every key in `start..end`, see `Chest::delete_range`
+    pub fn delete_range(&mut self, start: &str, end: &str) -> &mut Self {
+        self.writes.push((
+            start.to_owned(),
+            Operation::DeleteRange,
+            Value::String(end.to_owned()),
+        ));
+        self
+    }
+    pub fn merge(&mut self, key: &str, operand: Value) -> &mut Self {
+        self.writes
+            .push((key.to_owned(), Operation::Merge, operand));
+        self
+    }
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+/// Channels of every subscription, dropped once their receiver is gone
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers {
+    channels: Vec<(String, Sender<ChangeEvent>)>,
+}
+
+impl Subscribers {
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+    /// Subscribes to the keys starting with `prefix`, sending `backlog` first
+    pub fn add(&mut self, prefix: &str, backlog: Vec<ChangeEvent>) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        for event in backlog {
+            if event.touches(prefix) {
+                // The receiver is still in scope, so it can't have hung up
+                let _ = sender.send(event);
+            }
+        }
+        self.channels.push((prefix.to_owned(), sender));
+        receiver
+    }
+    pub fn publish(&mut self, events: &[ChangeEvent]) {
+        self.channels.retain(|(prefix, sender)| {
+            events
+                .iter()
+                .filter(|event| event.touches(prefix))
+                .all(|event| sender.send(event.clone()).is_ok())
+        });
+    }
+}
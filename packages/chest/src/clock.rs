@@ -0,0 +1,104 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use errors::{DungeonError, DungeonResult};
+
+use crate::file_system::{write_atomic, FileSystem};
+
+const CLOCK_FILE_NAME: &str = "CLOCK";
+
+/// Hybrid logical clock handing out strictly increasing timestamps, in nanoseconds since the unix
+/// epoch. It follows the wall clock while it moves forward and counts up from the last issued
+/// timestamp when it doesn't, so a wall clock jumping backwards can't reorder writes
+#[derive(Debug)]
+pub struct HybridLogicalClock {
+    last: u128,
+    file_path: PathBuf,
+    fs: Arc<dyn FileSystem>,
+}
+
+pub fn wall_clock_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0)
+}
+
+impl HybridLogicalClock {
+    /// Loads the last timestamp persisted in `dir_path`, if any
+    pub fn load(fs: Arc<dyn FileSystem>, dir_path: &Path) -> DungeonResult<Self> {
+        let file_path = dir_path.join(CLOCK_FILE_NAME);
+        let last = if fs.is_file(&file_path) {
+            let data = fs
+                .read(&file_path)
+                .map_err(|_| DungeonError::new("Could not read clock file"))?;
+            String::from_utf8_lossy(&data)
+                .trim()
+                .parse()
+                .map_err(|_| DungeonError::new("Could not parse clock file"))?
+        } else {
+            0
+        };
+        Ok(Self {
+            last,
+            file_path,
+            fs,
+        })
+    }
+    pub fn tick(&mut self) -> u128 {
+        self.last = wall_clock_nanos().max(self.last + 1);
+        self.last
+    }
+    /// Makes sure every future tick is greater than `timestamp`
+    pub fn observe(&mut self, timestamp: u128) {
+        self.last = self.last.max(timestamp);
+    }
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+    /// Saves the last issued timestamp, so a restarted chest never issues it again
+    pub fn persist(&self) -> DungeonResult<()> {
+        write_atomic(
+            self.fs.as_ref(),
+            &self.file_path.with_extension("tmp"),
+            &self.file_path,
+            self.last.to_string().as_bytes(),
+        )
+        .map_err(|_| DungeonError::new("Could not write clock file"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use super::{wall_clock_nanos, HybridLogicalClock};
+    use crate::file_system::MemoryFileSystem;
+
+    #[test]
+    fn test_ticks_increase() {
+        let mut clock =
+            HybridLogicalClock::load(Arc::new(MemoryFileSystem::new()), Path::new("/chest"))
+                .unwrap();
+        let mut last = clock.tick();
+        for _ in 0..1000 {
+            let next = clock.tick();
+            assert!(next > last);
+            last = next;
+        }
+    }
+    #[test]
+    fn test_wall_clock_behind() {
+        let mut clock =
+            HybridLogicalClock::load(Arc::new(MemoryFileSystem::new()), Path::new("/chest"))
+                .unwrap();
+        // Same as the wall clock jumping an hour backwards after the last tick
+        let ahead = wall_clock_nanos() + 3_600_000_000_000;
+        clock.observe(ahead);
+        assert_eq!(clock.tick(), ahead + 1);
+        assert_eq!(clock.tick(), ahead + 2);
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    io::{self, Write},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use errors::{DungeonError, DungeonResult};
+
+use crate::ss_table::SSTable;
+
+/// Paces the writes of compactions to `ChestOptions::compaction_rate`, and holds them while
+/// compaction is paused
+#[derive(Debug)]
+pub(crate) struct CompactionControl {
+    bytes_per_second: Option<u64>,
+    state: Mutex<ControlState>,
+    resumed: Condvar,
+}
+
+#[derive(Debug)]
+struct ControlState {
+    paused: bool,
+    /// Set while the chest waits for a running compaction or runs a manual one, which then
+    /// ignores the pause
+    draining: bool,
+    /// Set once the chest is dropped, letting the running compaction finish at full speed
+    shut_down: bool,
+    /// Bytes that can be written right away. Goes negative after a write larger than that, which
+    /// the next writes wait out
+    available: f64,
+    refilled: Instant,
+}
+
+impl CompactionControl {
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        Self {
+            bytes_per_second,
+            state: Mutex::new(ControlState {
+                paused: false,
+                draining: false,
+                shut_down: false,
+                available: bytes_per_second.unwrap_or(0) as f64,
+                refilled: Instant::now(),
+            }),
+            resumed: Condvar::new(),
+        }
+    }
+    fn state(&self) -> MutexGuard<'_, ControlState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    pub fn is_paused(&self) -> bool {
+        self.state().paused
+    }
+    pub fn set_paused(&self, paused: bool) {
+        self.state().paused = paused;
+        self.resumed.notify_all();
+    }
+    pub fn set_draining(&self, draining: bool) {
+        self.state().draining = draining;
+        self.resumed.notify_all();
+    }
+    pub fn shut_down(&self) {
+        self.state().shut_down = true;
+        self.resumed.notify_all();
+    }
+    /// Blocks while compaction is paused, then until writing `bytes` keeps to the rate
+    pub fn acquire(&self, bytes: usize) {
+        let mut state = self.state();
+        while state.paused && !state.draining && !state.shut_down {
+            state = self
+                .resumed
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        let Some(rate) = self.bytes_per_second.filter(|_| !state.shut_down) else {
+            return;
+        };
+        let rate = rate.max(1) as f64;
+        let now = Instant::now();
+        let refill = now.duration_since(state.refilled).as_secs_f64() * rate;
+        state.available = (state.available + refill).min(rate) - bytes as f64;
+        state.refilled = now;
+        if state.available < 0.0 {
+            let wait = Duration::from_secs_f64(-state.available / rate);
+            drop(state);
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Writer that goes through `CompactionControl::acquire` before every write
+pub(crate) struct Throttled<W> {
+    inner: W,
+    control: Arc<CompactionControl>,
+}
+
+impl<W> Throttled<W> {
+    pub fn new(inner: W, control: Arc<CompactionControl>) -> Self {
+        Self { inner, control }
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.control.acquire(buf.len());
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Merge running on its own thread. The merged tables stay live until its result is installed
+pub(crate) struct CompactionJob {
+    handle: JoinHandle<DungeonResult<(SSTable, Duration)>>,
+}
+
+impl CompactionJob {
+    pub fn spawn(merge: impl FnOnce() -> DungeonResult<SSTable> + Send + 'static) -> Self {
+        let handle = thread::spawn(move || {
+            let started = Instant::now();
+            let merged = merge()?;
+            Ok((merged, started.elapsed()))
+        });
+        Self { handle }
+    }
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+    /// Waits for the merge, returning the merged table and how long the merge took
+    pub fn join(self) -> DungeonResult<(SSTable, Duration)> {
+        self.handle
+            .join()
+            .map_err(|_| DungeonError::new("Compaction thread panicked"))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::CompactionControl;
+
+    #[test]
+    fn test_rate_limit() {
+        let control = CompactionControl::new(Some(1000));
+        let started = Instant::now();
+        // The first second worth of bytes goes through right away
+        control.acquire(1000);
+        assert!(started.elapsed() < Duration::from_millis(100));
+        control.acquire(200);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        let unlimited = CompactionControl::new(None);
+        let started = Instant::now();
+        unlimited.acquire(usize::MAX);
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_pause() {
+        let control = Arc::new(CompactionControl::new(None));
+        control.set_paused(true);
+        let writer = {
+            let control = control.clone();
+            thread::spawn(move || control.acquire(1))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!writer.is_finished());
+        control.set_paused(false);
+        writer.join().unwrap();
+    }
+}
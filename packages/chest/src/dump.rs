@@ -0,0 +1,74 @@
+use std::io::{BufRead, Write};
+
+use errors::{DungeonError, DungeonResult};
+use serde::{Deserialize, Serialize};
+
+use crate::value::Value;
+
+/// Amount of imported entries written to each sstable
+pub const IMPORT_TABLE_SIZE: usize = 65536;
+
+/// Portable encodings for `Chest::export` and `Chest::import`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One JSON object per line
+    #[default]
+    JsonLines,
+    /// MessagePack encoded entries written back to back
+    MessagePack,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DumpEntry {
+    pub key: String,
+    pub value: Value,
+}
+
+impl DumpFormat {
+    pub fn write_entry(&self, w: &mut impl Write, entry: &DumpEntry) -> DungeonResult<()> {
+        match self {
+            DumpFormat::JsonLines => {
+                serde_json::to_writer(&mut *w, entry)
+                    .map_err(|_| DungeonError::new("Could not write dump entry"))?;
+                w.write_all(b"\n")
+                    .map_err(|_| DungeonError::new("Could not write dump entry"))?;
+            }
+            DumpFormat::MessagePack => {
+                rmp_serde::encode::write(w, entry)
+                    .map_err(|_| DungeonError::new("Could not write dump entry"))?;
+            }
+        }
+        Ok(())
+    }
+    /// Reads the next entry, or None once the dump is over
+    pub fn read_entry(&self, r: &mut impl BufRead) -> DungeonResult<Option<DumpEntry>> {
+        match self {
+            DumpFormat::JsonLines => loop {
+                let mut line = String::new();
+                let read = r
+                    .read_line(&mut line)
+                    .map_err(|_| DungeonError::new("Could not read dump file"))?;
+                if read == 0 {
+                    return Ok(None);
+                }
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry = serde_json::from_str(&line)
+                    .map_err(|_| DungeonError::new("Could not parse dump entry"))?;
+                return Ok(Some(entry));
+            },
+            DumpFormat::MessagePack => {
+                let remaining = r
+                    .fill_buf()
+                    .map_err(|_| DungeonError::new("Could not read dump file"))?;
+                if remaining.is_empty() {
+                    return Ok(None);
+                }
+                let entry = rmp_serde::decode::from_read(r)
+                    .map_err(|_| DungeonError::new("Could not parse dump entry"))?;
+                Ok(Some(entry))
+            }
+        }
+    }
+}
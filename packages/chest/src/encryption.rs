@@ -0,0 +1,747 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use errors::{DungeonError, DungeonResult};
+
+use crate::{
+    file_system::{FileSystem, MappedFile},
+    options::ChestOptions,
+};
+
+/// Starts every encrypted file. Files without it are only read, as they are, when plaintext is
+/// allowed, see `ChestOptions::allow_unencrypted`
+const MAGIC: &[u8; 8] = b"DGNCRYPT";
+const NONCE_PREFIX_SIZE: usize = 16;
+/// Magic, key id and nonce prefix
+const HEADER_SIZE: usize = MAGIC.len() + 4 + NONCE_PREFIX_SIZE;
+/// Plaintext bytes sealed together. Reads decrypt whole blocks
+const BLOCK_SIZE: usize = 4096;
+const TAG_SIZE: usize = 16;
+const SEALED_BLOCK_SIZE: usize = BLOCK_SIZE + TAG_SIZE;
+/// Starts every encrypted file written through `FileSystem::append`, followed by the key id and
+/// a random file id. Every append is sealed on its own as a frame of its sealed length, a random
+/// nonce and the sealed bytes
+const APPEND_MAGIC: &[u8; 8] = b"DGNCRAPP";
+const FILE_ID_SIZE: usize = 16;
+const APPEND_HEADER_SIZE: usize = APPEND_MAGIC.len() + 4 + FILE_ID_SIZE;
+const NONCE_SIZE: usize = 24;
+
+/// Where the keys of an encrypted chest come from. Either way they are a list of `<id>:<key>`
+/// entries separated by commas or new lines, where `<key>` is 32 bytes in hex. The first entry
+/// encrypts new files, the others are only used to read files written before a key rotation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeySource {
+    /// Path of a key file, read through `ChestOptions::file_system`
+    File(PathBuf),
+    /// Name of an environment variable
+    Env(String),
+}
+
+/// Keys of an encrypted chest by id
+#[derive(Clone)]
+pub struct Keyring {
+    active: u32,
+    keys: BTreeMap<u32, XChaCha20Poly1305>,
+}
+
+impl Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("active", &self.active)
+            .field("ids", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Keyring {
+    pub fn load(source: &KeySource, fs: &dyn FileSystem) -> DungeonResult<Self> {
+        let text = match source {
+            KeySource::File(path) => String::from_utf8(
+                fs.read(path)
+                    .map_err(|_| DungeonError::new("Could not read key file"))?,
+            )
+            .map_err(|_| DungeonError::new("Could not parse key file"))?,
+            KeySource::Env(name) => {
+                std::env::var(name).map_err(|_| DungeonError::new("Could not read key variable"))?
+            }
+        };
+        Self::parse(&text)
+    }
+    pub fn parse(text: &str) -> DungeonResult<Self> {
+        let mut active = None;
+        let mut keys = BTreeMap::new();
+        for entry in text.split([',', '\n']).map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+            let (id, key) = entry
+                .split_once(':')
+                .ok_or(DungeonError::new("Key entries must look like <id>:<key>"))?;
+            let id: u32 = id
+                .trim()
+                .parse()
+                .map_err(|_| DungeonError::new("Could not parse key id"))?;
+            let key =
+                hex::decode(key.trim()).map_err(|_| DungeonError::new("Could not parse key"))?;
+            let cipher = XChaCha20Poly1305::new_from_slice(&key)
+                .map_err(|_| DungeonError::new("Keys must be 32 bytes long"))?;
+            if keys.insert(id, cipher).is_some() {
+                return Err(DungeonError::new("Key ids must be unique"));
+            }
+            active.get_or_insert(id);
+        }
+        let active = active.ok_or(DungeonError::new("Keyring has no keys"))?;
+        Ok(Self { active, keys })
+    }
+    fn cipher(&self, id: u32) -> io::Result<&XChaCha20Poly1305> {
+        self.keys
+            .get(&id)
+            .ok_or_else(|| io::Error::other(format!("Unknown encryption key {id}")))
+    }
+}
+
+/// Swaps the file system of `options` for one that encrypts through the keys of
+/// `options.encryption`
+pub(crate) fn apply(mut options: ChestOptions) -> DungeonResult<ChestOptions> {
+    if let Some(source) = options.encryption.take() {
+        let keyring = Keyring::load(&source, options.file_system.as_ref())?;
+        options.file_system = Arc::new(
+            EncryptedFileSystem::new(options.file_system.clone(), keyring)
+                .allowing_plaintext(options.allow_unencrypted),
+        );
+    }
+    Ok(options)
+}
+
+/// Header of an encrypted file
+#[derive(Clone, Copy)]
+struct Header {
+    key_id: u32,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE || !bytes.starts_with(MAGIC) {
+            return None;
+        }
+        let key_id = u32::from_le_bytes(bytes[MAGIC.len()..MAGIC.len() + 4].try_into().ok()?);
+        let nonce_prefix = bytes[MAGIC.len() + 4..HEADER_SIZE].try_into().ok()?;
+        Some(Self {
+            key_id,
+            nonce_prefix,
+        })
+    }
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&self.key_id.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce_prefix);
+        bytes
+    }
+    /// Every block has its own nonce, and the last one is marked so truncating a file at a block
+    /// boundary is detected
+    fn seal_params(&self, block: u64, last: bool) -> (XNonce, Vec<u8>) {
+        let mut nonce = [0; 24];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_SIZE..].copy_from_slice(&block.to_le_bytes());
+        let mut aad = self.to_bytes();
+        aad.push(last as u8);
+        (nonce.into(), aad)
+    }
+}
+
+/// File id, size and frame count of a file written through `append`
+type AppendedFile = ([u8; FILE_ID_SIZE], u64, u64);
+
+/// Header of a file written through `append`
+#[derive(Clone, Copy)]
+struct AppendHeader {
+    key_id: u32,
+    file_id: [u8; FILE_ID_SIZE],
+}
+
+impl AppendHeader {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < APPEND_HEADER_SIZE || !bytes.starts_with(APPEND_MAGIC) {
+            return None;
+        }
+        let key_id = u32::from_le_bytes(
+            bytes[APPEND_MAGIC.len()..APPEND_MAGIC.len() + 4]
+                .try_into()
+                .ok()?,
+        );
+        let file_id = bytes[APPEND_MAGIC.len() + 4..APPEND_HEADER_SIZE]
+            .try_into()
+            .ok()?;
+        Some(Self { key_id, file_id })
+    }
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(APPEND_HEADER_SIZE);
+        bytes.extend_from_slice(APPEND_MAGIC);
+        bytes.extend_from_slice(&self.key_id.to_le_bytes());
+        bytes.extend_from_slice(&self.file_id);
+        bytes
+    }
+    /// Frames are sealed along with their file and position, so one that is moved, repeated,
+    /// dropped or taken from another file doesn't open
+    fn frame_aad(&self, frame: u64) -> Vec<u8> {
+        let mut aad = self.to_bytes();
+        aad.extend_from_slice(&frame.to_le_bytes());
+        aad
+    }
+}
+
+/// Opens the frames of an appended file. A trailing frame that is cut short is the append that
+/// was going on during a crash, and is left out
+fn open_frames(keyring: &Keyring, data: &[u8]) -> io::Result<Vec<u8>> {
+    let header = AppendHeader::parse(data).ok_or_else(corrupt)?;
+    let cipher = keyring.cipher(header.key_id)?;
+    let mut plain = Vec::new();
+    let mut rest = &data[APPEND_HEADER_SIZE..];
+    let mut frame_count = 0;
+    while rest.len() >= 4 + NONCE_SIZE {
+        let sealed_len = u32::from_le_bytes(rest[..4].try_into().map_err(|_| corrupt())?) as usize;
+        let Some(frame) = rest.get(4..4 + NONCE_SIZE + sealed_len) else {
+            break;
+        };
+        let (nonce, sealed) = frame.split_at(NONCE_SIZE);
+        let opened = cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: &header.frame_aad(frame_count),
+                },
+            )
+            .map_err(|_| corrupt())?;
+        plain.extend(opened);
+        rest = &rest[4 + NONCE_SIZE + sealed_len..];
+        frame_count += 1;
+    }
+    Ok(plain)
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Could not decrypt file")
+}
+
+/// Amount of sealed blocks in an encrypted file of `size` bytes
+fn block_count(size: usize) -> usize {
+    (size - HEADER_SIZE).div_ceil(SEALED_BLOCK_SIZE)
+}
+
+/// Encrypts every file written through it with XChaCha20-Poly1305 in fixed size blocks, so
+/// reads at an offset only decrypt the blocks they touch. A file is sealed once its writer is
+/// flushed or dropped, nothing can be appended to it after that. Files written through `append`
+/// are sealed an append at a time instead
+#[derive(Clone, Debug)]
+pub struct EncryptedFileSystem {
+    inner: Arc<dyn FileSystem>,
+    keyring: Arc<Keyring>,
+    /// Reads files without a header as they are instead of failing
+    allow_plaintext: bool,
+    /// File id, size and frame count of the files appended to, so the frames aren't counted
+    /// again on every append
+    appends: Arc<Mutex<HashMap<PathBuf, AppendedFile>>>,
+}
+
+impl EncryptedFileSystem {
+    pub fn new(inner: Arc<dyn FileSystem>, keyring: Keyring) -> Self {
+        Self {
+            inner,
+            keyring: Arc::new(keyring),
+            allow_plaintext: false,
+            appends: Arc::default(),
+        }
+    }
+    pub fn allowing_plaintext(mut self, allow_plaintext: bool) -> Self {
+        self.allow_plaintext = allow_plaintext;
+        self
+    }
+    /// Fails for a file without a header unless plaintext is allowed
+    fn check_plaintext(&self) -> io::Result<()> {
+        if self.allow_plaintext {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "File is not encrypted",
+        ))
+    }
+    /// Whether the file at `path` was written through `append`
+    fn is_appended(&self, path: &Path) -> io::Result<bool> {
+        let size = self.inner.file_size(path)? as usize;
+        if size < APPEND_HEADER_SIZE {
+            return Ok(false);
+        }
+        Ok(self.inner.read_at(path, 0, APPEND_MAGIC.len())? == APPEND_MAGIC)
+    }
+    fn appends(&self) -> MutexGuard<'_, HashMap<PathBuf, AppendedFile>> {
+        self.appends
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    /// Complete frames of the appended file at `path`, which is `size` bytes long
+    fn frame_count(&self, path: &Path, header: &AppendHeader, size: u64) -> io::Result<u64> {
+        let known = self.appends().get(path).copied();
+        if let Some((file_id, known_size, frames)) = known {
+            if file_id == header.file_id && known_size == size {
+                return Ok(frames);
+            }
+        }
+        let mut frames = 0;
+        let mut offset = APPEND_HEADER_SIZE as u64;
+        while offset + 4 <= size {
+            let sealed_len = self.inner.read_at(path, offset, 4)?;
+            let sealed_len = u32::from_le_bytes(sealed_len.try_into().map_err(|_| corrupt())?);
+            offset += (4 + NONCE_SIZE) as u64 + sealed_len as u64;
+            if offset > size {
+                break;
+            }
+            frames += 1;
+        }
+        Ok(frames)
+    }
+    /// Header of the file at `path`, or None when it isn't encrypted
+    fn header(&self, path: &Path) -> io::Result<Option<Header>> {
+        let size = self.inner.file_size(path)? as usize;
+        if size < HEADER_SIZE {
+            return Ok(None);
+        }
+        Ok(Header::parse(&self.inner.read_at(path, 0, HEADER_SIZE)?))
+    }
+    fn open_block(
+        &self,
+        header: &Header,
+        block: usize,
+        last: bool,
+        sealed: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let (nonce, aad) = header.seal_params(block as u64, last);
+        self.keyring
+            .cipher(header.key_id)?
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: sealed,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| corrupt())
+    }
+}
+
+impl FileSystem for EncryptedFileSystem {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+    fn is_file(&self, path: &Path) -> bool {
+        self.inner.is_file(path)
+    }
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let data = self.inner.read(path)?;
+        if data.starts_with(APPEND_MAGIC) && data.len() >= APPEND_HEADER_SIZE {
+            return open_frames(&self.keyring, &data);
+        }
+        let Some(header) = Header::parse(&data) else {
+            self.check_plaintext()?;
+            return Ok(data);
+        };
+        let blocks = block_count(data.len());
+        let mut plain = Vec::with_capacity(blocks * BLOCK_SIZE);
+        for (block, sealed) in data[HEADER_SIZE..].chunks(SEALED_BLOCK_SIZE).enumerate() {
+            plain.extend(self.open_block(&header, block, block + 1 == blocks, sealed)?);
+        }
+        Ok(plain)
+    }
+    fn read_at(&self, path: &Path, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        if self.is_appended(path)? {
+            let plain = self.read(path)?;
+            let start = offset as usize;
+            return plain
+                .get(start..start + length)
+                .map(<[u8]>::to_vec)
+                .ok_or(io::ErrorKind::UnexpectedEof.into());
+        }
+        let Some(header) = self.header(path)? else {
+            self.check_plaintext()?;
+            return self.inner.read_at(path, offset, length);
+        };
+        let size = self.inner.file_size(path)? as usize;
+        let blocks = block_count(size);
+        let offset = offset as usize;
+        let first = offset / BLOCK_SIZE;
+        let last = (offset + length).div_ceil(BLOCK_SIZE).max(first + 1);
+        if last > blocks {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let start = HEADER_SIZE + first * SEALED_BLOCK_SIZE;
+        let end = (HEADER_SIZE + last * SEALED_BLOCK_SIZE).min(size);
+        let data = self.inner.read_at(path, start as u64, end - start)?;
+        let mut plain = Vec::with_capacity((last - first) * BLOCK_SIZE);
+        for (i, sealed) in data.chunks(SEALED_BLOCK_SIZE).enumerate() {
+            let block = first + i;
+            plain.extend(self.open_block(&header, block, block + 1 == blocks, sealed)?);
+        }
+        let skip = offset - first * BLOCK_SIZE;
+        if skip + length > plain.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(plain[skip..skip + length].to_vec())
+    }
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+        nonce_prefix.copy_from_slice(&XChaCha20Poly1305::generate_nonce(&mut OsRng)[..16]);
+        let header = Header {
+            key_id: self.keyring.active,
+            nonce_prefix,
+        };
+        let mut inner = self.inner.create(path)?;
+        inner.write_all(&header.to_bytes())?;
+        Ok(Box::new(EncryptedFile {
+            inner,
+            keyring: self.keyring.clone(),
+            header,
+            block: 0,
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+            sealed: false,
+        }))
+    }
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut appended = Vec::new();
+        let size = if self.inner.is_file(path) {
+            self.inner.file_size(path)?
+        } else {
+            0
+        };
+        let (header, frame) = if size > 0 {
+            if !self.is_appended(path)? {
+                return Err(io::Error::other(
+                    "Could not append to a file not written by append",
+                ));
+            }
+            let header = self.inner.read_at(path, 0, APPEND_HEADER_SIZE)?;
+            let header = AppendHeader::parse(&header).ok_or_else(corrupt)?;
+            let frames = self.frame_count(path, &header, size)?;
+            (header, frames)
+        } else {
+            let mut file_id = [0; FILE_ID_SIZE];
+            file_id.copy_from_slice(&XChaCha20Poly1305::generate_nonce(&mut OsRng)[..FILE_ID_SIZE]);
+            let header = AppendHeader {
+                key_id: self.keyring.active,
+                file_id,
+            };
+            appended.extend_from_slice(&header.to_bytes());
+            (header, 0)
+        };
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .keyring
+            .cipher(header.key_id)?
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: data,
+                    aad: &header.frame_aad(frame),
+                },
+            )
+            .map_err(|_| io::Error::other("Could not encrypt block"))?;
+        appended.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        appended.extend_from_slice(&nonce);
+        appended.extend_from_slice(&sealed);
+        self.inner.append(path, &appended)?;
+        self.appends().insert(
+            path.to_path_buf(),
+            (header.file_id, size + appended.len() as u64, frame + 1),
+        );
+        Ok(())
+    }
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.appends().remove(path);
+        self.inner.remove_file(path)
+    }
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir_all(path)
+    }
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.hard_link(from, to)
+    }
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        // Nothing in an encrypted file depends on its path, so it is copied as it is
+        self.inner.copy(from, to)
+    }
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        if self.is_appended(path)? {
+            return Ok(self.read(path)?.len() as u64);
+        }
+        let size = self.inner.file_size(path)?;
+        if self.header(path)?.is_none() {
+            return Ok(size);
+        }
+        let blocks = block_count(size as usize);
+        Ok((size as usize - HEADER_SIZE - blocks * TAG_SIZE) as u64)
+    }
+    fn disk_size(&self, path: &Path) -> io::Result<u64> {
+        self.inner.disk_size(path)
+    }
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        self.inner.sync(path)
+    }
+    fn map(&self, path: &Path) -> Option<MappedFile> {
+        if self.is_appended(path).ok()? {
+            return Some(Arc::new(self.read(path).ok()?));
+        }
+        if self.header(path).ok()?.is_none() {
+            self.check_plaintext().ok()?;
+            return self.inner.map(path);
+        }
+        Some(Arc::new(self.read(path).ok()?))
+    }
+    fn try_lock(&self, path: &Path) -> io::Result<Box<dyn Send + Sync>> {
+        self.inner.try_lock(path)
+    }
+}
+
+/// Writer of an `EncryptedFileSystem`, sealing a block every `BLOCK_SIZE` bytes. The last block
+/// is sealed on flush, even when it is empty
+struct EncryptedFile {
+    inner: Box<dyn Write + Send>,
+    keyring: Arc<Keyring>,
+    header: Header,
+    block: u64,
+    buffer: Vec<u8>,
+    sealed: bool,
+}
+
+impl EncryptedFile {
+    fn seal_block(&mut self, last: bool) -> io::Result<()> {
+        let (nonce, aad) = self.header.seal_params(self.block, last);
+        let sealed = self
+            .keyring
+            .cipher(self.header.key_id)?
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &self.buffer,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| io::Error::other("Could not encrypt block"))?;
+        self.inner.write_all(&sealed)?;
+        self.buffer.clear();
+        self.block += 1;
+        Ok(())
+    }
+}
+
+impl Write for EncryptedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.sealed {
+            return Err(io::Error::other("Encrypted file was already sealed"));
+        }
+        // A full block is only sealed once more data arrives, since the last block is sealed
+        // differently
+        if self.buffer.len() == BLOCK_SIZE {
+            self.seal_block(false)?;
+        }
+        let written = buf.len().min(BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..written]);
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.sealed {
+            self.seal_block(true)?;
+            self.sealed = true;
+        }
+        self.inner.flush()
+    }
+}
+
+impl Drop for EncryptedFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::Path, sync::Arc};
+
+    use super::{EncryptedFileSystem, Keyring, APPEND_HEADER_SIZE, BLOCK_SIZE, NONCE_SIZE};
+    use crate::file_system::{FileSystem, MemoryFileSystem};
+
+    const OLD_KEY: &str = "1:0000000000000000000000000000000000000000000000000000000000000001";
+    const NEW_KEY: &str = "2:0000000000000000000000000000000000000000000000000000000000000002";
+
+    fn encrypted(inner: &MemoryFileSystem, keys: &str) -> EncryptedFileSystem {
+        EncryptedFileSystem::new(Arc::new(inner.clone()), Keyring::parse(keys).unwrap())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let inner = MemoryFileSystem::new();
+        inner.create_dir_all(Path::new("/dir")).unwrap();
+        let fs = encrypted(&inner, OLD_KEY);
+        let path = Path::new("/dir/file");
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let mut file = fs.create(path).unwrap();
+        file.write_all(&data).unwrap();
+        file.flush().unwrap();
+        assert!(file.write_all(b"more").is_err());
+        drop(file);
+
+        assert_eq!(fs.read(path).unwrap(), data);
+        assert_eq!(fs.file_size(path).unwrap(), data.len() as u64);
+        let offset = BLOCK_SIZE - 5;
+        assert_eq!(
+            fs.read_at(path, offset as u64, 20).unwrap(),
+            &data[offset..offset + 20]
+        );
+        assert!(fs.read_at(path, data.len() as u64 - 5, 10).is_err());
+        assert_eq!(fs.disk_size(path).unwrap(), inner.file_size(path).unwrap());
+        assert!(fs.disk_size(path).unwrap() > fs.file_size(path).unwrap());
+        assert!(!inner
+            .read(path)
+            .unwrap()
+            .windows(16)
+            .any(|window| data.windows(16).any(|plain| plain == window)));
+    }
+
+    #[test]
+    fn test_detects_tampering() {
+        let inner = MemoryFileSystem::new();
+        inner.create_dir_all(Path::new("/dir")).unwrap();
+        let fs = encrypted(&inner, OLD_KEY);
+        let path = Path::new("/dir/file");
+        fs.write(path, &vec![7; BLOCK_SIZE * 2]).unwrap();
+        let mut sealed = inner.read(path).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        inner.write(path, &sealed).unwrap();
+        assert!(fs.read(path).is_err());
+        // Dropping the last block is noticed even though every remaining block is intact
+        sealed[last] ^= 1;
+        inner.write(path, &sealed[..sealed.len() - 16]).unwrap();
+        assert!(fs.read(path).is_err());
+    }
+
+    #[test]
+    fn test_plaintext_only_when_allowed() {
+        let inner = MemoryFileSystem::new();
+        inner.create_dir_all(Path::new("/dir")).unwrap();
+        let path = Path::new("/dir/file");
+        inner.write(path, b"swapped in").unwrap();
+        let fs = encrypted(&inner, OLD_KEY);
+        assert!(fs.read(path).is_err());
+        assert!(fs.read_at(path, 0, 4).is_err());
+        assert!(fs.map(path).is_none());
+        let fs = fs.allowing_plaintext(true);
+        assert_eq!(fs.read(path).unwrap(), b"swapped in");
+        assert_eq!(fs.read_at(path, 0, 7).unwrap(), b"swapped");
+    }
+
+    #[test]
+    fn test_appends() {
+        let inner = MemoryFileSystem::new();
+        inner.create_dir_all(Path::new("/dir")).unwrap();
+        let fs = encrypted(&inner, OLD_KEY);
+        let path = Path::new("/dir/log");
+        fs.append(path, b"first ").unwrap();
+        fs.append(path, b"second").unwrap();
+        assert_eq!(fs.read(path).unwrap(), b"first second");
+        assert_eq!(fs.read_at(path, 6, 6).unwrap(), b"second");
+        assert_eq!(fs.file_size(path).unwrap(), 12);
+        // An append cut short by a crash is dropped
+        let sealed = inner.read(path).unwrap();
+        inner.write(path, &sealed[..sealed.len() - 3]).unwrap();
+        assert_eq!(fs.read(path).unwrap(), b"first ");
+        assert!(fs.append(Path::new("/dir/file"), b"").is_ok());
+        fs.write(Path::new("/dir/file"), b"sealed").unwrap();
+        assert!(fs.append(Path::new("/dir/file"), b"more").is_err());
+    }
+
+    /// Header and frames of an appended file as stored
+    fn split_frames(sealed: &[u8]) -> (&[u8], Vec<&[u8]>) {
+        let (header, mut rest) = sealed.split_at(APPEND_HEADER_SIZE);
+        let mut frames = Vec::new();
+        while !rest.is_empty() {
+            let sealed_len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (frame, next) = rest.split_at(4 + NONCE_SIZE + sealed_len);
+            frames.push(frame);
+            rest = next;
+        }
+        (header, frames)
+    }
+
+    #[test]
+    fn test_append_frames_stay_in_place() {
+        let inner = MemoryFileSystem::new();
+        inner.create_dir_all(Path::new("/dir")).unwrap();
+        let fs = encrypted(&inner, OLD_KEY);
+        let path = Path::new("/dir/log");
+        let other = Path::new("/dir/other");
+        for data in [b"a", b"b"] {
+            fs.append(path, data).unwrap();
+            fs.append(other, data).unwrap();
+        }
+        // Picks up the frame count from the file
+        encrypted(&inner, OLD_KEY).append(path, b"c").unwrap();
+        fs.append(path, b"d").unwrap();
+        assert_eq!(fs.read(path).unwrap(), b"abcd");
+
+        let sealed = inner.read(path).unwrap();
+        let (header, frames) = split_frames(&sealed);
+        let other_sealed = inner.read(other).unwrap();
+        let (_, other_frames) = split_frames(&other_sealed);
+        let tampered = [
+            // Reordered
+            [frames[1], frames[0]],
+            // Repeated
+            [frames[0], frames[0]],
+            // Dropped
+            [frames[0], frames[2]],
+            // From another file
+            [frames[0], other_frames[1]],
+        ];
+        for tampered in tampered {
+            inner
+                .write(path, &[header, &tampered.concat()].concat())
+                .unwrap();
+            assert!(fs.read(path).is_err());
+        }
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let inner = MemoryFileSystem::new();
+        inner.create_dir_all(Path::new("/dir")).unwrap();
+        let path = Path::new("/dir/file");
+        encrypted(&inner, OLD_KEY).write(path, b"old").unwrap();
+        let rotated = encrypted(&inner, &format!("{NEW_KEY}\n{OLD_KEY}"));
+        assert_eq!(rotated.read(path).unwrap(), b"old");
+        rotated.write(path, b"new").unwrap();
+        assert!(encrypted(&inner, OLD_KEY).read(path).is_err());
+        assert_eq!(encrypted(&inner, NEW_KEY).read(path).unwrap(), b"new");
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("1:abcd").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::file_system::{FileSystem, MappedFile, MemoryFileSystem};
+
+/// Small deterministic generator, so a failing case can be replayed from its seed
+#[derive(Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    /// Uniform in `0..bound`, or 0 when `bound` is 0
+    pub fn below(&mut self, bound: usize) -> usize {
+        if bound == 0 {
+            return 0;
+        }
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+#[derive(Debug)]
+struct FaultState {
+    /// Length of the prefix of every file that was synced, the part that survives a power loss
+    synced: BTreeMap<PathBuf, usize>,
+    /// Writes, renames, removals and syncs left before the file system crashes
+    ops_left: Option<usize>,
+    crashed: bool,
+    /// Writes fail the way they do on a full disk, without crashing
+    full: bool,
+    rng: Rng,
+}
+
+/// `MemoryFileSystem` that can crash at an arbitrary point and then lose power. Once crashed
+/// every change fails, the way nothing more reaches the disk after the process dies. A power loss
+/// drops everything written since the last sync of each file, keeping a random part of it as a
+/// torn write. Creating, renaming and removing files is durable right away
+#[derive(Clone, Debug)]
+pub struct FaultyFileSystem {
+    inner: MemoryFileSystem,
+    state: Arc<Mutex<FaultState>>,
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("Injected crash")
+}
+
+fn disk_full() -> io::Error {
+    io::Error::new(io::ErrorKind::StorageFull, "Injected full disk")
+}
+
+impl FaultyFileSystem {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            inner: MemoryFileSystem::new(),
+            state: Arc::new(Mutex::new(FaultState {
+                synced: BTreeMap::new(),
+                ops_left: None,
+                crashed: false,
+                full: false,
+                rng: Rng::new(seed),
+            })),
+        }
+    }
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    /// Lets `ops` more changes through before crashing
+    pub fn crash_after(&self, ops: usize) {
+        self.state().ops_left = Some(ops);
+    }
+    /// Makes writes to files fail until called again with false. Everything else keeps working
+    pub fn fill_disk(&self, full: bool) {
+        self.state().full = full;
+    }
+    pub fn has_crashed(&self) -> bool {
+        self.state().crashed
+    }
+    /// Throws away what wasn't synced, tearing the unsynced tail of some files, and lets changes
+    /// through again
+    pub fn power_loss(&self) {
+        let mut state = self.state();
+        let synced = state.synced.clone();
+        for (path, synced_len) in synced {
+            let Ok(data) = self.inner.read(&path) else {
+                continue;
+            };
+            let kept = synced_len + state.rng.below(data.len() - synced_len + 1);
+            self.inner
+                .write(&path, &data[..kept])
+                .expect("Could not truncate file");
+        }
+        state.ops_left = None;
+        state.crashed = false;
+    }
+    /// Counts one change, failing once the file system crashed
+    fn check(&self) -> io::Result<()> {
+        Self::count_op(&mut self.state())
+    }
+    fn count_op(state: &mut FaultState) -> io::Result<()> {
+        if state.crashed {
+            return Err(crashed());
+        }
+        match &mut state.ops_left {
+            Some(0) => {
+                state.crashed = true;
+                Err(crashed())
+            }
+            Some(ops_left) => {
+                *ops_left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl FileSystem for FaultyFileSystem {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.create_dir_all(path)
+    }
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+    fn is_file(&self, path: &Path) -> bool {
+        self.inner.is_file(path)
+    }
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+    fn read_at(&self, path: &Path, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        self.inner.read_at(path, offset, length)
+    }
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        self.check()?;
+        let inner = self.inner.create(path)?;
+        self.state().synced.insert(path.to_path_buf(), 0);
+        Ok(Box::new(FaultyFile {
+            fs: self.clone(),
+            inner,
+        }))
+    }
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+        if state.full {
+            return Err(disk_full());
+        }
+        let was_crashed = state.crashed;
+        let result = Self::count_op(&mut state);
+        state.synced.entry(path.to_path_buf()).or_insert(0);
+        if let Err(err) = result {
+            if !was_crashed {
+                let torn = state.rng.below(data.len());
+                drop(state);
+                self.inner.append(path, &data[..torn])?;
+            }
+            return Err(err);
+        }
+        drop(state);
+        self.inner.append(path, data)
+    }
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.rename(from, to)?;
+        let mut state = self.state();
+        let synced = state.synced.remove(from).unwrap_or_default();
+        state.synced.insert(to.to_path_buf(), synced);
+        Ok(())
+    }
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.remove_file(path)?;
+        self.state().synced.remove(path);
+        Ok(())
+    }
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.remove_dir_all(path)?;
+        self.state()
+            .synced
+            .retain(|file, _| !file.starts_with(path));
+        Ok(())
+    }
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.hard_link(from, to)?;
+        let mut state = self.state();
+        let synced = state.synced.get(from).copied().unwrap_or_default();
+        state.synced.insert(to.to_path_buf(), synced);
+        Ok(())
+    }
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        self.inner.file_size(path)
+    }
+    fn disk_size(&self, path: &Path) -> io::Result<u64> {
+        self.inner.disk_size(path)
+    }
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        self.check()?;
+        self.inner.sync(path)?;
+        if self.inner.is_file(path) {
+            let len = self.inner.file_size(path)? as usize;
+            self.state().synced.insert(path.to_path_buf(), len);
+        }
+        Ok(())
+    }
+    fn map(&self, path: &Path) -> Option<MappedFile> {
+        self.inner.map(path)
+    }
+    fn try_lock(&self, path: &Path) -> io::Result<Box<dyn Send + Sync>> {
+        self.inner.try_lock(path)
+    }
+}
+
+/// Writer of a `FaultyFileSystem`. The write that crashes it only gets part of its bytes in
+struct FaultyFile {
+    fs: FaultyFileSystem,
+    inner: Box<dyn Write + Send>,
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.fs.state();
+        if state.full {
+            return Err(disk_full());
+        }
+        let was_crashed = state.crashed;
+        if let Err(err) = FaultyFileSystem::count_op(&mut state) {
+            if !was_crashed {
+                let torn = state.rng.below(buf.len());
+                drop(state);
+                self.inner.write_all(&buf[..torn])?;
+            }
+            return Err(err);
+        }
+        drop(state);
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::Path};
+
+    use super::FaultyFileSystem;
+    use crate::file_system::FileSystem;
+
+    #[test]
+    fn test_power_loss_keeps_synced_data() {
+        let fs = FaultyFileSystem::new(7);
+        fs.create_dir_all(Path::new("/dir")).unwrap();
+        let path = Path::new("/dir/file");
+        fs.write(path, b"synced").unwrap();
+        fs.sync(path).unwrap();
+        let mut file = fs.create(Path::new("/dir/other")).unwrap();
+        file.write_all(b"unsynced").unwrap();
+        drop(file);
+
+        fs.crash_after(0);
+        assert!(fs.write(path, b"lost").is_err());
+        assert!(fs.has_crashed());
+        fs.power_loss();
+        assert_eq!(fs.read(path).unwrap(), b"synced");
+        let other = fs.read(Path::new("/dir/other")).unwrap();
+        assert!(b"unsynced".starts_with(&other));
+    }
+}
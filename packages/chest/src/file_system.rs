@@ -0,0 +1,409 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    fs::{File, OpenOptions, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use memmap2::Mmap;
+
+/// Contents of a whole file that can be read without going through the file system again
+pub type MappedFile = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// Every file system access of a chest goes through this trait, so chests can run on something
+/// other than the OS file system
+pub trait FileSystem: Debug + Send + Sync {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn is_dir(&self, path: &Path) -> bool;
+    fn is_file(&self, path: &Path) -> bool;
+    /// Paths of the files and dirs directly inside `path`
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// Reads exactly `length` bytes starting at `offset`
+    fn read_at(&self, path: &Path, offset: u64, length: usize) -> io::Result<Vec<u8>>;
+    /// Creates or truncates a file that is written through the returned writer
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>>;
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = self.create(path)?;
+        file.write_all(data)?;
+        file.flush()
+    }
+    /// Writes `data` at the end of the file at `path`, creating it when missing. Unlike files
+    /// written through `create`, it can be appended to again later
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let data = self.read(from)?;
+        self.write(to, &data)
+    }
+    fn file_size(&self, path: &Path) -> io::Result<u64>;
+    /// Bytes the file at `path` takes on disk, which is more than `file_size` for file systems
+    /// storing files with extra data, like `EncryptedFileSystem`
+    fn disk_size(&self, path: &Path) -> io::Result<u64> {
+        self.file_size(path)
+    }
+    /// Makes what was written to the file, or the entries of the dir, at `path` survive a power
+    /// loss
+    fn sync(&self, path: &Path) -> io::Result<()>;
+    /// Maps the whole file, or None when this file system can't
+    fn map(&self, path: &Path) -> Option<MappedFile>;
+    /// Takes an exclusive lock on `path`, held until the returned guard is dropped. Fails with
+    /// `io::ErrorKind::WouldBlock` while someone else holds it
+    fn try_lock(&self, path: &Path) -> io::Result<Box<dyn Send + Sync>>;
+}
+
+/// Writes `data` to `tmp_path` and renames it over `path` once it is synced, so after a crash
+/// `path` has either its old or its new contents
+pub(crate) fn write_atomic(
+    fs: &dyn FileSystem,
+    tmp_path: &Path,
+    path: &Path,
+    data: &[u8],
+) -> io::Result<()> {
+    fs.write(tmp_path, data)?;
+    fs.sync(tmp_path)?;
+    fs.rename(tmp_path, path)?;
+    sync_parent(fs, path)
+}
+
+/// Makes the creation, rename or removal of `path` survive a power loss
+pub(crate) fn sync_parent(fs: &dyn FileSystem, path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs.sync(parent),
+        _ => Ok(()),
+    }
+}
+
+/// Bytes taken by the files in `dir` and its subdirs
+pub(crate) fn dir_size(fs: &dyn FileSystem, dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for path in fs.read_dir(dir)? {
+        size += if fs.is_dir(&path) {
+            dir_size(fs, &path)?
+        } else {
+            fs.disk_size(&path)?
+        };
+    }
+    Ok(size)
+}
+
+/// The file system of the OS, through `std::fs`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+    fn read_at(&self, path: &Path, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        let mut file = File::open(path)?;
+        file.seek(io::SeekFrom::Start(offset))?;
+        let mut buff = vec![0; length];
+        file.read_exact(&mut buff)?;
+        Ok(buff)
+    }
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(File::create(path)?))
+    }
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        std::fs::write(path, data)
+    }
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(data)
+    }
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::hard_link(from, to)
+    }
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::copy(from, to).map(|_| ())
+    }
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        std::fs::metadata(path).map(|metadata| metadata.len())
+    }
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+    fn map(&self, path: &Path) -> Option<MappedFile> {
+        let file = File::open(path).ok()?;
+        // SAFETY: data files are never modified after the sstable is written, they are only
+        // deleted, which doesn't invalidate an existing mapping
+        let mmap = unsafe { Mmap::map(&file) }.ok()?;
+        Some(Arc::new(mmap))
+    }
+    fn try_lock(&self, path: &Path) -> io::Result<Box<dyn Send + Sync>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Box::new(file)),
+            Err(TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TryLockError::Error(err)) => Err(err),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    dirs: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, Arc<Vec<u8>>>,
+    locks: BTreeSet<PathBuf>,
+}
+
+/// File system kept entirely in memory, for chests that don't need to outlive the process and for
+/// tests. Clones share the same files
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFileSystem {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+fn not_found() -> io::Error {
+    io::ErrorKind::NotFound.into()
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        // A panic while holding the lock can't leave the maps half updated
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn check_parent(state: &MemoryState, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !state.dirs.contains(parent) => {
+                Err(not_found())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        for ancestor in path.ancestors() {
+            if ancestor.as_os_str().is_empty() {
+                continue;
+            }
+            if state.files.contains_key(ancestor) {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+            state.dirs.insert(ancestor.to_path_buf());
+        }
+        Ok(())
+    }
+    fn is_dir(&self, path: &Path) -> bool {
+        self.state().dirs.contains(path)
+    }
+    fn is_file(&self, path: &Path) -> bool {
+        self.state().files.contains_key(path)
+    }
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state();
+        if !state.dirs.contains(path) {
+            return Err(not_found());
+        }
+        let files = state.files.keys();
+        let dirs = state.dirs.iter();
+        Ok(files
+            .chain(dirs)
+            .filter(|child| child.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let state = self.state();
+        let data = state.files.get(path).ok_or_else(not_found)?;
+        Ok(data.as_ref().clone())
+    }
+    fn read_at(&self, path: &Path, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        let state = self.state();
+        let data = state.files.get(path).ok_or_else(not_found)?;
+        let start = offset as usize;
+        data.get(start..start + length)
+            .map(<[u8]>::to_vec)
+            .ok_or(io::ErrorKind::UnexpectedEof.into())
+    }
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send>> {
+        let mut state = self.state();
+        Self::check_parent(&state, path)?;
+        state.files.insert(path.to_path_buf(), Arc::default());
+        Ok(Box::new(MemoryFile {
+            fs: self.clone(),
+            path: path.to_path_buf(),
+        }))
+    }
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+        Self::check_parent(&state, path)?;
+        let file = state.files.entry(path.to_path_buf()).or_default();
+        Arc::make_mut(file).extend_from_slice(data);
+        Ok(())
+    }
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        Self::check_parent(&state, to)?;
+        let data = state.files.remove(from).ok_or_else(not_found)?;
+        state.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.state()
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(not_found)
+    }
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        if !state.dirs.contains(path) {
+            return Err(not_found());
+        }
+        state.files.retain(|file, _| !file.starts_with(path));
+        state.dirs.retain(|dir| !dir.starts_with(path));
+        Ok(())
+    }
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        Self::check_parent(&state, to)?;
+        if state.files.contains_key(to) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let data = state.files.get(from).ok_or_else(not_found)?.clone();
+        state.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        let state = self.state();
+        let data = state.files.get(path).ok_or_else(not_found)?;
+        Ok(data.len() as u64)
+    }
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        let state = self.state();
+        if state.files.contains_key(path) || state.dirs.contains(path) {
+            Ok(())
+        } else {
+            Err(not_found())
+        }
+    }
+    fn map(&self, path: &Path) -> Option<MappedFile> {
+        let data = self.state().files.get(path)?.clone();
+        Some(data)
+    }
+    fn try_lock(&self, path: &Path) -> io::Result<Box<dyn Send + Sync>> {
+        let mut state = self.state();
+        Self::check_parent(&state, path)?;
+        if !state.locks.insert(path.to_path_buf()) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(Box::new(MemoryLock {
+            fs: self.clone(),
+            path: path.to_path_buf(),
+        }))
+    }
+}
+
+/// Appends to a file of a `MemoryFileSystem`. Mappings taken before keep seeing the old contents
+struct MemoryFile {
+    fs: MemoryFileSystem,
+    path: PathBuf,
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.fs.state();
+        let data = state.files.get_mut(&self.path).ok_or_else(not_found)?;
+        Arc::make_mut(data).extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct MemoryLock {
+    fs: MemoryFileSystem,
+    path: PathBuf,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        self.fs.state().locks.remove(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::Path};
+
+    use super::{FileSystem, MemoryFileSystem};
+
+    #[test]
+    fn test_memory_files() {
+        let fs = MemoryFileSystem::new();
+        let dir = Path::new("/chest");
+        assert!(fs.write(&dir.join("a"), b"data").is_err());
+        fs.create_dir_all(dir).unwrap();
+        let mut file = fs.create(&dir.join("a")).unwrap();
+        file.write_all(b"hello ").unwrap();
+        let mapped = fs.map(&dir.join("a")).unwrap();
+        file.write_all(b"world").unwrap();
+        assert_eq!((*mapped).as_ref(), b"hello ");
+        assert_eq!(fs.read_at(&dir.join("a"), 6, 5).unwrap(), b"world");
+        assert!(fs.read_at(&dir.join("a"), 6, 6).is_err());
+
+        fs.append(&dir.join("a"), b"!").unwrap();
+        assert_eq!(fs.read(&dir.join("a")).unwrap(), b"hello world!");
+
+        fs.rename(&dir.join("a"), &dir.join("b")).unwrap();
+        assert!(!fs.is_file(&dir.join("a")));
+        assert_eq!(fs.read_dir(dir).unwrap(), [dir.join("b")]);
+        fs.remove_dir_all(dir).unwrap();
+        assert!(!fs.is_file(&dir.join("b")));
+    }
+    #[test]
+    fn test_memory_lock() {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all(Path::new("/chest")).unwrap();
+        let lock = fs.try_lock(Path::new("/chest/LOCK")).unwrap();
+        assert!(fs.try_lock(Path::new("/chest/LOCK")).is_err());
+        drop(lock);
+        fs.try_lock(Path::new("/chest/LOCK")).unwrap();
+    }
+}
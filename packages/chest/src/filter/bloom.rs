@@ -1,7 +1,7 @@
 use super::Filter;
 
 fn num_bits(size: usize, fp_rate: f64) -> usize {
-    let num = -1.0f64 * size as f64 * fp_rate.ln();
+    let num = -(size as f64) * fp_rate.ln();
     let den = 2.0f64.ln().powf(2.0);
     (num / den).ceil() as usize
 }
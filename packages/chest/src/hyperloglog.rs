@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+
+/// Bits of the hash used to pick a register
+const PRECISION: u32 = 10;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// Estimates the amount of distinct keys inserted in it, within about 3% of the real amount,
+/// using one byte per register
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HyperLogLog {
+    #[serde(with = "serde_bytes")]
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn insert(&mut self, item: &str) {
+        let hash = fasthash::xx::hash64(item);
+        let register = (hash >> (64 - PRECISION)) as usize;
+        // Leading zeros of the bits left after the register index, counting from 1
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() + 1;
+        self.registers[register] = self.registers[register].max(rank as u8);
+    }
+    /// Adds every key of `other` to this estimator
+    pub fn union(&mut self, other: &HyperLogLog) {
+        for (register, other_register) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other_register);
+        }
+    }
+    pub fn estimate(&self) -> usize {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|register| 2f64.powi(-(*register as i32)))
+            .sum();
+        let raw = alpha * m * m / sum;
+        let empty = self
+            .registers
+            .iter()
+            .filter(|register| **register == 0)
+            .count();
+        // Linear counting is more accurate while many registers are still empty
+        if raw <= 2.5 * m && empty > 0 {
+            return (m * (m / empty as f64).ln()).round() as usize;
+        }
+        raw.round() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HyperLogLog;
+
+    #[test]
+    fn test_estimate() {
+        for count in [0, 10, 1000, 50_000] {
+            let mut hll = HyperLogLog::default();
+            for i in 0..count {
+                hll.insert(&format!("key{i}"));
+                // Repeated keys don't count twice
+                hll.insert(&format!("key{i}"));
+            }
+            let estimate = hll.estimate() as f64;
+            assert!((estimate - count as f64).abs() <= count as f64 * 0.1);
+        }
+    }
+    #[test]
+    fn test_union() {
+        let mut first = HyperLogLog::default();
+        let mut second = HyperLogLog::default();
+        for i in 0..1000 {
+            first.insert(&format!("key{i}"));
+            second.insert(&format!("key{}", i + 500));
+        }
+        first.union(&second);
+        let estimate = first.estimate() as f64;
+        assert!((estimate - 1500.0).abs() <= 150.0);
+    }
+}
@@ -0,0 +1,169 @@
+use std::path::{Path, PathBuf};
+
+use errors::{DungeonError, DungeonResult};
+use rmp_serde::from_slice;
+
+use crate::{
+    backup,
+    file_system::{write_atomic, FileSystem},
+    ss_table::{Index, FORMAT_VERSION},
+    value::TimeStampedValue,
+};
+
+/// External tables are linked here under their final names before being moved next to the live
+/// ones
+const INGEST_DIR: &str = "ingest";
+/// Written once every table of an ingestion is staged. Without it the staged tables are dropped
+const COMMIT_FILE_NAME: &str = "COMMIT";
+
+/// External sstable checked by `validate` and ready to be staged
+pub struct ExternalSSTable {
+    pub data_file_path: PathBuf,
+    pub index_file_path: PathBuf,
+    pub index: Index,
+    pub first_key: String,
+    pub last_key: String,
+    /// Oldest and newest record timestamps
+    pub timestamps: (u128, u128),
+}
+
+impl ExternalSSTable {
+    pub fn open(fs: &dyn FileSystem, index_file_path: &Path) -> DungeonResult<Self> {
+        let index = Index::from_file(fs, index_file_path)?;
+        if index.version != FORMAT_VERSION {
+            return Err(DungeonError::new(
+                "External sstable has an unsupported format",
+            ));
+        }
+        // Writes of the log up to that sequence would be taken as flushed when reopening
+        if index.last_sequence != 0 {
+            return Err(DungeonError::new(
+                "External sstable was flushed by another chest",
+            ));
+        }
+        let (Some((first_key, last_key)), Some(timestamps)) = (index.key_range(), index.timestamps)
+        else {
+            return Err(DungeonError::new("External sstable is empty"));
+        };
+        let data_file_path = index_file_path.with_extension("chest");
+        if !fs.is_file(&data_file_path) {
+            return Err(DungeonError::new("Could not find external data file"));
+        }
+        Ok(Self {
+            data_file_path,
+            index_file_path: index_file_path.to_path_buf(),
+            first_key: first_key.clone(),
+            last_key: last_key.clone(),
+            timestamps,
+            index,
+        })
+    }
+    /// Every record the index points at, with its key
+    pub fn records(&self, fs: &dyn FileSystem) -> DungeonResult<Vec<(&String, TimeStampedValue)>> {
+        let data = fs
+            .read(&self.data_file_path)
+            .map_err(|_| DungeonError::new("Could not read external data file"))?;
+        self.index
+            .table
+            .iter()
+            .map(|(key, segment)| {
+                let record = data
+                    .get(segment.offset()..segment.offset() + segment.length())
+                    .and_then(|bytes| from_slice(bytes).ok())
+                    .ok_or(DungeonError::new("Could not read external sstable record"))?;
+                Ok((key, record))
+            })
+            .collect()
+    }
+}
+
+/// Checks that the key ranges of the tables don't overlap, so the order they end up in doesn't
+/// matter
+pub fn validate(tables: &mut [ExternalSSTable]) -> DungeonResult<()> {
+    tables.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+    for pair in tables.windows(2) {
+        if pair[1].first_key <= pair[0].last_key {
+            return Err(DungeonError::new(
+                "External sstables have overlapping key ranges",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Links the files of `table` into the staging dir under `file_name`
+pub fn stage(
+    fs: &dyn FileSystem,
+    dir_path: &Path,
+    table: &ExternalSSTable,
+    file_name: &str,
+) -> DungeonResult<()> {
+    let staging_dir = dir_path.join(INGEST_DIR);
+    fs.create_dir_all(&staging_dir)
+        .map_err(|_| DungeonError::new("Could not create ingest dir"))?;
+    backup::link_or_copy(
+        fs,
+        &table.data_file_path,
+        &staging_dir.join(file_name).with_extension("chest"),
+    )?;
+    backup::link_or_copy(
+        fs,
+        &table.index_file_path,
+        &staging_dir.join(file_name).with_extension("index"),
+    )?;
+    Ok(())
+}
+
+/// Marks every staged table as ingested and moves them into place
+pub fn commit(fs: &dyn FileSystem, dir_path: &Path) -> DungeonResult<()> {
+    let staging_dir = dir_path.join(INGEST_DIR);
+    // The staged links must be durable before the commit file that makes them count
+    fs.sync(&staging_dir)
+        .map_err(|_| DungeonError::new("Could not commit ingestion"))?;
+    let commit_file_path = staging_dir.join(COMMIT_FILE_NAME);
+    write_atomic(
+        fs,
+        &commit_file_path.with_extension("tmp"),
+        &commit_file_path,
+        &[],
+    )
+    .map_err(|_| DungeonError::new("Could not commit ingestion"))?;
+    recover(fs, dir_path)
+}
+
+/// Finishes a committed ingestion that was interrupted while moving its tables, or drops the
+/// staged tables of one that never committed
+pub fn recover(fs: &dyn FileSystem, dir_path: &Path) -> DungeonResult<()> {
+    let staging_dir = dir_path.join(INGEST_DIR);
+    if !fs.is_dir(&staging_dir) {
+        return Ok(());
+    }
+    if fs.is_file(&staging_dir.join(COMMIT_FILE_NAME)) {
+        let files = fs
+            .read_dir(&staging_dir)
+            .map_err(|_| DungeonError::new("Could not read ingest files"))?;
+        let mut index_file_paths = Vec::new();
+        for file_path in files {
+            match file_path.extension().and_then(|ext| ext.to_str()) {
+                // Data files go first, a data file without its index is ignored when opening
+                Some("chest") => move_into(fs, dir_path, &file_path)?,
+                Some("index") => index_file_paths.push(file_path),
+                _ => (),
+            }
+        }
+        for index_file_path in index_file_paths {
+            move_into(fs, dir_path, &index_file_path)?;
+        }
+    }
+    fs.remove_dir_all(&staging_dir)
+        .map_err(|_| DungeonError::new("Could not clean ingest dir"))?;
+    Ok(())
+}
+
+fn move_into(fs: &dyn FileSystem, dir_path: &Path, file_path: &Path) -> DungeonResult<()> {
+    let file_name = file_path
+        .file_name()
+        .ok_or(DungeonError::new("Could not get file name"))?;
+    fs.rename(file_path, &dir_path.join(file_name))
+        .map_err(|_| DungeonError::new("Could not move ingested file"))
+}
@@ -0,0 +1,163 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use errors::{DungeonError, DungeonResult};
+
+use crate::{
+    encryption,
+    options::ChestOptions,
+    ss_table::SSTable,
+    value::{RecordKind, TimeStampedValue},
+};
+
+/// A single indexed record of an sstable
+#[derive(Clone, Debug)]
+pub struct EntryInfo {
+    pub key: String,
+    pub offset: usize,
+    pub length: usize,
+    /// Whether this is an older version kept by `History` instead of the latest one
+    pub is_history: bool,
+    /// None when the segment doesn't decode
+    pub record: Option<TimeStampedValue>,
+}
+
+impl Display for EntryInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let version = if self.is_history { "history" } else { "latest" };
+        write!(
+            f,
+            "{}\t{}\t{}+{}\t",
+            self.key, version, self.offset, self.length
+        )?;
+        match &self.record {
+            Some(record) => {
+                write!(f, "{}\t{:?}\t", record.timestamp, record.kind)?;
+                match &record.pointer {
+                    Some(pointer) => write!(
+                        f,
+                        "<{}.vlog {}+{}>",
+                        pointer.log, pointer.offset, pointer.length
+                    ),
+                    None => write!(f, "{}", record.value),
+                }
+            }
+            None => write!(f, "<corrupt>"),
+        }
+    }
+}
+
+/// Summary of an sstable, including every segment that failed to decode
+#[derive(Clone, Debug, Default)]
+pub struct TableStats {
+    pub format_version: u32,
+    pub keys: usize,
+    /// Records of every version, including the ones kept by `History`
+    pub entries: usize,
+    pub tombstones: usize,
+    pub merge_operands: usize,
+    pub key_range: Option<(String, String)>,
+    pub timestamps: Option<(u128, u128)>,
+    pub data_file_size: u64,
+    pub index_file_size: u64,
+    /// Keys with a segment that doesn't decode
+    pub corrupt_keys: Vec<String>,
+}
+
+impl TableStats {
+    pub fn is_valid(&self) -> bool {
+        self.corrupt_keys.is_empty()
+    }
+}
+
+impl Display for TableStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "format version: {}", self.format_version)?;
+        writeln!(f, "keys: {}", self.keys)?;
+        writeln!(f, "entries: {}", self.entries)?;
+        writeln!(f, "tombstones: {}", self.tombstones)?;
+        writeln!(f, "merge operands: {}", self.merge_operands)?;
+        if let Some((first, last)) = &self.key_range {
+            writeln!(f, "key range: {first} .. {last}")?;
+        }
+        if let Some((oldest, newest)) = &self.timestamps {
+            writeln!(f, "timestamps: {oldest} .. {newest}")?;
+        }
+        writeln!(f, "data file size: {}", self.data_file_size)?;
+        writeln!(f, "index file size: {}", self.index_file_size)?;
+        if self.is_valid() {
+            writeln!(f, "every segment decodes")
+        } else {
+            writeln!(f, "corrupt keys: {}", self.corrupt_keys.join(", "))
+        }
+    }
+}
+
+/// Opens the table of a `.index` or `.chest` file
+fn open_table(path: &Path, options: &ChestOptions) -> DungeonResult<SSTable> {
+    let base_dir = path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let file_name = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or(DungeonError::new("Could not get file stem"))?;
+    SSTable::from_file(base_dir, file_name.to_owned(), options)
+}
+
+/// Every record of the table at `path`, sorted by key with the latest version first
+pub fn dump(path: &Path, options: &ChestOptions) -> DungeonResult<Vec<EntryInfo>> {
+    let options = &encryption::apply(options.clone())?;
+    let sstable = open_table(path, options)?;
+    let mut entries = Vec::new();
+    for (key, segment) in sstable.index.clone().into_entries() {
+        let is_history = entries
+            .last()
+            .is_some_and(|previous: &EntryInfo| previous.key == key);
+        entries.push(EntryInfo {
+            offset: segment.offset(),
+            length: segment.length(),
+            is_history,
+            record: sstable.read_segment(segment).ok(),
+            key,
+        });
+    }
+    Ok(entries)
+}
+
+/// Reads every record of the table at `path`, verifying that it decodes
+pub fn stats(path: &Path, options: &ChestOptions) -> DungeonResult<TableStats> {
+    let options = &encryption::apply(options.clone())?;
+    let sstable = open_table(path, options)?;
+    let file_size = |file_path: PathBuf| {
+        options
+            .file_system
+            .file_size(&file_path)
+            .map_err(|_| DungeonError::new("Could not read file size"))
+    };
+    let mut stats = TableStats {
+        format_version: sstable.index.version,
+        keys: sstable.index.table.len(),
+        key_range: sstable
+            .index
+            .key_range()
+            .map(|(first, last)| (first.clone(), last.clone())),
+        timestamps: sstable.index.timestamps,
+        data_file_size: file_size(sstable.get_data_file_path())?,
+        index_file_size: file_size(sstable.get_index_file_path())?,
+        ..Default::default()
+    };
+    for entry in dump(path, options)? {
+        stats.entries += 1;
+        match entry.record.map(|record| record.kind) {
+            Some(RecordKind::Delete) => stats.tombstones += 1,
+            Some(RecordKind::Merge) => stats.merge_operands += 1,
+            Some(RecordKind::Put) => (),
+            None => stats.corrupt_keys.push(entry.key),
+        }
+    }
+    Ok(stats)
+}
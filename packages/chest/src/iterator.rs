@@ -0,0 +1,146 @@
+use std::ops::Bound;
+
+use errors::DungeonResult;
+
+use crate::{
+    secondary_index::{is_index_key, INDEX_KEY_PREFIX},
+    value::TimeStampedValue,
+    Chest,
+};
+
+/// Cursor over the live keys of a chest in key order, moving both ways. Every move merges the
+/// memtable and every sstable, skipping deleted keys and index entries. It starts out on no key,
+/// where `next` goes to the first key and `prev` to the last one. The chest can't change while the
+/// cursor borrows it
+pub struct ChestIterator<'a> {
+    chest: &'a Chest,
+    /// Reads the values as they were right after it, see `Chest::iter_at`
+    timestamp: Option<u128>,
+    current: Option<(String, TimeStampedValue)>,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Forward,
+    Backward,
+}
+
+impl<'a> ChestIterator<'a> {
+    pub(crate) fn new(chest: &'a Chest, timestamp: Option<u128>) -> Self {
+        Self {
+            chest,
+            timestamp,
+            current: None,
+        }
+    }
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+    pub fn key(&self) -> Option<&str> {
+        self.current.as_ref().map(|(key, _)| key.as_str())
+    }
+    pub fn value(&self) -> Option<&TimeStampedValue> {
+        self.current.as_ref().map(|(_, value)| value)
+    }
+    /// Moves to the first key. Returns whether there is one, as every move does
+    pub fn seek_to_first(&mut self) -> DungeonResult<bool> {
+        self.settle(Bound::Unbounded, Direction::Forward)
+    }
+    pub fn seek_to_last(&mut self) -> DungeonResult<bool> {
+        self.settle(Bound::Unbounded, Direction::Backward)
+    }
+    /// Moves to the first key at or after `key`
+    pub fn seek(&mut self, key: &str) -> DungeonResult<bool> {
+        self.settle(Bound::Included(key.to_owned()), Direction::Forward)
+    }
+    /// Moves to the last key at or before `key`
+    pub fn seek_for_prev(&mut self, key: &str) -> DungeonResult<bool> {
+        self.settle(Bound::Included(key.to_owned()), Direction::Backward)
+    }
+    // A cursor move rather than `Iterator::next`, the cursor stays on the key it moves to
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> DungeonResult<bool> {
+        match self.current.take() {
+            Some((key, _)) => self.settle(Bound::Excluded(key), Direction::Forward),
+            None => self.seek_to_first(),
+        }
+    }
+    pub fn prev(&mut self) -> DungeonResult<bool> {
+        match self.current.take() {
+            Some((key, _)) => self.settle(Bound::Excluded(key), Direction::Backward),
+            None => self.seek_to_last(),
+        }
+    }
+    /// Moves to the closest live key from `from` on in `direction`
+    fn settle(&mut self, mut from: Bound<String>, direction: Direction) -> DungeonResult<bool> {
+        self.current = None;
+        loop {
+            let Some(key) = self.neighbour(&from, direction) else {
+                return Ok(false);
+            };
+            if is_index_key(&key) {
+                // Jumps over every index entry at once
+                from = match direction {
+                    Direction::Forward => Bound::Included(index_keys_end()),
+                    Direction::Backward => Bound::Excluded(INDEX_KEY_PREFIX.to_owned()),
+                };
+                continue;
+            }
+            let found = match self.timestamp {
+                Some(timestamp) => self.chest.get_at(&key, timestamp)?,
+                None => self
+                    .chest
+                    .lookup(&key)?
+                    .and_then(TimeStampedValue::into_visible),
+            };
+            match found {
+                Some(value) => {
+                    self.current = Some((key, value));
+                    return Ok(true);
+                }
+                None => from = Bound::Excluded(key),
+            }
+        }
+    }
+    /// Closest key with a record from `from` on in `direction`, across the memtable and every
+    /// sstable
+    fn neighbour(&self, from: &Bound<String>, direction: Direction) -> Option<String> {
+        let chest = self.chest;
+        let mut mem_table = chest.mem_table.keys_in(bounds(from, direction));
+        let sstables = chest.sstables.iter().map(|sstable| {
+            sstable
+                .0
+                .index
+                .table
+                .range(bounds(from, direction))
+                .map(|(key, _)| key)
+        });
+        let closest = match direction {
+            Direction::Forward => std::iter::once(mem_table.next())
+                .chain(sstables.map(|mut keys| keys.next()))
+                .flatten()
+                .min(),
+            Direction::Backward => std::iter::once(mem_table.next_back())
+                .chain(sstables.map(|mut keys| keys.next_back()))
+                .flatten()
+                .max(),
+        };
+        closest.cloned()
+    }
+}
+
+/// Range of the keys from `from` on in `direction`
+fn bounds(from: &Bound<String>, direction: Direction) -> (Bound<String>, Bound<String>) {
+    match direction {
+        Direction::Forward => (from.clone(), Bound::Unbounded),
+        Direction::Backward => (Bound::Unbounded, from.clone()),
+    }
+}
+
+/// Smallest key after every index entry
+fn index_keys_end() -> String {
+    let mut end = INDEX_KEY_PREFIX.to_owned();
+    end.pop();
+    end.push('\u{1}');
+    end
+}
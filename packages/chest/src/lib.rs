@@ -1,39 +1,88 @@
+#[cfg(feature = "async")]
+pub mod async_chest;
+pub mod backup;
+pub mod change_feed;
+mod clock;
+mod compaction;
+pub mod dump;
+pub mod encryption;
+#[cfg(test)]
+mod fault_injection;
+pub mod file_system;
 pub mod filter;
+mod hyperloglog;
+mod ingest;
+pub mod inspect;
+pub mod iterator;
+mod lock;
 mod mem_table;
+mod migration;
+pub mod options;
+pub mod repair;
+pub mod secondary_index;
 mod ss_table;
+pub mod stats;
 pub mod value;
+pub mod value_log;
+mod wal;
 
 #[cfg(test)]
 mod tests;
 
 use std::{
     cmp::Ordering,
-    collections::BTreeSet,
-    path::PathBuf,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{BTreeMap, BTreeSet},
+    io::{BufReader, BufWriter, Read, Write},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{mpsc::Receiver, Arc},
+    time::{Duration, Instant},
 };
 
-use errors::{DungeonError, DungeonResult};
+use backup::BackupReport;
+use change_feed::{ChangeEvent, Operation, Subscribers, WriteBatch};
+use clock::HybridLogicalClock;
+use compaction::{CompactionControl, CompactionJob};
+use dump::{DumpEntry, DumpFormat, IMPORT_TABLE_SIZE};
+use errors::{DungeonError, DungeonResult, ErrorKind};
+use file_system::{dir_size, FileSystem};
 use filter::Filter;
+use hyperloglog::HyperLogLog;
+use iterator::ChestIterator;
+use itertools::{kmerge, Either, Itertools};
 use mem_table::MemTable;
-use ss_table::SSTable;
-use value::TimeStampedValue;
+use options::ChestOptions;
+use rmp_serde::to_vec;
+use secondary_index::{check_user_key, is_index_key, IndexStates};
+use ss_table::{SSTable, TableSettings, FORMAT_VERSION};
+use stats::{ChestStats, Metrics, SSTableStats};
 
-use crate::value::Value;
+pub use ss_table::SSTableWriter;
+use value::{deleted_at, resolve_versions, RangeTombstone, RecordKind, TimeStampedValue, Value};
+use value_log::ValueLogReport;
+use wal::{Wal, WalEntry};
 
 pub struct Chest {
     dir_path: PathBuf,
     mem_table: MemTable,
-    flush_size: usize,
+    options: ChestOptions,
     sstables: BTreeSet<OrderedByDateSSTable>,
-    max_sstable_count: usize,
     filter: Box<dyn Filter + Send>,
-}
-
-fn generate_sstable_name() -> String {
-    let current_time = SystemTime::now();
-    let elapsed = current_time.duration_since(UNIX_EPOCH).unwrap();
-    format!("{}", elapsed.as_nanos())
+    clock: HybridLogicalClock,
+    metrics: Metrics,
+    index_states: IndexStates,
+    /// Sequence number of the latest committed write
+    sequence: u64,
+    /// Only writers with `ChestOptions::wal` keep one
+    wal: Option<Wal>,
+    subscribers: Subscribers,
+    /// Merge running in the background, see `ChestOptions::background_compaction`
+    compaction: Option<CompactionJob>,
+    compaction_control: Arc<CompactionControl>,
+    /// Set while writes are rejected for lack of disk space, see `is_degraded`
+    degraded: bool,
+    /// Held for as long as the chest is open. Read only chests don't take it
+    _lock: Option<Box<dyn Send + Sync>>,
 }
 
 impl Chest {
@@ -41,114 +90,1390 @@ impl Chest {
         dir_path: &str,
         flush_size: usize,
         max_sstable_count: usize,
+        filter: Box<dyn Filter + Send>,
+    ) -> DungeonResult<Self> {
+        Self::with_options(
+            dir_path,
+            ChestOptions {
+                flush_size,
+                max_sstable_count,
+                ..Default::default()
+            },
+            filter,
+        )
+    }
+    pub fn with_options(
+        dir_path: &str,
+        options: ChestOptions,
         mut filter: Box<dyn Filter + Send>,
     ) -> DungeonResult<Self> {
+        let options = encryption::apply(options)?;
+        secondary_index::validate(&options.indexes)?;
         let mut sstables = BTreeSet::new();
         let dir_path = PathBuf::from(dir_path);
-        if !dir_path.is_dir() {
-            std::fs::create_dir_all(&dir_path)
+        let fs = options.file_system.clone();
+        if options.read_only && !fs.is_dir(&dir_path) {
+            return Err(DungeonError::new("Could not find chest dir"));
+        }
+        if !fs.is_dir(&dir_path) {
+            fs.create_dir_all(&dir_path)
                 .map_err(|_| DungeonError::new("Could not create chest dir"))?;
         }
-        let dir_files =
-            std::fs::read_dir(&dir_path).map_err(|_| DungeonError::new("Could not read files"))?;
+        let lock = if options.read_only {
+            None
+        } else {
+            let lock = lock::acquire(fs.as_ref(), &dir_path)?;
+            migration::recover(fs.as_ref(), &dir_path)?;
+            ingest::recover(fs.as_ref(), &dir_path)?;
+            Some(lock)
+        };
+        let mut clock = HybridLogicalClock::load(fs.clone(), &dir_path)?;
+        let index_states = IndexStates::load(fs.clone(), &dir_path)?;
 
-        for file in dir_files {
-            let ok_file = file.map_err(|_| DungeonError::new("Invalid file"))?;
-            let file_path = ok_file.path();
-            match file_path.extension() {
-                Some(ok_path) => {
-                    if ok_path.to_str() == Some("index") {
-                        let sstable = SSTable::from_file(
-                            dir_path.clone(),
-                            file_path
-                                .file_stem()
-                                .ok_or(DungeonError::new("Could not get file stem"))?
-                                .to_str()
-                                .ok_or(DungeonError::new("Could not convert file path to string"))?
-                                .to_owned(),
-                        )?;
-                        for (key, _) in sstable.index.table.iter() {
-                            filter.insert(key);
-                        }
-                        sstables.insert(OrderedByDateSSTable(sstable));
-                    }
+        let loaded = Self::table_names(fs.as_ref(), &dir_path)?
+            .into_iter()
+            .map(|file_name| SSTable::from_file(dir_path.clone(), file_name, &options))
+            .collect::<DungeonResult<Vec<_>>>()?;
+        let compacted = Self::compacted_names(&loaded);
+        for mut sstable in loaded {
+            if compacted.contains(&sstable.file_name) {
+                if !options.read_only {
+                    sstable.delete_self()?;
                 }
-                None => unreachable!(),
+                continue;
             }
+            if sstable.index.version < FORMAT_VERSION {
+                if options.read_only {
+                    return Err(DungeonError::new(
+                        "Chest must be opened by a writer once to migrate it",
+                    ));
+                }
+                sstable = migration::migrate_sstable(sstable, &options)?;
+            }
+            for (key, _) in sstable.index.table.iter() {
+                filter.insert(key);
+            }
+            let sstable = OrderedByDateSSTable(sstable);
+            // Table names are timestamps issued after every record in them
+            clock.observe(sstable.get_date_milis());
+            sstables.insert(sstable);
         }
-        Ok(Self {
+        // Every write up to here made it into a table
+        let flushed = sstables
+            .iter()
+            .map(|sstable| sstable.0.index.last_sequence)
+            .max()
+            .unwrap_or(0);
+        let compaction_control = Arc::new(CompactionControl::new(options.compaction_rate));
+        let mut chest = Self {
             dir_path,
-            mem_table: MemTable::new(),
-            flush_size,
-            max_sstable_count,
+            mem_table: MemTable::new(options.history.is_enabled()),
+            options,
             sstables,
             filter,
-        })
+            clock,
+            metrics: Metrics::default(),
+            index_states,
+            sequence: flushed,
+            wal: None,
+            subscribers: Subscribers::default(),
+            compaction: None,
+            compaction_control,
+            degraded: false,
+            _lock: lock,
+        };
+        if !chest.options.read_only {
+            chest.replay_wal()?;
+            chest.declare_indexes()?;
+            if chest.options.wal {
+                chest.wal = Some(Wal::open(
+                    fs,
+                    &chest.dir_path,
+                    chest.sequence + 1,
+                    chest.options.wal_retention,
+                )?);
+            }
+        }
+        Ok(chest)
     }
-    pub fn set(&mut self, key: &str, value: TimeStampedValue) -> DungeonResult<()> {
-        self.mem_table.set(key, value);
-        self.filter.insert(key);
-        if self.mem_table.size() >= self.flush_size {
+    /// Applies the writes in the log that never got flushed. The log is read even when the chest
+    /// is opened without `ChestOptions::wal`, so turning it off loses nothing
+    fn replay_wal(&mut self) -> DungeonResult<()> {
+        let entries = wal::read_entries(self.options.file_system.as_ref(), &self.dir_path)?;
+        let flushed = self.sequence;
+        for entry in entries {
+            if entry.event.sequence <= flushed {
+                continue;
+            }
+            self.clock.observe(entry.record.timestamp);
+            self.apply(&entry.event, entry.record)?;
+            self.sequence = entry.event.sequence;
+        }
+        Ok(())
+    }
+    /// Removes the entries of the indexes that were dropped or redefined since the last time the
+    /// chest was opened, and starts building the new ones
+    fn declare_indexes(&mut self) -> DungeonResult<()> {
+        let stale: Vec<String> = self
+            .index_states
+            .stale(&self.options.indexes)
+            .map(str::to_owned)
+            .collect();
+        for name in stale {
+            let entries_prefix = secondary_index::entries_prefix(&name);
+            let entries: Vec<String> = self
+                .keys_in(entries_prefix.clone()..)
+                .take_while(|key| key.starts_with(&entries_prefix))
+                .cloned()
+                .collect();
+            for entry in entries {
+                let timestamp = self.clock.tick();
+                self.mem_table
+                    .set(&entry, TimeStampedValue::tombstone(timestamp));
+            }
+            self.index_states.remove(&name);
+        }
+        self.index_states.start_builds(&self.options.indexes);
+        // The removed entries must be gone before their index is
+        if self.mem_table.size() > 0 {
+            self.flush()?;
+        }
+        self.index_states.persist()
+    }
+    /// Names of the sstables in `dir_path`, taken from their index files
+    fn table_names(fs: &dyn FileSystem, dir_path: &Path) -> DungeonResult<Vec<String>> {
+        let dir_files = fs
+            .read_dir(dir_path)
+            .map_err(|_| DungeonError::new("Could not read files"))?;
+        let mut names = Vec::new();
+        for file_path in dir_files {
+            if file_path.extension().and_then(|ext| ext.to_str()) != Some("index") {
+                continue;
+            }
+            names.push(
+                file_path
+                    .file_stem()
+                    .ok_or(DungeonError::new("Could not get file stem"))?
+                    .to_str()
+                    .ok_or(DungeonError::new("Could not convert file path to string"))?
+                    .to_owned(),
+            );
+        }
+        Ok(names)
+    }
+    /// Tables already merged into one of `sstables`. They are only left behind when the chest
+    /// stopped between writing the merged table and removing them
+    fn compacted_names<'a>(sstables: impl IntoIterator<Item = &'a SSTable>) -> BTreeSet<String> {
+        sstables
+            .into_iter()
+            .flat_map(|sstable| sstable.index.compacted.iter().cloned())
+            .collect()
+    }
+    /// Picks up the sstables the writer flushed or merged since this read only chest was opened or
+    /// last refreshed
+    pub fn refresh(&mut self) -> DungeonResult<()> {
+        if !self.options.read_only {
+            return Err(DungeonError::new("Only read only chests can be refreshed"));
+        }
+        let names = Self::table_names(self.options.file_system.as_ref(), &self.dir_path)?;
+        self.sstables
+            .retain(|sstable| names.contains(&sstable.0.file_name));
+        for name in names {
+            if self
+                .sstables
+                .iter()
+                .any(|sstable| sstable.0.file_name == name)
+            {
+                continue;
+            }
+            // The writer may have merged the table away after the dir was listed
+            let Ok(sstable) = SSTable::from_file(self.dir_path.clone(), name, &self.options) else {
+                continue;
+            };
+            if sstable.index.version < FORMAT_VERSION {
+                continue;
+            }
+            for key in sstable.index.table.keys() {
+                self.filter.insert(key);
+            }
+            self.sstables.insert(OrderedByDateSSTable(sstable));
+        }
+        let compacted = Self::compacted_names(self.sstables.iter().map(|sstable| &sstable.0));
+        self.sstables
+            .retain(|sstable| !compacted.contains(&sstable.0.file_name));
+        Ok(())
+    }
+    fn check_writable(&self) -> DungeonResult<()> {
+        if self.options.read_only {
+            return Err(DungeonError::new("Chest is read only"));
+        }
+        Ok(())
+    }
+    pub fn set(&mut self, key: &str, value: Value) -> DungeonResult<()> {
+        self.commit(vec![(key.to_owned(), Operation::Set, value)])
+    }
+    /// Commits every write of `batch`, in order. Either all of them are applied or none is
+    pub fn write_batch(&mut self, batch: WriteBatch) -> DungeonResult<()> {
+        self.commit(batch.writes)
+    }
+    /// Logs `writes` when the write ahead log is on, applies them and sends their change events
+    fn commit(&mut self, writes: Vec<(String, Operation, Value)>) -> DungeonResult<()> {
+        self.check_writable()?;
+        for (key, operation, value) in &writes {
+            match operation {
+                Operation::Delete => self.check_write(key, None)?,
+                Operation::DeleteRange => Self::check_range(key, value)?,
+                _ => self.check_write(key, Some(value))?,
+            }
+        }
+        self.check_space(&writes)?;
+        // Old and new values take a lookup per write, only done when something reads them
+        let observed = self.wal.is_some() || !self.subscribers.is_empty();
+        // Latest records of the keys written earlier in the batch
+        let mut pending: BTreeMap<String, Option<TimeStampedValue>> = BTreeMap::new();
+        // Range deletes of the batch, which the chest doesn't see yet
+        let mut deleted_ranges = Vec::new();
+        let mut entries = Vec::with_capacity(writes.len());
+        for (sequence, (key, operation, value)) in (self.sequence + 1..).zip(writes) {
+            let timestamp = self.clock.tick();
+            if let (Operation::DeleteRange, Value::String(end)) = (operation, &value) {
+                let tombstone = RangeTombstone {
+                    start: key.clone(),
+                    end: end.clone(),
+                    timestamp,
+                };
+                for (_, before) in pending.range_mut(key.clone()..end.clone()) {
+                    *before = Some(TimeStampedValue::tombstone(timestamp));
+                }
+                deleted_ranges.push(tombstone);
+                entries.push(WalEntry {
+                    event: ChangeEvent {
+                        sequence,
+                        key,
+                        operation,
+                        old: None,
+                        new: None,
+                        timestamp,
+                        end: Some(end.clone()),
+                    },
+                    record: TimeStampedValue::tombstone(timestamp),
+                });
+                continue;
+            }
+            let record = match operation {
+                Operation::Set => TimeStampedValue::new(value, timestamp),
+                Operation::Merge => TimeStampedValue::merge_operand(value, timestamp),
+                _ => TimeStampedValue::tombstone(timestamp),
+            };
+            let (old, new) = if observed {
+                let before = match pending.get(&key) {
+                    Some(before) => before.clone(),
+                    None => match deleted_at(&deleted_ranges, &key) {
+                        0 => self.lookup(&key)?,
+                        deleted => Some(TimeStampedValue::tombstone(deleted)),
+                    },
+                };
+                let after = match before.clone() {
+                    Some(before) => record.clone().merge_onto(before),
+                    None => record.clone(),
+                };
+                pending.insert(key.clone(), Some(after.clone()));
+                let visible = |found: Option<TimeStampedValue>| {
+                    found?.into_visible().map(|found| found.value)
+                };
+                (visible(before), visible(Some(after)))
+            } else {
+                (None, None)
+            };
+            entries.push(WalEntry {
+                event: ChangeEvent {
+                    sequence,
+                    key,
+                    operation,
+                    old,
+                    new,
+                    timestamp,
+                    end: None,
+                },
+                record,
+            });
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.append(&entries)?;
+        }
+        let mut events = Vec::with_capacity(entries.len());
+        for entry in entries {
+            self.apply(&entry.event, entry.record)?;
+            self.sequence = entry.event.sequence;
+            events.push(entry.event);
+        }
+        self.subscribers.publish(&events);
+        if self.mem_table.size() >= self.options.flush_size {
             self.flush()?;
         }
         Ok(())
     }
+    /// Rejects the keys and values `ChestOptions` doesn't allow
+    fn check_write(&self, key: &str, value: Option<&Value>) -> DungeonResult<()> {
+        check_user_key(key)?;
+        if key.len() > self.options.max_key_size {
+            return Err(DungeonError::with_kind(
+                ErrorKind::KeyTooLarge,
+                "Key is longer than the maximum key size",
+            ));
+        }
+        if !self.options.key_charset.allows(key) {
+            return Err(DungeonError::with_kind(
+                ErrorKind::InvalidKey,
+                "Key is empty or has characters that aren't allowed",
+            ));
+        }
+        let Some(value) = value else {
+            return Ok(());
+        };
+        let encoded = to_vec(value).map_err(|_| DungeonError::new("Could not parse value"))?;
+        if encoded.len() > self.options.max_value_size {
+            return Err(DungeonError::with_kind(
+                ErrorKind::ValueTooLarge,
+                "Value is larger than the maximum value size",
+            ));
+        }
+        Ok(())
+    }
+    /// Rejects the ranges `delete_range` can't delete, which are the empty ones and the ones
+    /// starting inside the index entries
+    fn check_range(start: &str, end: &Value) -> DungeonResult<()> {
+        check_user_key(start)?;
+        match end {
+            Value::String(end) if start < end.as_str() => Ok(()),
+            _ => Err(DungeonError::with_kind(
+                ErrorKind::InvalidKey,
+                "Range must end after its start",
+            )),
+        }
+    }
+    /// Rejects `writes` when they would start a flush going past `ChestOptions::disk_quota`,
+    /// degrading the chest. While degraded, writes first retry the flush that failed
+    fn check_space(&mut self, writes: &[(String, Operation, Value)]) -> DungeonResult<()> {
+        let flush_due =
+            self.degraded || self.mem_table.size() + writes.len() >= self.options.flush_size;
+        if !flush_due {
+            return Ok(());
+        }
+        if self.options.disk_quota.is_some() {
+            let incoming: usize = writes
+                .iter()
+                .map(|(key, _, value)| key.len() + to_vec(value).map_or(0, |encoded| encoded.len()))
+                .sum();
+            let needed = self.flush_footprint((self.mem_table.bytes(..) + incoming) as u64);
+            self.check_quota(needed)
+                .inspect_err(|_| self.degraded = true)?;
+        }
+        if self.degraded && self.mem_table.size() > 0 {
+            self.flush().map_err(|_| {
+                DungeonError::with_kind(
+                    ErrorKind::Degraded,
+                    "Chest is read only until the memtable can be flushed",
+                )
+            })?;
+        }
+        self.degraded = false;
+        Ok(())
+    }
+    /// Rejects writing `needed` more bytes when they would take the chest past
+    /// `ChestOptions::disk_quota`
+    fn check_quota(&self, needed: u64) -> DungeonResult<()> {
+        let Some(quota) = self.options.disk_quota else {
+            return Ok(());
+        };
+        if self.disk_usage()? + needed > quota {
+            return Err(DungeonError::with_kind(
+                ErrorKind::QuotaExceeded,
+                "Write would take the chest past its disk quota",
+            ));
+        }
+        Ok(())
+    }
+    /// Bytes a flush of `flush_bytes` may add before the tables it replaces are removed: the new
+    /// table, plus the merge that may follow it
+    fn flush_footprint(&self, flush_bytes: u64) -> u64 {
+        // The index takes less than the data it points at
+        let table = flush_bytes * 2;
+        if self.sstables.len() < self.options.max_sstable_count {
+            return table;
+        }
+        let newest = self
+            .sstables
+            .first()
+            .map_or(0, |sstable| sstable.0.data_size() * 2);
+        table * 2 + newest
+    }
+    /// Bytes taken by the files of the chest, as counted against `ChestOptions::disk_quota`
+    pub fn disk_usage(&self) -> DungeonResult<u64> {
+        dir_size(self.options.file_system.as_ref(), &self.dir_path)
+            .map_err(|_| DungeonError::new("Could not read disk usage"))
+    }
+    /// Whether writes are rejected, either because they would have gone past the disk quota or
+    /// because a flush failed. Reads keep working, and the chest leaves this mode on the first
+    /// write that fits in the quota and gets the pending flush through
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+    /// Puts the record of a committed write in the memtable along with its index entries
+    fn apply(&mut self, event: &ChangeEvent, record: TimeStampedValue) -> DungeonResult<()> {
+        let key = event.key.as_str();
+        if let Some(end) = &event.end {
+            self.mem_table.delete_range(RangeTombstone {
+                start: key.to_owned(),
+                end: end.clone(),
+                timestamp: record.timestamp,
+            });
+            return Ok(());
+        }
+        let indexed_before = self.index_entries(key)?;
+        if record.kind == RecordKind::Merge {
+            self.mem_table.merge(key, record);
+        } else {
+            self.mem_table.set(key, record);
+        }
+        self.filter.insert(key);
+        self.update_indexes(key, indexed_before)
+    }
+    /// Sequence number of the latest committed write, 0 before the first one. Every write of a
+    /// batch gets its own
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+    /// Sends the change event of every write committed from now on to a key starting with
+    /// `prefix`. The subscription ends when the receiver is dropped
+    pub fn subscribe(&mut self, prefix: &str) -> Receiver<ChangeEvent> {
+        self.subscribers.add(prefix, Vec::new())
+    }
+    /// Same as `subscribe`, first sending the events of the writes from `sequence` on that are
+    /// still in the write ahead log. Fails when some of them aren't anymore, see
+    /// `ChestOptions::wal_retention`
+    pub fn subscribe_from(
+        &mut self,
+        prefix: &str,
+        sequence: u64,
+    ) -> DungeonResult<Receiver<ChangeEvent>> {
+        if self.wal.is_none() {
+            return Err(DungeonError::new("Write ahead log is disabled"));
+        }
+        let entries = wal::read_entries(self.options.file_system.as_ref(), &self.dir_path)?;
+        let oldest = entries
+            .first()
+            .map_or(self.sequence + 1, |entry| entry.event.sequence);
+        if sequence.max(1) < oldest {
+            return Err(DungeonError::new(
+                "Sequence is no longer in the write ahead log",
+            ));
+        }
+        let backlog = entries
+            .into_iter()
+            .map(|entry| entry.event)
+            .filter(|event| event.sequence >= sequence)
+            .collect();
+        Ok(self.subscribers.add(prefix, backlog))
+    }
+    /// Entry keys of `key` for its current value, one per index covering it. Empty when no index
+    /// covers the key
+    fn index_entries(&self, key: &str) -> DungeonResult<Vec<Option<String>>> {
+        if !self.options.indexes.iter().any(|index| index.covers(key)) {
+            return Ok(Vec::new());
+        }
+        let current = self.lookup(key)?.and_then(TimeStampedValue::into_visible);
+        self.options
+            .indexes
+            .iter()
+            .filter(|index| index.covers(key))
+            .map(|index| match &current {
+                Some(current) => index.entry_key(key, &current.value),
+                None => Ok(None),
+            })
+            .collect()
+    }
+    /// Replaces the index entries `key` had before a write with the ones of its new value. They
+    /// go into the memtable along with the write, so they are flushed together
+    fn update_indexes(&mut self, key: &str, before: Vec<Option<String>>) -> DungeonResult<()> {
+        if before.is_empty() {
+            return Ok(());
+        }
+        let after = self.index_entries(key)?;
+        for (old, new) in before.into_iter().zip(after) {
+            if old == new {
+                continue;
+            }
+            if let Some(old) = old {
+                let timestamp = self.clock.tick();
+                self.mem_table
+                    .set(&old, TimeStampedValue::tombstone(timestamp));
+            }
+            if let Some(new) = new {
+                let timestamp = self.clock.tick();
+                self.mem_table
+                    .set(&new, TimeStampedValue::new(Value::Null, timestamp));
+            }
+        }
+        Ok(())
+    }
+    /// Index entries of the `keys` an import or ingestion is about to write, for `update_indexes`
+    /// once their tables are live. Keys no index covers are left out
+    fn index_entries_of<'a>(
+        &self,
+        keys: impl Iterator<Item = &'a String>,
+    ) -> DungeonResult<Vec<(String, Vec<Option<String>>)>> {
+        let mut entries = Vec::new();
+        for key in keys {
+            let before = self.index_entries(key)?;
+            if !before.is_empty() {
+                entries.push((key.clone(), before));
+            }
+        }
+        Ok(entries)
+    }
+    /// Every key with a record in `range`, sorted, including deleted keys and index entries
+    fn keys_in<'a>(
+        &'a self,
+        range: impl RangeBounds<String> + Clone + 'a,
+    ) -> impl Iterator<Item = &'a String> + 'a {
+        let mem_table_keys = Either::Left(self.mem_table.keys_in(range.clone()));
+        let sstable_keys = self.sstables.iter().map(move |sstable| {
+            Either::Right(
+                sstable
+                    .0
+                    .index
+                    .table
+                    .range(range.clone())
+                    .map(|(key, _)| key),
+            )
+        });
+        kmerge(std::iter::once(mem_table_keys).chain(sstable_keys)).dedup()
+    }
+    /// Keys whose value the index `name` maps to `indexed`, with their values, sorted by key
+    pub fn index_scan(
+        &self,
+        name: &str,
+        indexed: &Value,
+    ) -> DungeonResult<impl Iterator<Item = DungeonResult<(String, TimeStampedValue)>> + '_> {
+        let index = self
+            .options
+            .indexes
+            .iter()
+            .find(|index| index.name == name)
+            .ok_or(DungeonError::new("Could not find index"))?;
+        if !self.index_states.is_ready(name) {
+            return Err(DungeonError::new("Index is still being built"));
+        }
+        let value_prefix = index.value_prefix(indexed)?;
+        let prefix_len = value_prefix.len();
+        let entries = self
+            .keys_in(value_prefix.clone()..)
+            .take_while(move |entry| entry.starts_with(&value_prefix));
+        Ok(entries.filter_map(move |entry| {
+            let live = match self.lookup(entry) {
+                Ok(found) => found.and_then(TimeStampedValue::into_visible).is_some(),
+                Err(err) => return Some(Err(err)),
+            };
+            if !live {
+                return None;
+            }
+            let key = &entry[prefix_len..];
+            let value = match self.lookup(key) {
+                Ok(found) => found.and_then(TimeStampedValue::into_visible)?,
+                Err(err) => return Some(Err(err)),
+            };
+            // Range deletes leave the entries of the keys they cover behind
+            match index.entry_key(key, &value.value) {
+                Ok(current) if current.as_deref() == Some(entry.as_str()) => {
+                    Some(Ok((key.to_owned(), value)))
+                }
+                Ok(_) => None,
+                Err(err) => Some(Err(err)),
+            }
+        }))
+    }
+    /// Goes through up to `max_keys` more keys of every index that is still being built. Returns
+    /// whether every index is ready
+    pub fn build_indexes(&mut self, max_keys: usize) -> DungeonResult<bool> {
+        self.check_writable()?;
+        for index in self.options.indexes.clone() {
+            let Some(after) = self.index_states.build_position(&index.name) else {
+                continue;
+            };
+            let start = match after {
+                Some(after) => Bound::Excluded(after),
+                None => Bound::Included(index.prefix.clone()),
+            };
+            let keys: Vec<String> = self
+                .keys_in((start, Bound::Unbounded))
+                .take_while(|key| key.starts_with(&index.prefix))
+                .filter(|key| index.covers(key))
+                .take(max_keys)
+                .cloned()
+                .collect();
+            for key in &keys {
+                let Some(current) = self.lookup(key)?.and_then(TimeStampedValue::into_visible)
+                else {
+                    continue;
+                };
+                if let Some(entry) = index.entry_key(key, &current.value)? {
+                    let timestamp = self.clock.tick();
+                    self.mem_table
+                        .set(&entry, TimeStampedValue::new(Value::Null, timestamp));
+                }
+            }
+            let done = keys.len() < max_keys;
+            self.index_states
+                .advance(&index, (!done).then(|| keys.last().cloned()).flatten());
+        }
+        Ok(!self.index_states.is_building())
+    }
     pub fn get(&self, key: &str) -> DungeonResult<Option<TimeStampedValue>> {
         if !self.filter.contains(key) {
+            Metrics::add(&self.metrics.filter_negatives, 1);
             return Ok(None);
         }
-        match self.mem_table.get(key) {
-            None => {
-                for sstable in &self.sstables {
-                    if let Some(found) = sstable.0.get(key)? {
-                        if found.value == Value::Invalid {
-                            return Ok(None);
-                        }
-                        return Ok(Some(found));
-                    }
-                }
-                Ok(None)
+        Metrics::add(&self.metrics.filter_positives, 1);
+        let found = self.lookup(key)?;
+        if found.is_none() {
+            Metrics::add(&self.metrics.filter_false_positives, 1);
+        }
+        Ok(found.and_then(TimeStampedValue::into_visible))
+    }
+    /// Latest record of `key`, with every merge operand applied, without checking the filter
+    fn lookup(&self, key: &str) -> DungeonResult<Option<TimeStampedValue>> {
+        let deleted = self.range_deleted_at(key);
+        let mut found = self.mem_table.get(key);
+        for sstable in &self.sstables {
+            // Only merge operands need to look further into older tables
+            if matches!(&found, Some(newer) if newer.kind != RecordKind::Merge) {
+                break;
             }
-            Some(default) => {
-                if default.value == Value::Invalid {
-                    return Ok(None);
-                }
-                Ok(Some(default))
+            let older = match sstable.0.get(key)? {
+                // Hidden by a range delete, along with everything older
+                Some(older) if older.timestamp < deleted => TimeStampedValue::tombstone(deleted),
+                Some(older) => older,
+                None => continue,
+            };
+            found = Some(match found {
+                Some(newer) => newer.merge_onto(older),
+                None => older,
+            });
+        }
+        Ok(found)
+    }
+    /// Range tombstones of the memtable and every sstable
+    fn range_tombstones(&self) -> impl Iterator<Item = &RangeTombstone> {
+        let sstables = self
+            .sstables
+            .iter()
+            .flat_map(|sstable| &sstable.0.index.range_tombstones);
+        self.mem_table.range_tombstones().iter().chain(sstables)
+    }
+    /// Timestamp of the latest range delete covering `key`, 0 when there is none
+    fn range_deleted_at(&self, key: &str) -> u128 {
+        deleted_at(self.range_tombstones(), key)
+    }
+    /// Value of `key` as it was right after `timestamp`. Only versions kept by
+    /// `ChestOptions::history` can be read
+    pub fn get_at(&self, key: &str, timestamp: u128) -> DungeonResult<Option<TimeStampedValue>> {
+        let found = self
+            .history(key)?
+            .into_iter()
+            .find(|version| version.timestamp <= timestamp);
+        Ok(found.and_then(TimeStampedValue::into_visible))
+    }
+    /// Every kept version of `key`, newest first. Deletes show up as tombstones
+    pub fn history(&self, key: &str) -> DungeonResult<Vec<TimeStampedValue>> {
+        if !self.filter.contains(key) {
+            return Ok(Vec::new());
+        }
+        let mut versions = self.mem_table.get_versions(key);
+        for sstable in &self.sstables {
+            versions.extend(sstable.0.get_versions(key)?);
+        }
+        // Every range delete covering the key reads as a delete of its own
+        if !versions.is_empty() {
+            versions.extend(
+                self.range_tombstones()
+                    .filter(|tombstone| tombstone.covers(key))
+                    .map(|tombstone| TimeStampedValue::tombstone(tombstone.timestamp)),
+            );
+        }
+        Ok(resolve_versions(versions))
+    }
+    /// Cursor over the live keys, see `ChestIterator`
+    pub fn iter(&self) -> ChestIterator<'_> {
+        ChestIterator::new(self, None)
+    }
+    /// Cursor over the keys as they were right after `timestamp`, as `get_at` reads them. Only
+    /// versions kept by `ChestOptions::history` can be read
+    pub fn iter_at(&self, timestamp: u128) -> ChestIterator<'_> {
+        ChestIterator::new(self, Some(timestamp))
+    }
+    /// Timestamp after every write so far, for `iter_at` and `get_at` to read the chest as it is
+    /// now while it keeps changing
+    pub fn snapshot(&mut self) -> u128 {
+        self.clock.tick()
+    }
+    /// Every live key with its value, sorted by key
+    pub fn scan(&self) -> impl Iterator<Item = DungeonResult<(String, TimeStampedValue)>> + '_ {
+        self.keys_in(..)
+            .filter(|key| !is_index_key(key))
+            .filter_map(|key| {
+                self.lookup(key)
+                    .map(|found| found.and_then(TimeStampedValue::into_visible))
+                    .transpose()
+                    .map(|found| found.map(|value| (key.clone(), value)))
+            })
+    }
+    /// Writes every live key and value to `w`, returning the amount of exported entries
+    pub fn export(&self, w: impl Write, format: DumpFormat) -> DungeonResult<usize> {
+        let mut w = BufWriter::new(w);
+        let mut exported = 0;
+        for entry in self.scan() {
+            let (key, value) = entry?;
+            let entry = DumpEntry {
+                key,
+                value: value.value,
+            };
+            format.write_entry(&mut w, &entry)?;
+            exported += 1;
+        }
+        w.flush()
+            .map_err(|_| DungeonError::new("Could not write dump entry"))?;
+        Ok(exported)
+    }
+    /// Loads a dump written by `export`, returning the amount of imported entries. Entries are
+    /// written straight into new sstables of up to `IMPORT_TABLE_SIZE` entries instead of going
+    /// through the memtable. Later entries of a repeated key win
+    pub fn import(&mut self, r: impl Read, format: DumpFormat) -> DungeonResult<usize> {
+        self.check_writable()?;
+        // Anything left in the memtable is older than the import, but would shadow it on reads
+        if self.mem_table.size() > 0 {
+            self.flush()?;
+        }
+        let mut r = BufReader::new(r);
+        let mut imported = 0;
+        loop {
+            let mut batch = Vec::new();
+            let mut batch_bytes = 0;
+            while batch.len() < IMPORT_TABLE_SIZE {
+                let Some(entry) = format.read_entry(&mut r)? else {
+                    break;
+                };
+                self.check_write(&entry.key, Some(&entry.value))?;
+                batch_bytes +=
+                    entry.key.len() + to_vec(&entry.value).map_or(0, |encoded| encoded.len());
+                let value = TimeStampedValue::new(entry.value, self.clock.tick());
+                batch.push((entry.key, value));
+            }
+            if batch.is_empty() {
+                break;
+            }
+            // Written as a table of its own, the same way a flush would
+            self.check_quota(self.flush_footprint(batch_bytes as u64))?;
+            imported += batch.len();
+            let is_last = batch.len() < IMPORT_TABLE_SIZE;
+            // Stable, so the versions of a repeated key stay in timestamp order
+            batch.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (key, _) in &batch {
+                self.filter.insert(key);
+            }
+            let indexed_before = self.index_entries_of(batch.iter().map(|(key, _)| key).dedup())?;
+            let file_name = self.next_sstable_name();
+            let settings = self.table_settings(&file_name);
+            let sstable = SSTable::write_table(
+                self.dir_path.clone(),
+                file_name,
+                batch.into_iter().map(Ok).peekable(),
+                &self.options,
+                settings,
+            )?;
+            self.sstables.insert(OrderedByDateSSTable(sstable));
+            for (key, before) in indexed_before {
+                self.update_indexes(&key, before)?;
             }
+            if is_last {
+                break;
+            }
+        }
+        if self.mem_table.size() >= self.options.flush_size {
+            self.flush()?;
         }
+        self.clock.persist()?;
+        Ok(imported)
     }
     pub fn delete(&mut self, key: &str) -> DungeonResult<()> {
-        self.set(key, TimeStampedValue::new(Value::Invalid))?;
-        Ok(())
+        self.commit(vec![(key.to_owned(), Operation::Delete, Value::Null)])
+    }
+    /// Deletes every key in `start..end` with a single range tombstone, however many keys it
+    /// covers. Reads skip the covered keys right away, compaction drops their records later
+    pub fn delete_range(&mut self, start: &str, end: &str) -> DungeonResult<()> {
+        self.commit(vec![(
+            start.to_owned(),
+            Operation::DeleteRange,
+            Value::String(end.to_owned()),
+        )])
+    }
+    /// Stores a merge operand that is combined with the current value of the key through
+    /// `Value::merge` when read
+    pub fn merge(&mut self, key: &str, operand: Value) -> DungeonResult<()> {
+        self.commit(vec![(key.to_owned(), Operation::Merge, operand)])
     }
     fn flush(&mut self) -> DungeonResult<()> {
+        if self.options.read_only {
+            return Ok(());
+        }
+        let flushed = self.try_flush();
+        // Most likely out of disk space, writes are rejected until a flush goes through
+        self.degraded = flushed.is_err();
+        flushed
+    }
+    fn try_flush(&mut self) -> DungeonResult<()> {
+        if self.index_states.is_building() {
+            self.build_indexes(secondary_index::BUILD_BATCH)?;
+        }
         // Maps (String, Value) into a DungeonResult<(String, Value)> so it is complatible with the
         // `new` sstable method
-        let flushed = self.mem_table.flush().into_iter();
-        let file_name = generate_sstable_name();
-        let mut ss_table = SSTable::new(self.dir_path.clone(), file_name, flushed.peekable())?;
-        if self.sstables.len() >= self.max_sstable_count {
-            // Pick the oldest sstable and merge it with the new one. Since every merge result will
-            // be placed at the end of the sstable list, the start will mostly have the smaller
-            // ones
-            let mut smaller = self
-                .sstables
-                .pop_first()
-                .ok_or(DungeonError::new("Could not get smaller sstable"))?;
-            let merged = smaller.0.merge(&mut ss_table, generate_sstable_name())?;
-            self.sstables.insert(OrderedByDateSSTable(merged));
-            smaller.0.delete_self()?;
-            ss_table.delete_self()?;
-        } else {
-            self.sstables.insert(OrderedByDateSSTable(ss_table));
+        let (flushed, range_tombstones) = self.mem_table.flush();
+        let file_name = self.next_sstable_name();
+        let started = Instant::now();
+        let settings = TableSettings {
+            range_tombstones: range_tombstones.clone(),
+            ..self.table_settings(&file_name)
+        };
+        let written = SSTable::write_table(
+            self.dir_path.clone(),
+            file_name,
+            flushed.clone().into_iter().map(Ok).peekable(),
+            &self.options,
+            settings,
+        );
+        let ss_table = match written {
+            Ok(ss_table) => ss_table,
+            Err(err) => {
+                self.mem_table.restore(flushed, range_tombstones);
+                return Err(err);
+            }
+        };
+        Metrics::add(&self.metrics.flushes, 1);
+        Metrics::add_time(&self.metrics.flush_nanos, started.elapsed());
+        Metrics::add(&self.metrics.flushed_bytes, ss_table.data_size());
+        self.sstables.insert(OrderedByDateSSTable(ss_table));
+        self.finish_compaction(false)?;
+        self.compact()?;
+        if let Some(wal) = &mut self.wal {
+            wal.rotate(self.sequence + 1)?;
+        }
+        self.clock.persist()?;
+        self.index_states.persist()
+    }
+    /// Merges the two newest sstables while there are more than `max_sstable_count`, unless
+    /// compaction is paused. Since every merge result is newer than the rest, the older tables
+    /// are mostly the bigger ones. In the background only one merge runs at a time
+    fn compact(&mut self) -> DungeonResult<()> {
+        while self.sstables.len() > self.options.max_sstable_count
+            && self.compaction.is_none()
+            && !self.compaction_control.is_paused()
+        {
+            let mut inputs = vec![
+                self.sstables
+                    .pop_first()
+                    .ok_or(DungeonError::new("Could not get sstable to merge"))?
+                    .0,
+            ];
+            inputs.extend(self.sstables.pop_first().map(|sstable| sstable.0));
+            // A tombstone still hides the older records of its key in the tables left out of
+            // the merge
+            let drop_tombstones = self.sstables.is_empty();
+            let file_name = self.next_sstable_name();
+            if self.options.background_compaction {
+                // Reads keep going to the inputs until the merged table is installed. It is
+                // named now, so it sorts before every table flushed in the meantime
+                let mut merged_inputs = inputs.clone();
+                let options = self.options.clone();
+                let settings = self.compaction_settings(drop_tombstones);
+                self.sstables
+                    .extend(inputs.into_iter().map(OrderedByDateSSTable));
+                self.compaction = Some(CompactionJob::spawn(move || {
+                    SSTable::merge(&mut merged_inputs, file_name, &options, settings)
+                }));
+            } else {
+                self.merge_tables(inputs, file_name, drop_tombstones)?;
+            }
+        }
+        Ok(())
+    }
+    /// Stops starting compactions, holding the one that is running, until
+    /// `resume_compaction`. SSTables pile up past `max_sstable_count` in the meantime
+    pub fn pause_compaction(&self) {
+        self.compaction_control.set_paused(true);
+    }
+    /// Lets compaction go on, merging the tables that piled up on the next flushes
+    pub fn resume_compaction(&self) {
+        self.compaction_control.set_paused(false);
+    }
+    /// Merges every sstable holding keys in `start..end` into a single table, along with the
+    /// tables newer than them, since a merged table is read before every older one. Tombstones
+    /// are dropped when the oldest table is merged. Runs right away at `compaction_rate`, even
+    /// while compaction is paused
+    pub fn compact_range(&mut self, start: &str, end: &str) -> DungeonResult<()> {
+        self.check_writable()?;
+        if self.mem_table.size() > 0 {
+            self.flush()?;
+        }
+        self.finish_compaction(true)?;
+        let range = start.to_owned()..end.to_owned();
+        // Tables are sorted newest first
+        let Some(oldest) = self
+            .sstables
+            .iter()
+            .rposition(|sstable| sstable.0.index.table.range(range.clone()).next().is_some())
+        else {
+            return Ok(());
+        };
+        let drop_tombstones = oldest + 1 == self.sstables.len();
+        if oldest == 0 && !drop_tombstones {
+            return Ok(());
+        }
+        let inputs: Vec<SSTable> = (0..=oldest)
+            .filter_map(|_| self.sstables.pop_first())
+            .map(|sstable| sstable.0)
+            .collect();
+        let file_name = self.next_sstable_name();
+        // Nothing else is merging, the pause only holds the background merges back
+        self.compaction_control.set_draining(true);
+        let merged = self.merge_tables(inputs, file_name, drop_tombstones);
+        self.compaction_control.set_draining(false);
+        merged?;
+        self.clock.persist()
+    }
+    /// Merges `inputs`, taken out of the live tables, right away. They go back in if it fails
+    fn merge_tables(
+        &mut self,
+        inputs: Vec<SSTable>,
+        file_name: String,
+        drop_tombstones: bool,
+    ) -> DungeonResult<()> {
+        let started = Instant::now();
+        let settings = self.compaction_settings(drop_tombstones);
+        // Merging takes the indexes out of the tables it is given
+        let merged = SSTable::merge(&mut inputs.clone(), file_name, &self.options, settings);
+        match merged {
+            Ok(merged) => self.install_compaction(merged, &inputs, started.elapsed()),
+            Err(err) => {
+                self.sstables
+                    .extend(inputs.into_iter().map(OrderedByDateSSTable));
+                Err(err)
+            }
+        }
+    }
+    fn compaction_settings(&self, drop_tombstones: bool) -> TableSettings {
+        TableSettings {
+            drop_tombstones,
+            control: Some(self.compaction_control.clone()),
+            ..Default::default()
+        }
+    }
+    /// Replaces the `inputs` of a merge with its result
+    fn install_compaction(
+        &mut self,
+        merged: SSTable,
+        inputs: &[SSTable],
+        elapsed: Duration,
+    ) -> DungeonResult<()> {
+        Metrics::add(&self.metrics.compactions, 1);
+        Metrics::add_time(&self.metrics.compaction_nanos, elapsed);
+        Metrics::add(&self.metrics.compacted_bytes, merged.data_size());
+        self.sstables.insert(OrderedByDateSSTable(merged));
+        for input in inputs {
+            self.sstables
+                .retain(|sstable| sstable.0.file_name != input.file_name);
+            input.delete_self()?;
+        }
+        Ok(())
+    }
+    /// Installs the result of the background merge once it is done, or right away when `wait`
+    /// is set. A paused merge is let through while waiting
+    fn finish_compaction(&mut self, wait: bool) -> DungeonResult<()> {
+        let Some(job) = self.compaction.take_if(|job| wait || job.is_finished()) else {
+            return Ok(());
+        };
+        self.compaction_control.set_draining(true);
+        let joined = job.join();
+        self.compaction_control.set_draining(false);
+        let (merged, elapsed) = joined?;
+        let inputs: Vec<SSTable> = self
+            .sstables
+            .iter()
+            .filter(|sstable| merged.index.compacted.contains(&sstable.0.file_name))
+            .map(|sstable| sstable.0.clone())
+            .collect();
+        self.install_compaction(merged, &inputs, elapsed)
+    }
+    /// Settings of a table written from the memtable or an import, with large values going to a
+    /// value log named after it
+    fn table_settings(&self, file_name: &str) -> TableSettings {
+        TableSettings {
+            value_log: SSTable::value_log_for(&self.dir_path, file_name, &self.options),
+            last_sequence: self.sequence,
+            ..Default::default()
+        }
+    }
+    /// Reclaims the space of the values in value logs that compaction dropped since they were
+    /// written. Logs nothing points at anymore are removed. The tables pointing into a log with
+    /// at least `min_garbage_ratio` of dead bytes get every value moved to a new log, so the old
+    /// log can be removed as well. Rewritten tables keep their name, read only chests opened next
+    /// to this one have to be reopened to see them
+    pub fn collect_value_logs(&mut self, min_garbage_ratio: f64) -> DungeonResult<ValueLogReport> {
+        self.check_writable()?;
+        // The running merge may still read the logs
+        self.finish_compaction(true)?;
+        let fs = self.options.file_system.clone();
+        let mut report = ValueLogReport::default();
+        let live = self.live_value_log_bytes();
+        let mut garbage_logs = BTreeSet::new();
+        for log in value_log::log_names(fs.as_ref(), &self.dir_path)? {
+            let Some(live_bytes) = live.get(&log) else {
+                continue;
+            };
+            let size = fs
+                .file_size(&value_log::log_path(&self.dir_path, &log))
+                .map_err(|_| DungeonError::new("Could not read value log size"))?;
+            if size > 0 && 1.0 - *live_bytes as f64 / size as f64 >= min_garbage_ratio {
+                garbage_logs.insert(log);
+            }
+        }
+        let rewritten: Vec<SSTable> = self
+            .sstables
+            .iter()
+            .filter(|sstable| {
+                let logs = sstable.0.index.value_logs.keys();
+                logs.into_iter().any(|log| garbage_logs.contains(log))
+            })
+            .map(|sstable| sstable.0.clone())
+            .collect();
+        for sstable in rewritten {
+            let records = sstable
+                .index
+                .clone()
+                .into_entries()
+                .map(|(key, segment)| {
+                    let record = sstable.read_segment(segment)?;
+                    Ok((
+                        key,
+                        value_log::resolve(fs.as_ref(), &self.dir_path, record)?,
+                    ))
+                })
+                .collect::<DungeonResult<Vec<_>>>()?;
+            let log_name = self.next_sstable_name();
+            let settings = TableSettings {
+                value_log: SSTable::value_log_for(&self.dir_path, &log_name, &self.options),
+                ..Default::default()
+            };
+            let sstable = migration::rewrite_sstable(&sstable, records, &self.options, settings)?;
+            report.rewritten_tables.push(sstable.file_name.clone());
+            self.sstables.replace(OrderedByDateSSTable(sstable));
+        }
+        // Also catches the logs of flushes and merges that never got their index saved
+        let live = self.live_value_log_bytes();
+        for log in value_log::log_names(fs.as_ref(), &self.dir_path)? {
+            if live.contains_key(&log) {
+                continue;
+            }
+            let log_path = value_log::log_path(&self.dir_path, &log);
+            report.reclaimed_bytes += fs.file_size(&log_path).unwrap_or(0);
+            fs.remove_file(&log_path)
+                .map_err(|_| DungeonError::new("Could not remove value log"))?;
+            report.removed_logs.push(log);
+        }
+        self.clock.persist()?;
+        Ok(report)
+    }
+    /// Bytes the live sstables point at in every value log
+    fn live_value_log_bytes(&self) -> BTreeMap<String, u64> {
+        value_log::live_bytes(
+            self.sstables
+                .iter()
+                .map(|sstable| &sstable.0.index.value_logs),
+        )
+    }
+    /// Adds sstables built by `SSTableWriter` to the chest without rewriting them. `index_files`
+    /// are the paths of their `.index` files, the data files are expected next to them. Either
+    /// every table is added or none is. The source files are removed once they are in place
+    pub fn ingest_external(&mut self, index_files: &[impl AsRef<Path>]) -> DungeonResult<()> {
+        self.check_writable()?;
+        let mut tables = index_files
+            .iter()
+            .map(|file| {
+                ingest::ExternalSSTable::open(self.options.file_system.as_ref(), file.as_ref())
+            })
+            .collect::<DungeonResult<Vec<_>>>()?;
+        ingest::validate(&mut tables)?;
+        // Written outside of the chest, nothing held them to its limits yet
+        for table in &tables {
+            for (key, record) in table.records(self.options.file_system.as_ref())? {
+                self.check_write(key, (!record.is_tombstone()).then_some(&record.value))?;
+            }
+        }
+        // The memtable is always read first, so it can't keep anything older than the tables
+        if self.mem_table.size() > 0 {
+            self.flush()?;
+        }
+        for table in &tables {
+            let (oldest, newest) = table.timestamps;
+            // Ingested tables are read before every live one, so they must also be newer than
+            // every live record they could hide. A table name is newer than all of its records
+            let hides_newer = self.sstables.iter().any(|sstable| {
+                sstable.get_date_milis() >= oldest
+                    && table
+                        .index
+                        .table
+                        .keys()
+                        .any(|key| sstable.0.index.get(key).is_some())
+            });
+            if hides_newer {
+                return Err(DungeonError::new(
+                    "External sstable is older than overlapping live data",
+                ));
+            }
+            // Nor can they be older than a range delete, which would hide them right away
+            let deleted = self.range_tombstones().any(|tombstone| {
+                tombstone.timestamp > oldest
+                    && table
+                        .index
+                        .table
+                        .range(tombstone.start.clone()..tombstone.end.clone())
+                        .any(|(key, _)| tombstone.covers(key))
+            });
+            if deleted {
+                return Err(DungeonError::new(
+                    "External sstable is older than a range delete covering it",
+                ));
+            }
+            self.clock.observe(newest);
+        }
+        // Copied over when they can't be linked, and counted in the chest either way
+        let mut incoming = 0;
+        for table in &tables {
+            for path in [&table.data_file_path, &table.index_file_path] {
+                incoming += self
+                    .options
+                    .file_system
+                    .disk_size(path)
+                    .map_err(|_| DungeonError::new("Could not read external sstable size"))?;
+            }
+        }
+        self.check_quota(incoming)?;
+        let indexed_before =
+            self.index_entries_of(tables.iter().flat_map(|table| table.index.table.keys()))?;
+        let mut file_names = Vec::with_capacity(tables.len());
+        for table in &tables {
+            let file_name = self.next_sstable_name();
+            ingest::stage(
+                self.options.file_system.as_ref(),
+                &self.dir_path,
+                table,
+                &file_name,
+            )?;
+            file_names.push(file_name);
+        }
+        ingest::commit(self.options.file_system.as_ref(), &self.dir_path)?;
+        self.clock.persist()?;
+        for file_name in file_names {
+            let sstable = SSTable::from_file(self.dir_path.clone(), file_name, &self.options)?;
+            for key in sstable.index.table.keys() {
+                self.filter.insert(key);
+            }
+            self.sstables.insert(OrderedByDateSSTable(sstable));
+        }
+        for (key, before) in indexed_before {
+            self.update_indexes(&key, before)?;
+        }
+        if self.mem_table.size() >= self.options.flush_size {
+            self.flush()?;
+        }
+        for table in tables {
+            // Already ingested, a leftover source file only wastes space
+            let _ = self.options.file_system.remove_file(&table.data_file_path);
+            let _ = self.options.file_system.remove_file(&table.index_file_path);
+        }
+        Ok(())
+    }
+    /// Flushes the memtable and links every sstable into `target_dir`, which must be empty or not
+    /// exist yet. SSTables are never modified once written, so the checkpoint can be opened as a
+    /// standalone chest while this one keeps running
+    pub fn checkpoint(&mut self, target_dir: &str) -> DungeonResult<()> {
+        let target_dir = Path::new(target_dir);
+        let fs = self.options.file_system.clone();
+        backup::create_empty_dir(fs.as_ref(), target_dir)?;
+        self.flush_for_backup()?;
+        // Value logs are never modified once their table is written either
+        for log in self.live_value_log_bytes().keys() {
+            let log_path = value_log::log_path(&self.dir_path, log);
+            backup::link_or_copy(
+                fs.as_ref(),
+                &log_path,
+                &Self::backup_path(target_dir, &log_path)?,
+            )?;
+        }
+        for sstable in &self.sstables {
+            for file_path in backup::table_files(&sstable.0) {
+                backup::link_or_copy(
+                    fs.as_ref(),
+                    &file_path,
+                    &Self::backup_path(target_dir, &file_path)?,
+                )?;
+            }
+        }
+        // The clock and indexes files are replaced on flushes, so they are copied instead of
+        // linked
+        self.copy_state_files(target_dir)
+    }
+    /// Brings the backup in `target_dir` up to date with this chest, copying only the sstables it
+    /// doesn't have yet and removing the ones that were merged away since the last backup
+    pub fn backup(&mut self, target_dir: &str) -> DungeonResult<BackupReport> {
+        let target_dir = Path::new(target_dir);
+        let fs = self.options.file_system.clone();
+        if !fs.is_dir(target_dir) {
+            fs.create_dir_all(target_dir)
+                .map_err(|_| DungeonError::new("Could not create backup dir"))?;
+        }
+        self.flush_for_backup()?;
+        let mut report = BackupReport::default();
+        let live_logs: Vec<String> = self.live_value_log_bytes().into_keys().collect();
+        // Before the tables, so a backed up table never points at a missing log
+        for log in &live_logs {
+            let log_path = value_log::log_path(&self.dir_path, log);
+            let backup_log_path = Self::backup_path(target_dir, &log_path)?;
+            if fs.is_file(&backup_log_path) {
+                continue;
+            }
+            backup::copy_file(fs.as_ref(), &log_path, &backup_log_path)?;
+            report.copied_files += 1;
+        }
+        for sstable in &self.sstables {
+            let [data_file_path, index_file_path] = backup::table_files(&sstable.0);
+            let backup_index_path = Self::backup_path(target_dir, &index_file_path)?;
+            if backup::is_backed_up(fs.as_ref(), &index_file_path, &backup_index_path) {
+                continue;
+            }
+            // A table rewritten since the last backup, without its index it is never opened
+            // while half copied
+            if fs.is_file(&backup_index_path) {
+                fs.remove_file(&backup_index_path)
+                    .map_err(|_| DungeonError::new("Could not remove outdated index file"))?;
+            }
+            backup::copy_file(
+                fs.as_ref(),
+                &data_file_path,
+                &Self::backup_path(target_dir, &data_file_path)?,
+            )?;
+            backup::copy_file(fs.as_ref(), &index_file_path, &backup_index_path)?;
+            report.copied_files += 2;
+        }
+        self.copy_state_files(target_dir)?;
+        let live_names: Vec<String> = self
+            .sstables
+            .iter()
+            .map(|sstable| sstable.0.file_name.clone())
+            .collect();
+        report.removed_files = backup::remove_stale_tables(fs.as_ref(), target_dir, &live_names)?;
+        report.removed_files += backup::remove_stale_logs(fs.as_ref(), target_dir, &live_logs)?;
+        Ok(report)
+    }
+    fn copy_state_files(&self, target_dir: &Path) -> DungeonResult<()> {
+        let fs = self.options.file_system.as_ref();
+        let clock_path = self.clock.file_path();
+        backup::copy_file(fs, clock_path, &Self::backup_path(target_dir, clock_path)?)?;
+        let indexes_path = self.index_states.file_path();
+        if fs.is_file(indexes_path) {
+            backup::copy_file(
+                fs,
+                indexes_path,
+                &Self::backup_path(target_dir, indexes_path)?,
+            )?;
         }
         Ok(())
     }
+    fn flush_for_backup(&mut self) -> DungeonResult<()> {
+        if self.options.read_only {
+            return Ok(());
+        }
+        if self.mem_table.size() > 0 {
+            self.flush()?;
+        }
+        self.clock.persist()
+    }
+    fn backup_path(target_dir: &Path, file_path: &Path) -> DungeonResult<PathBuf> {
+        let file_name = file_path
+            .file_name()
+            .ok_or(DungeonError::new("Could not get file name"))?;
+        Ok(target_dir.join(file_name))
+    }
+    /// SSTables are named after a clock tick, so names never collide and sort by creation
+    fn next_sstable_name(&mut self) -> String {
+        self.clock.tick().to_string()
+    }
+    pub fn stats(&self) -> ChestStats {
+        let mut stats = ChestStats {
+            memtable_entries: self.mem_table.size(),
+            memtable_bytes: self.mem_table.bytes(..),
+            sstables: self
+                .sstables
+                .iter()
+                .map(|sstable| SSTableStats {
+                    name: sstable.0.file_name.clone(),
+                    keys: sstable.0.index.table.len(),
+                    data_bytes: sstable.0.data_size(),
+                    index_bytes: self
+                        .options
+                        .file_system
+                        .file_size(&sstable.0.get_index_file_path())
+                        .unwrap_or(0),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        self.metrics.fill(&mut stats);
+        stats
+    }
+    /// Amount of entries in the memtable. See `count` and `approximate_len` for the amount of keys
     pub fn len(&self) -> usize {
         self.mem_table.size()
     }
+    /// Whether the chest has no live keys at all
     pub fn is_empty(&self) -> bool {
-        self.len() > 0
+        self.scan().next().is_none()
+    }
+    /// Exact amount of live keys, reading every table
+    pub fn count(&self) -> DungeonResult<usize> {
+        self.scan()
+            .try_fold(0, |count, entry| entry.map(|_| count + 1))
+    }
+    /// Estimated amount of live keys, computed from the sstable indexes alone. Keys deleted in a
+    /// newer table may still be counted until the table holding their value is compacted
+    pub fn approximate_len(&self) -> usize {
+        let mut sketch = HyperLogLog::default();
+        for key in self.mem_table.live_keys().filter(|key| !is_index_key(key)) {
+            sketch.insert(key);
+        }
+        for sstable in &self.sstables {
+            sketch.union(&sstable.0.sketch());
+        }
+        sketch.estimate()
+    }
+    /// Estimated bytes taken by the keys in `range`. Versions shadowed by newer tables are counted
+    /// until they are compacted away
+    pub fn approximate_size(&self, range: impl RangeBounds<String> + Clone) -> u64 {
+        let sstables_size: u64 = self
+            .sstables
+            .iter()
+            .map(|sstable| sstable.0.size_of_range(range.clone()))
+            .sum();
+        sstables_size + self.mem_table.bytes(range) as u64
     }
 }
 #[derive(Clone)]
@@ -183,7 +1508,9 @@ impl PartialEq for OrderedByDateSSTable {
 
 impl Drop for Chest {
     fn drop(&mut self) {
-        match self.flush() {
+        // Nothing is left to wait for
+        self.compaction_control.shut_down();
+        match self.flush().and_then(|_| self.finish_compaction(true)) {
             Ok(_) => (),
             Err(err) => eprintln!("{err}"),
         }
@@ -0,0 +1,19 @@
+use std::{io, path::Path};
+
+use errors::{DungeonError, DungeonResult};
+
+use crate::file_system::FileSystem;
+
+const LOCK_FILE_NAME: &str = "LOCK";
+
+/// Takes the advisory lock of a chest dir, which is held until the returned guard is dropped. Only
+/// one writer can open a dir at a time
+pub fn acquire(fs: &dyn FileSystem, dir_path: &Path) -> DungeonResult<Box<dyn Send + Sync>> {
+    fs.try_lock(&dir_path.join(LOCK_FILE_NAME))
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::WouldBlock => {
+                DungeonError::new("Chest dir is already opened by another writer")
+            }
+            _ => DungeonError::new("Could not lock chest dir"),
+        })
+}
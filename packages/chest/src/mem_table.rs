@@ -1,29 +1,137 @@
-use std::{collections::BTreeMap, mem};
+use std::{collections::BTreeMap, mem, ops::RangeBounds};
 
-use crate::value::TimeStampedValue;
+use rmp_serde::to_vec;
+
+use crate::value::{resolve_versions, RangeTombstone, TimeStampedValue};
 
 type MemTableTable = BTreeMap<String, TimeStampedValue>;
 #[derive(Debug)]
 pub struct MemTable {
     table: MemTableTable,
+    /// Versions replaced since the last flush, oldest first. Only filled when keeping history
+    history: BTreeMap<String, Vec<TimeStampedValue>>,
+    /// Range deletes since the last flush, hiding the records of older tables
+    range_tombstones: Vec<RangeTombstone>,
+    keep_history: bool,
 }
 
 impl MemTable {
-    pub fn new() -> Self {
+    pub fn new(keep_history: bool) -> Self {
         Self {
             table: Default::default(),
+            history: Default::default(),
+            range_tombstones: Vec::new(),
+            keep_history,
         }
     }
     pub fn set(&mut self, key: &str, value: TimeStampedValue) {
-        self.table.insert(key.to_owned(), value);
+        if let Some(replaced) = self.table.insert(key.to_owned(), value) {
+            self.keep(key, replaced);
+        }
+    }
+    pub fn merge(&mut self, key: &str, operand: TimeStampedValue) {
+        // Operands are kept as written so every version in between can still be read
+        if self.keep_history {
+            return self.set(key, operand);
+        }
+        let merged = match self.table.remove(key) {
+            Some(existing) => {
+                let merged = operand.merge_onto(existing.clone());
+                self.keep(key, existing);
+                merged
+            }
+            None => operand,
+        };
+        self.table.insert(key.to_owned(), merged);
+    }
+    /// Deletes the keys of the memtable in the range right away, and keeps the tombstone for the
+    /// ones in older tables
+    pub fn delete_range(&mut self, tombstone: RangeTombstone) {
+        let covered: Vec<String> = self
+            .table
+            .range(tombstone.start.clone()..tombstone.end.clone())
+            .map(|(key, _)| key)
+            .filter(|key| tombstone.covers(key))
+            .cloned()
+            .collect();
+        for key in covered {
+            self.set(&key, TimeStampedValue::tombstone(tombstone.timestamp));
+        }
+        self.range_tombstones.push(tombstone);
+    }
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+    fn keep(&mut self, key: &str, replaced: TimeStampedValue) {
+        if self.keep_history {
+            self.history
+                .entry(key.to_owned())
+                .or_default()
+                .push(replaced);
+        }
     }
     pub fn get(&self, key: &str) -> Option<TimeStampedValue> {
+        if self.history.contains_key(key) {
+            return resolve_versions(self.get_versions(key)).into_iter().next();
+        }
         self.table.get(key).cloned()
     }
-    pub fn flush(&mut self) -> MemTableTable {
-        mem::take(&mut self.table)
+    /// Every version of `key` still in memory as it was written, in no particular order
+    pub fn get_versions(&self, key: &str) -> Vec<TimeStampedValue> {
+        let mut versions = self.history.get(key).cloned().unwrap_or_default();
+        versions.extend(self.table.get(key).cloned());
+        versions
+    }
+    /// Takes every version out of the memtable, sorted by key, along with the range tombstones
+    pub fn flush(&mut self) -> (Vec<(String, TimeStampedValue)>, Vec<RangeTombstone>) {
+        let mut history = mem::take(&mut self.history);
+        let mut flushed = Vec::with_capacity(self.table.len());
+        for (key, value) in mem::take(&mut self.table) {
+            for replaced in history.remove(&key).unwrap_or_default() {
+                flushed.push((key.clone(), replaced));
+            }
+            flushed.push((key, value));
+        }
+        (flushed, mem::take(&mut self.range_tombstones))
+    }
+    /// Puts back what `flush` took out when it could not be written
+    pub fn restore(
+        &mut self,
+        flushed: Vec<(String, TimeStampedValue)>,
+        range_tombstones: Vec<RangeTombstone>,
+    ) {
+        for (key, value) in flushed {
+            self.set(&key, value);
+        }
+        self.range_tombstones.extend(range_tombstones);
+    }
+    pub fn keys_in(
+        &self,
+        range: impl RangeBounds<String>,
+    ) -> impl DoubleEndedIterator<Item = &String> {
+        self.table.range(range).map(|(key, _)| key)
+    }
+    /// Encoded size of the keys in `range` and their records
+    pub fn bytes(&self, range: impl RangeBounds<String> + Clone) -> usize {
+        let history = self
+            .history
+            .range(range.clone())
+            .flat_map(|(key, versions)| versions.iter().map(move |version| (key, version)));
+        self.table
+            .range(range)
+            .chain(history)
+            .map(|(key, version)| key.len() + to_vec(version).map_or(0, |encoded| encoded.len()))
+            .sum()
+    }
+    /// Keys whose latest record isn't a tombstone
+    pub fn live_keys(&self) -> impl Iterator<Item = &String> {
+        self.table
+            .iter()
+            .filter(|(_, value)| !value.is_tombstone())
+            .map(|(key, _)| key)
     }
+    /// Entries in the memtable, counting every range tombstone as one
     pub fn size(&self) -> usize {
-        self.table.len()
+        self.table.len() + self.range_tombstones.len()
     }
 }
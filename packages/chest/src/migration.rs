@@ -0,0 +1,160 @@
+use std::{collections::BTreeMap, path::Path};
+
+use errors::{DungeonError, DungeonResult};
+use rmp_serde::from_slice;
+use serde::Deserialize;
+
+use crate::{
+    file_system::FileSystem,
+    options::ChestOptions,
+    ss_table::{SSTable, TableSettings},
+    value::{RecordKind, TimeStampedValue, Value},
+};
+
+/// Tables are rewritten here first and only moved next to the live ones once both files are
+/// complete
+const MIGRATION_DIR: &str = "migration";
+
+/// Value as encoded before record kinds existed, when `Invalid` doubled as the tombstone
+#[derive(Deserialize)]
+enum LegacyValue {
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Boolean(bool),
+    Null,
+    Bytes(#[serde(with = "serde_bytes")] Vec<u8>),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+    Timestamp(u128),
+    Invalid,
+}
+
+/// Record as encoded before record kinds existed. The oldest tables don't have the `deleted` flag
+#[derive(Deserialize)]
+struct LegacyRecord {
+    timestamp: u128,
+    value: LegacyValue,
+    #[serde(default)]
+    deleted: bool,
+}
+
+impl From<LegacyRecord> for TimeStampedValue {
+    fn from(legacy: LegacyRecord) -> Self {
+        let kind = if legacy.deleted || matches!(legacy.value, LegacyValue::Invalid) {
+            RecordKind::Delete
+        } else {
+            RecordKind::Put
+        };
+        let value = match legacy.value {
+            LegacyValue::Integer(v) => Value::Integer(v),
+            LegacyValue::Float(v) => Value::Float(v),
+            LegacyValue::String(v) => Value::String(v),
+            LegacyValue::Boolean(v) => Value::Boolean(v),
+            LegacyValue::Null | LegacyValue::Invalid => Value::Null,
+            LegacyValue::Bytes(v) => Value::Bytes(v),
+            LegacyValue::List(v) => Value::List(v),
+            LegacyValue::Map(v) => Value::Map(v),
+            LegacyValue::Timestamp(v) => Value::Timestamp(v),
+        };
+        Self {
+            timestamp: legacy.timestamp,
+            value,
+            kind,
+            pointer: None,
+        }
+    }
+}
+
+/// Finishes migrations that were interrupted after the data file was moved into place but before
+/// the index was, and throws away the ones that didn't finish writing
+pub fn recover(fs: &dyn FileSystem, dir_path: &Path) -> DungeonResult<()> {
+    let migration_dir = dir_path.join(MIGRATION_DIR);
+    if !fs.is_dir(&migration_dir) {
+        return Ok(());
+    }
+    let files = fs
+        .read_dir(&migration_dir)
+        .map_err(|_| DungeonError::new("Could not read migration files"))?;
+    for file_path in files {
+        if file_path.extension().and_then(|ext| ext.to_str()) != Some("index") {
+            continue;
+        }
+        let file_name = file_path
+            .file_stem()
+            .ok_or(DungeonError::new("Could not get file stem"))?
+            .to_owned();
+        let data_file_path = migration_dir.join(&file_name).with_extension("chest");
+        if fs.is_file(&data_file_path) {
+            fs.rename(
+                &data_file_path,
+                &dir_path.join(&file_name).with_extension("chest"),
+            )
+            .map_err(|_| DungeonError::new("Could not move migrated data file"))?;
+        }
+        fs.rename(
+            &file_path,
+            &dir_path.join(&file_name).with_extension("index"),
+        )
+        .map_err(|_| DungeonError::new("Could not move migrated index file"))?;
+    }
+    fs.remove_dir_all(&migration_dir)
+        .map_err(|_| DungeonError::new("Could not clean migration dir"))?;
+    Ok(())
+}
+
+/// Rewrites a table written before `FORMAT_VERSION` in the current format. The file name is kept,
+/// so the table keeps its place in the sstable ordering
+pub fn migrate_sstable(sstable: SSTable, options: &ChestOptions) -> DungeonResult<SSTable> {
+    let records = sstable
+        .index
+        .clone()
+        .into_entries()
+        .map(|(key, segment)| {
+            let raw = sstable.read_raw(segment)?;
+            let record = match sstable.index.version {
+                0 => from_slice::<LegacyRecord>(&raw).map(TimeStampedValue::from),
+                // Version 1 records are the current ones without the key in front of them
+                _ => from_slice::<TimeStampedValue>(&raw),
+            }
+            .map_err(|_| DungeonError::new("Could not parse legacy value"))?;
+            Ok((key, record))
+        })
+        .collect::<DungeonResult<Vec<_>>>()?;
+    rewrite_sstable(&sstable, records, options, TableSettings::default())
+}
+
+/// Replaces the content of `sstable` with `records`, keeping its name. The new table is written
+/// next to the migrated ones, so a crash leaves either the old or the new table in place
+pub(crate) fn rewrite_sstable(
+    sstable: &SSTable,
+    records: Vec<(String, TimeStampedValue)>,
+    options: &ChestOptions,
+    mut settings: TableSettings,
+) -> DungeonResult<SSTable> {
+    settings.last_sequence = sstable.index.last_sequence;
+    settings.range_tombstones = sstable.index.range_tombstones.clone();
+    let fs = options.file_system.as_ref();
+    let migration_dir = sstable.base_dir.join(MIGRATION_DIR);
+    fs.create_dir_all(&migration_dir)
+        .map_err(|_| DungeonError::new("Could not create migration dir"))?;
+    let migrated = SSTable::write_table(
+        migration_dir,
+        sstable.file_name.clone(),
+        records.into_iter().map(Ok).peekable(),
+        options,
+        settings,
+    )?;
+    fs.rename(
+        &migrated.get_data_file_path(),
+        &sstable.get_data_file_path(),
+    )
+    .map_err(|_| DungeonError::new("Could not move migrated data file"))?;
+    fs.rename(
+        &migrated.get_index_file_path(),
+        &sstable.get_index_file_path(),
+    )
+    .map_err(|_| DungeonError::new("Could not move migrated index file"))?;
+    recover(fs, &sstable.base_dir)?;
+    SSTable::from_file(sstable.base_dir.clone(), sstable.file_name.clone(), options)
+}
@@ -0,0 +1,170 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    encryption::KeySource,
+    file_system::{FileSystem, OsFileSystem},
+    secondary_index::IndexDefinition,
+    value::TimeStampedValue,
+};
+
+/// How SSTable data files are read from disk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Opens, seeks and reads the data file for every lookup
+    #[default]
+    Buffered,
+    /// Maps the whole data file into memory once, so lookups are plain memory reads. Falls back
+    /// to `Buffered` for tables that can't be mapped
+    Mmap,
+}
+
+/// Characters user keys may be made of
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyCharset {
+    /// Any non empty string
+    #[default]
+    Any,
+    /// ASCII letters and digits only, the keys grimoire queries can name
+    AsciiAlphanumeric,
+}
+
+impl KeyCharset {
+    pub fn allows(&self, key: &str) -> bool {
+        match self {
+            KeyCharset::Any => !key.is_empty(),
+            KeyCharset::AsciiAlphanumeric => {
+                !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric())
+            }
+        }
+    }
+}
+
+/// Superseded versions of each key that are kept for `Chest::get_at` and `Chest::history`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum History {
+    /// Only the latest version of each key is kept
+    #[default]
+    Disabled,
+    /// Keeps up to this amount of versions per key, counting the latest one
+    Versions(usize),
+    /// Keeps every version that was current at some point inside this window
+    Window(Duration),
+}
+
+impl History {
+    pub fn is_enabled(&self) -> bool {
+        *self != History::Disabled
+    }
+    /// Drops the versions that aren't kept from `versions`, which is sorted newest first. The
+    /// oldest kept version absorbs the dropped ones, so merge operands keep their base. The latest
+    /// version is always kept
+    pub(crate) fn retain(&self, versions: &mut Vec<TimeStampedValue>) {
+        let keep = match self {
+            History::Disabled => 1,
+            History::Versions(count) => *count,
+            History::Window(window) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_nanos())
+                    .unwrap_or(0);
+                let cutoff = now.saturating_sub(window.as_nanos());
+                // The newest version from before the window was still the current one when the
+                // window started
+                versions
+                    .iter()
+                    .position(|version| version.timestamp < cutoff)
+                    .map_or(versions.len(), |i| i + 1)
+            }
+        };
+        let keep = keep.max(1);
+        if versions.len() <= keep {
+            return;
+        }
+        let base = versions
+            .drain(keep - 1..)
+            .rev()
+            .reduce(|older, newer| newer.merge_onto(older));
+        versions.extend(base);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChestOptions {
+    /// Amount of memtable entries that triggers a flush to a new sstable
+    pub flush_size: usize,
+    /// Amount of sstables kept before the newest ones start being merged
+    pub max_sstable_count: usize,
+    pub read_mode: ReadMode,
+    pub history: History,
+    /// Opens the chest without taking the dir lock, so it can be opened next to a writer. Writes
+    /// are rejected and `Chest::refresh` picks up the sstables flushed by the writer since
+    pub read_only: bool,
+    /// Where the chest dir lives, the OS file system by default
+    pub file_system: Arc<dyn FileSystem>,
+    /// Encrypts every file of the chest with the keys found here. Reading a file that isn't
+    /// encrypted fails, unless `allow_unencrypted` is set
+    pub encryption: Option<KeySource>,
+    /// Lets an encrypted chest read files that aren't encrypted, to turn encryption on for an
+    /// existing chest. Its tables get encrypted as compaction rewrites them. Off by default,
+    /// since an unencrypted file swapped in for an encrypted one would go unnoticed
+    pub allow_unencrypted: bool,
+    /// Values that encode to more bytes than this are written to a value log, leaving sstables
+    /// with a pointer to them, so compaction doesn't keep rewriting them. Every value stays in
+    /// its sstable when None. See `Chest::collect_value_logs` to reclaim the space of dead values
+    pub value_log_threshold: Option<usize>,
+    /// Secondary indexes kept up to date with every write, queried through `Chest::index_scan`.
+    /// Indexes declared over existing data are built a batch of keys per flush, or right away
+    /// with `Chest::build_indexes`. Entries of indexes that aren't declared anymore are removed
+    pub indexes: Vec<IndexDefinition>,
+    /// Appends every commit to a write ahead log before applying it, so writes that weren't
+    /// flushed yet survive a crash. Without it they are lost, even though their change events
+    /// were already sent to subscribers
+    pub wal: bool,
+    /// Log segments kept after their writes were flushed, one per flush, so
+    /// `Chest::subscribe_from` can go back that far
+    pub wal_retention: usize,
+    /// Runs compactions on a thread of their own instead of in the flush that needs them. Reads
+    /// keep using the merged tables until the merge is done
+    pub background_compaction: bool,
+    /// Bytes per second compactions write their data files at. Unlimited when None
+    pub compaction_rate: Option<u64>,
+    /// Bytes the files of the chest may take up. Writes that would start a flush going past it
+    /// fail with `ErrorKind::QuotaExceeded` and leave the chest degraded, see
+    /// `Chest::is_degraded`. Unlimited when None
+    pub disk_quota: Option<u64>,
+    /// Longest key in bytes a write accepts, longer ones fail with `ErrorKind::KeyTooLarge`
+    pub max_key_size: usize,
+    /// Largest encoded value, or merge operand, a write accepts. Larger ones fail with
+    /// `ErrorKind::ValueTooLarge`. Merges can still grow a value past it
+    pub max_value_size: usize,
+    /// Keys made of anything else fail with `ErrorKind::InvalidKey`
+    pub key_charset: KeyCharset,
+}
+
+impl Default for ChestOptions {
+    fn default() -> Self {
+        Self {
+            flush_size: 512,
+            max_sstable_count: 24,
+            read_mode: ReadMode::default(),
+            history: History::default(),
+            read_only: false,
+            file_system: Arc::new(OsFileSystem),
+            encryption: None,
+            allow_unencrypted: false,
+            value_log_threshold: None,
+            indexes: Vec::new(),
+            wal: false,
+            wal_retention: 4,
+            background_compaction: false,
+            compaction_rate: None,
+            disk_quota: None,
+            max_key_size: 1024,
+            max_value_size: 16 << 20,
+            key_charset: KeyCharset::default(),
+        }
+    }
+}
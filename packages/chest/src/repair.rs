@@ -0,0 +1,182 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use errors::{DungeonError, DungeonResult};
+
+use crate::{
+    encryption,
+    file_system::FileSystem,
+    options::ChestOptions,
+    ss_table::{Index, SSTable, ScannedDataFile, FORMAT_VERSION},
+};
+
+/// Unreadable tables are moved here, out of the way of `Chest::new`
+pub const QUARANTINE_DIR: &str = "quarantine";
+/// Written next to the tables after every repair
+pub const REPORT_FILE_NAME: &str = "REPAIR_REPORT";
+
+/// What `repair` recovered and what it had to give up on
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Tables whose index was rebuilt from their data file
+    pub rebuilt: Vec<String>,
+    /// Files moved to the quarantine dir
+    pub quarantined: Vec<String>,
+    /// Records indexed by the rebuilt tables
+    pub recovered_entries: usize,
+    /// Trailing bytes of data files that didn't decode into records
+    pub lost_bytes: usize,
+}
+
+impl RepairReport {
+    pub fn is_clean(&self) -> bool {
+        self.rebuilt.is_empty() && self.quarantined.is_empty()
+    }
+}
+
+impl Display for RepairReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_clean() {
+            return writeln!(f, "Nothing to repair");
+        }
+        for table in &self.rebuilt {
+            writeln!(f, "rebuilt index of {table}")?;
+        }
+        for file in &self.quarantined {
+            writeln!(f, "quarantined {file}")?;
+        }
+        writeln!(f, "recovered entries: {}", self.recovered_entries)?;
+        writeln!(f, "lost bytes: {}", self.lost_bytes)
+    }
+}
+
+/// Makes a damaged chest directory openable again. Indexes that are missing, can't be parsed or
+/// point at records that don't decode are rebuilt from their data file. Tables with nothing left
+/// to recover are moved to `QUARANTINE_DIR`, and so are the data files of merged tables whose
+/// removal was cut short. Must not run while a chest has the directory open
+pub fn repair(dir_path: &str, options: &ChestOptions) -> DungeonResult<RepairReport> {
+    let options = &encryption::apply(options.clone())?;
+    let fs = options.file_system.as_ref();
+    let dir_path = PathBuf::from(dir_path);
+    let mut report = RepairReport::default();
+    let mut table_names = Vec::new();
+    let files = fs
+        .read_dir(&dir_path)
+        .map_err(|_| DungeonError::new("Could not read files"))?;
+    for file_path in files {
+        let ext = file_path.extension().and_then(|ext| ext.to_str());
+        if ext != Some("chest") && ext != Some("index") {
+            continue;
+        }
+        let Some(name) = file_path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if !table_names.iter().any(|table_name| table_name == name) {
+            table_names.push(name.to_owned());
+        }
+    }
+    table_names.sort();
+    // Merged tables lose their index first, a data file without one may be all that is left of
+    // a table the merge output replaced
+    let compacted: BTreeSet<String> = table_names
+        .iter()
+        .filter_map(|name| Index::from_file(fs, &dir_path.join(name).with_extension("index")).ok())
+        .flat_map(|index| index.compacted)
+        .collect();
+    for name in table_names {
+        repair_table(&dir_path, &name, options, &compacted, &mut report)?;
+    }
+    fs.write(
+        &dir_path.join(REPORT_FILE_NAME),
+        report.to_string().as_bytes(),
+    )
+    .map_err(|_| DungeonError::new("Could not write repair report"))?;
+    Ok(report)
+}
+
+fn repair_table(
+    dir_path: &Path,
+    name: &str,
+    options: &ChestOptions,
+    compacted: &BTreeSet<String>,
+    report: &mut RepairReport,
+) -> DungeonResult<()> {
+    let fs = options.file_system.as_ref();
+    let data_file_path = dir_path.join(name).with_extension("chest");
+    let index_file_path = dir_path.join(name).with_extension("index");
+    if !fs.is_file(&data_file_path) {
+        return quarantine(fs, dir_path, &index_file_path, report);
+    }
+    // Rebuilding it would bring back whatever the merge dropped
+    if !fs.is_file(&index_file_path) && compacted.contains(name) {
+        return quarantine(fs, dir_path, &data_file_path, report);
+    }
+    if fs.is_file(&index_file_path) && is_readable(dir_path, name, options) {
+        return Ok(());
+    }
+    let ScannedDataFile {
+        entries,
+        trailer,
+        lost_bytes,
+    } = SSTable::scan_data_file(fs, &data_file_path)?;
+    if entries.is_empty() && lost_bytes > 0 {
+        quarantine(fs, dir_path, &data_file_path, report)?;
+        if fs.is_file(&index_file_path) {
+            quarantine(fs, dir_path, &index_file_path, report)?;
+        }
+        report.lost_bytes += lost_bytes;
+        return Ok(());
+    }
+    let mut index = Index::new();
+    if let Some(trailer) = trailer {
+        index.range_tombstones = trailer.range_tombstones;
+        index.last_sequence = trailer.last_sequence;
+    }
+    report.recovered_entries += entries.len();
+    for (key, record, segment) in entries {
+        index.insert_scanned(key, &record, segment);
+    }
+    index.save(fs, &index_file_path)?;
+    report.rebuilt.push(name.to_owned());
+    report.lost_bytes += lost_bytes;
+    Ok(())
+}
+
+/// Whether the index parses and every record it points at decodes. Tables older than
+/// `FORMAT_VERSION` are left for the migration that runs when the chest is opened
+fn is_readable(dir_path: &Path, name: &str, options: &ChestOptions) -> bool {
+    let Ok(sstable) = SSTable::from_file(dir_path.to_path_buf(), name.to_owned(), options) else {
+        return false;
+    };
+    if sstable.index.version < FORMAT_VERSION {
+        return true;
+    }
+    sstable
+        .index
+        .clone()
+        .into_entries()
+        .all(|(_, segment)| sstable.read_segment(segment).is_ok())
+}
+
+fn quarantine(
+    fs: &dyn FileSystem,
+    dir_path: &Path,
+    file_path: &Path,
+    report: &mut RepairReport,
+) -> DungeonResult<()> {
+    let quarantine_dir = dir_path.join(QUARANTINE_DIR);
+    fs.create_dir_all(&quarantine_dir)
+        .map_err(|_| DungeonError::new("Could not create quarantine dir"))?;
+    let file_name = file_path
+        .file_name()
+        .ok_or(DungeonError::new("Could not get file name"))?;
+    fs.rename(file_path, &quarantine_dir.join(file_name))
+        .map_err(|_| DungeonError::new("Could not quarantine file"))?;
+    report
+        .quarantined
+        .push(file_name.to_string_lossy().into_owned());
+    Ok(())
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use errors::{DungeonError, DungeonResult, ErrorKind};
+use rmp_serde::{from_slice, to_vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file_system::{write_atomic, FileSystem},
+    value::Value,
+};
+
+/// Every index entry is stored under a key starting with this. Users can't write such keys and
+/// scans never return them
+pub const INDEX_KEY_PREFIX: &str = "\u{0}index\u{0}";
+const INDEXES_FILE_NAME: &str = "INDEXES";
+/// Keys an index build goes through on every flush
+pub(crate) const BUILD_BATCH: usize = 256;
+
+pub type Extractor = Arc<dyn Fn(&Value) -> Option<Value> + Send + Sync>;
+
+/// Secondary index over the values of the keys starting with `prefix`. Every key is indexed by
+/// what `extractor` returns for its value, keys it returns None for are left out. The extractor
+/// can't be stored, so an index must be declared again every time the chest is opened. Give it a
+/// new name when the extractor changes, so it gets rebuilt
+#[derive(Clone)]
+pub struct IndexDefinition {
+    pub name: String,
+    pub prefix: String,
+    pub extractor: Extractor,
+}
+
+impl Debug for IndexDefinition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IndexDefinition")
+            .field("name", &self.name)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl IndexDefinition {
+    pub fn new(
+        name: &str,
+        prefix: &str,
+        extractor: impl Fn(&Value) -> Option<Value> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.to_owned(),
+            prefix: prefix.to_owned(),
+            extractor: Arc::new(extractor),
+        }
+    }
+    /// Indexes map values by their `field`
+    pub fn field(name: &str, prefix: &str, field: &str) -> Self {
+        let field = field.to_owned();
+        Self::new(name, prefix, move |value| match value {
+            Value::Map(map) => map.get(&field).cloned(),
+            _ => None,
+        })
+    }
+    pub fn covers(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix) && !is_index_key(key)
+    }
+    /// Start of the keys of the entries for the keys indexed by `indexed`. Values are encoded, so
+    /// values of different types never collide
+    pub(crate) fn value_prefix(&self, indexed: &Value) -> DungeonResult<String> {
+        let encoded = to_vec(indexed).map_err(|_| DungeonError::new("Could not parse value"))?;
+        Ok(format!(
+            "{}{}\u{0}",
+            entries_prefix(&self.name),
+            hex::encode(encoded)
+        ))
+    }
+    /// Key of the entry of `key` while its value is `value`
+    pub(crate) fn entry_key(&self, key: &str, value: &Value) -> DungeonResult<Option<String>> {
+        let Some(indexed) = (self.extractor)(value) else {
+            return Ok(None);
+        };
+        Ok(Some(format!("{}{key}", self.value_prefix(&indexed)?)))
+    }
+}
+
+/// Start of the keys of every entry of the index `name`
+pub(crate) fn entries_prefix(name: &str) -> String {
+    format!("{INDEX_KEY_PREFIX}{name}\u{0}")
+}
+
+pub fn is_index_key(key: &str) -> bool {
+    key.starts_with(INDEX_KEY_PREFIX)
+}
+
+pub(crate) fn check_user_key(key: &str) -> DungeonResult<()> {
+    if is_index_key(key) {
+        return Err(DungeonError::with_kind(
+            ErrorKind::InvalidKey,
+            "Key is reserved for secondary indexes",
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects repeated names and names that would make entry keys ambiguous
+pub(crate) fn validate(indexes: &[IndexDefinition]) -> DungeonResult<()> {
+    for (i, index) in indexes.iter().enumerate() {
+        if index.name.contains('\u{0}') {
+            return Err(DungeonError::new("Index names can't contain NUL"));
+        }
+        if indexes[..i].iter().any(|other| other.name == index.name) {
+            return Err(DungeonError::new("Index names must be unique"));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum IndexState {
+    /// Keys up to `after` were indexed by the build, none yet when it is None
+    Building {
+        prefix: String,
+        after: Option<String>,
+    },
+    Ready {
+        prefix: String,
+    },
+}
+
+impl IndexState {
+    fn prefix(&self) -> &str {
+        match self {
+            IndexState::Building { prefix, .. } | IndexState::Ready { prefix } => prefix,
+        }
+    }
+}
+
+/// State of every index, saved next to the tables once the entries it accounts for are flushed
+#[derive(Debug)]
+pub(crate) struct IndexStates {
+    states: BTreeMap<String, IndexState>,
+    file_path: PathBuf,
+    fs: Arc<dyn FileSystem>,
+    dirty: bool,
+}
+
+impl IndexStates {
+    pub fn load(fs: Arc<dyn FileSystem>, dir_path: &Path) -> DungeonResult<Self> {
+        let file_path = dir_path.join(INDEXES_FILE_NAME);
+        let states = if fs.is_file(&file_path) {
+            let data = fs
+                .read(&file_path)
+                .map_err(|_| DungeonError::new("Could not read indexes file"))?;
+            from_slice(&data).map_err(|_| DungeonError::new("Could not parse indexes file"))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            states,
+            file_path,
+            fs,
+            dirty: false,
+        })
+    }
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+    /// Stored indexes that aren't declared anymore, or were declared over another prefix
+    pub fn stale<'a>(&'a self, indexes: &'a [IndexDefinition]) -> impl Iterator<Item = &'a str> {
+        self.states.iter().filter_map(|(name, state)| {
+            let declared = indexes
+                .iter()
+                .any(|index| index.name == *name && index.prefix == state.prefix());
+            (!declared).then_some(name.as_str())
+        })
+    }
+    pub fn remove(&mut self, name: &str) {
+        self.dirty |= self.states.remove(name).is_some();
+    }
+    /// Starts building the declared indexes that have no state yet
+    pub fn start_builds(&mut self, indexes: &[IndexDefinition]) {
+        for index in indexes {
+            if self.states.contains_key(&index.name) {
+                continue;
+            }
+            self.states.insert(
+                index.name.clone(),
+                IndexState::Building {
+                    prefix: index.prefix.clone(),
+                    after: None,
+                },
+            );
+            self.dirty = true;
+        }
+    }
+    pub fn is_ready(&self, name: &str) -> bool {
+        matches!(self.states.get(name), Some(IndexState::Ready { .. }))
+    }
+    /// Where the build of `name` continues, None when it isn't building
+    pub fn build_position(&self, name: &str) -> Option<Option<String>> {
+        match self.states.get(name) {
+            Some(IndexState::Building { after, .. }) => Some(after.clone()),
+            _ => None,
+        }
+    }
+    pub fn is_building(&self) -> bool {
+        self.states
+            .values()
+            .any(|state| matches!(state, IndexState::Building { .. }))
+    }
+    /// Records that the build of `index` went up to `after`, or is done when None
+    pub fn advance(&mut self, index: &IndexDefinition, after: Option<String>) {
+        let prefix = index.prefix.clone();
+        let state = match after {
+            Some(after) => IndexState::Building {
+                prefix,
+                after: Some(after),
+            },
+            None => IndexState::Ready { prefix },
+        };
+        self.states.insert(index.name.clone(), state);
+        self.dirty = true;
+    }
+    /// Saves the states when they changed since the last save
+    pub fn persist(&mut self) -> DungeonResult<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let data =
+            to_vec(&self.states).map_err(|_| DungeonError::new("Could not parse indexes"))?;
+        write_atomic(
+            self.fs.as_ref(),
+            &self.file_path.with_extension("tmp"),
+            &self.file_path,
+            &data,
+        )
+        .map_err(|_| DungeonError::new("Could not write indexes file"))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
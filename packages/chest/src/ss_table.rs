@@ -1,12 +1,28 @@
 use std::{
-    collections::BTreeMap,
-    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    borrow::Cow,
+    collections::{btree_map::Entry, BTreeMap},
+    fmt::Debug,
+    io::{self, BufWriter, Write},
     iter::Peekable,
-    path::PathBuf,
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use crate::value::{TimeStampedValue, Value};
-use itertools::{kmerge, Either};
+use crate::{
+    clock::wall_clock_nanos,
+    compaction::{CompactionControl, Throttled},
+    file_system::{write_atomic, FileSystem, MappedFile, OsFileSystem},
+    hyperloglog::HyperLogLog,
+    options::{ChestOptions, ReadMode},
+    secondary_index::is_index_key,
+    value::{
+        deleted_at, resolve_versions, sort_versions, RangeTombstone, RecordKind, TimeStampedValue,
+        Value,
+    },
+    value_log::{self, ValueLogWriter},
+};
+use itertools::Itertools;
 
 use errors::{DungeonError, DungeonResult};
 use rmp_serde::decode::from_read;
@@ -20,6 +36,14 @@ pub struct DocumentSegment {
     offset: usize,
     length: usize,
 }
+impl DocumentSegment {
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+    pub fn length(&self) -> usize {
+        self.length
+    }
+}
 impl From<(usize, usize)> for DocumentSegment {
     fn from(value: (usize, usize)) -> Self {
         let (offset, length) = value;
@@ -27,31 +51,159 @@ impl From<(usize, usize)> for DocumentSegment {
     }
 }
 
+/// Version of the record encoding used in data files. Tables from before the format was versioned
+/// read as version 0 and are rewritten by `migration::migrate_sstable` when the chest is opened.
+/// Since version 2 every record is preceded by its key
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Key, record and segment of a record found by `SSTable::scan_data_file`
+pub type ScannedEntry = (String, TimeStampedValue, DocumentSegment);
+
+/// Comes where a key would, to mark the trailer. Keys always encode to a string
+const TRAILER_MARKER: u8 = 0xc0;
+
+/// What the index holds besides the records, written after them so `repair` can restore it.
+/// Tables from before the trailer and external ones don't have it
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct TableTrailer {
+    #[serde(default)]
+    pub range_tombstones: Vec<RangeTombstone>,
+    /// Without it the log would replay writes the table already holds
+    #[serde(default)]
+    pub last_sequence: u64,
+}
+
+/// What `SSTable::scan_data_file` could decode
+#[derive(Debug, Default)]
+pub struct ScannedDataFile {
+    pub entries: Vec<ScannedEntry>,
+    pub trailer: Option<TableTrailer>,
+    /// Trailing bytes that don't decode
+    pub lost_bytes: usize,
+}
+
 #[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub struct Index {
     pub table: BTreeMap<String, DocumentSegment>,
+    #[serde(default)]
+    pub version: u32,
+    /// Superseded versions kept by `History`, newest first
+    #[serde(default)]
+    pub history: BTreeMap<String, Vec<DocumentSegment>>,
+    /// Oldest and newest record timestamps in the table. Tables written before these were
+    /// tracked read as `None`
+    #[serde(default)]
+    pub timestamps: Option<(u128, u128)>,
+    /// Distinct keys whose latest record in the table isn't a tombstone, leaving out index entries
+    #[serde(default)]
+    pub sketch: Option<HyperLogLog>,
+    /// Tables merged into this one, which must be gone once this one is live
+    #[serde(default)]
+    pub compacted: Vec<String>,
+    /// Bytes of every value log that records of this table point at
+    #[serde(default)]
+    pub value_logs: BTreeMap<String, u64>,
+    /// Sequence number of the latest write in the table, see `Chest::sequence`
+    #[serde(default)]
+    pub last_sequence: u64,
+    /// Range deletes hiding the records of older tables, see `Chest::delete_range`
+    #[serde(default)]
+    pub range_tombstones: Vec<RangeTombstone>,
 }
 impl Index {
     pub fn new() -> Self {
         Self {
             table: BTreeMap::new(),
+            version: FORMAT_VERSION,
+            history: BTreeMap::new(),
+            timestamps: None,
+            sketch: Some(HyperLogLog::default()),
+            compacted: Vec::new(),
+            value_logs: BTreeMap::new(),
+            last_sequence: 0,
+            range_tombstones: Vec::new(),
         }
     }
-    pub fn from_file(file_path: PathBuf) -> DungeonResult<Self> {
-        let parsed_index: Self = from_read(
-            std::fs::File::open(file_path)
+    pub fn from_file(fs: &dyn FileSystem, file_path: &Path) -> DungeonResult<Self> {
+        let parsed_index: Self = from_slice(
+            &fs.read(file_path)
                 .map_err(|_| DungeonError::new("Could not open index file"))?,
         )
         .map_err(|_| DungeonError::new("Could not parse index file"))?;
         Ok(parsed_index)
     }
+    /// Writes the index through a temporary file, so a crash never leaves a partial index
+    pub fn save(&self, fs: &dyn FileSystem, file_path: &Path) -> DungeonResult<()> {
+        write_atomic(
+            fs,
+            &file_path.with_extension("index-tmp"),
+            file_path,
+            &to_vec(self).map_err(|_| DungeonError::new("Could not parse data to bytes"))?,
+        )
+        .map_err(|_| DungeonError::new("Could not save index"))
+    }
     pub fn insert(&mut self, key: String, segment: DocumentSegment) {
         self.table.insert(key, segment);
     }
+    /// Indexes a record found while scanning a data file, which has the latest version of each
+    /// key first
+    pub fn insert_scanned(
+        &mut self,
+        key: String,
+        record: &TimeStampedValue,
+        segment: DocumentSegment,
+    ) {
+        self.observe_timestamp(record.timestamp);
+        self.reference(record);
+        match self.table.entry(key) {
+            Entry::Occupied(latest) => self
+                .history
+                .entry(latest.key().clone())
+                .or_default()
+                .push(segment),
+            Entry::Vacant(entry) => {
+                if let (Some(sketch), false) = (&mut self.sketch, record.is_tombstone()) {
+                    if !is_index_key(entry.key()) {
+                        sketch.insert(entry.key());
+                    }
+                }
+                entry.insert(segment);
+            }
+        }
+    }
     pub fn get(&self, key: &str) -> Option<DocumentSegment> {
         let found = self.table.get(key).cloned();
         found
     }
+    pub fn observe_timestamp(&mut self, timestamp: u128) {
+        self.timestamps = Some(match self.timestamps {
+            Some((oldest, newest)) => (oldest.min(timestamp), newest.max(timestamp)),
+            None => (timestamp, timestamp),
+        });
+    }
+    /// Counts the value log bytes `record` points at
+    pub fn reference(&mut self, record: &TimeStampedValue) {
+        if let Some(pointer) = &record.pointer {
+            *self.value_logs.entry(pointer.log.clone()).or_default() += pointer.length as u64;
+        }
+    }
+    /// First and last key of the table
+    pub fn key_range(&self) -> Option<(&String, &String)> {
+        let (first, _) = self.table.first_key_value()?;
+        let (last, _) = self.table.last_key_value()?;
+        Some((first, last))
+    }
+    /// Every segment of the table including older versions, sorted by key
+    pub fn into_entries(mut self) -> impl Iterator<Item = (String, DocumentSegment)> {
+        std::mem::take(&mut self.table)
+            .into_iter()
+            .flat_map(move |(key, segment)| {
+                let history = self.history.remove(&key).unwrap_or_default();
+                std::iter::once(segment)
+                    .chain(history)
+                    .map(move |segment| (key.clone(), segment))
+            })
+    }
 }
 impl Iterator for Index {
     type Item = (String, DocumentSegment);
@@ -60,11 +212,43 @@ impl Iterator for Index {
         self.table.pop_first()
     }
 }
-#[derive(Clone, Debug)]
+/// What `SSTable::write_table` does besides writing the records
+#[derive(Default)]
+pub(crate) struct TableSettings {
+    /// Drops the keys whose only record left is a tombstone
+    pub drop_tombstones: bool,
+    /// Tables the new one replaces, see `Index::compacted`
+    pub compacted: Vec<String>,
+    /// Receives the values over `ChestOptions::value_log_threshold`
+    pub value_log: Option<ValueLogWriter>,
+    /// See `Index::last_sequence`
+    pub last_sequence: u64,
+    /// Paces the writes of the data file, set for compactions
+    pub control: Option<Arc<CompactionControl>>,
+    /// Kept in the index unless dropping tombstones. The records they cover are replaced by a
+    /// tombstone, or kept behind one when keeping history
+    pub range_tombstones: Vec<RangeTombstone>,
+}
+
+#[derive(Clone)]
 pub struct SSTable {
     pub index: Index,
     pub base_dir: PathBuf,
     pub file_name: String,
+    fs: Arc<dyn FileSystem>,
+    /// Only present when reading in `ReadMode::Mmap` and the data file could be mapped
+    mmap: Option<MappedFile>,
+}
+
+impl Debug for SSTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SSTable")
+            .field("index", &self.index)
+            .field("base_dir", &self.base_dir)
+            .field("file_name", &self.file_name)
+            .field("mapped", &self.mmap.is_some())
+            .finish()
+    }
 }
 
 impl SSTable {
@@ -74,136 +258,344 @@ impl SSTable {
     pub fn new(
         base_dir: PathBuf,
         file_name: String,
-        mut table: Peekable<impl Iterator<Item = (String, TimeStampedValue)>>,
+        table: Peekable<impl Iterator<Item = (String, TimeStampedValue)>>,
+        options: &ChestOptions,
+    ) -> DungeonResult<Self> {
+        Self::write_table(
+            base_dir,
+            file_name,
+            table.map(Ok).peekable(),
+            options,
+            TableSettings::default(),
+        )
+    }
+    pub(crate) fn value_log_for(
+        base_dir: &Path,
+        file_name: &str,
+        options: &ChestOptions,
+    ) -> Option<ValueLogWriter> {
+        let threshold = options.value_log_threshold?;
+        Some(ValueLogWriter::new(
+            options.file_system.clone(),
+            base_dir,
+            file_name.to_owned(),
+            threshold,
+        ))
+    }
+    /// Same as `new`, following `settings`. Values already in a value log stay there, their
+    /// logs must be in `base_dir`. Nothing is left behind when writing fails
+    pub(crate) fn write_table(
+        base_dir: PathBuf,
+        file_name: String,
+        table: Peekable<impl Iterator<Item = DungeonResult<(String, TimeStampedValue)>>>,
+        options: &ChestOptions,
+        settings: TableSettings,
     ) -> DungeonResult<Self> {
+        let fs = options.file_system.clone();
+        let data_file_path = base_dir.join(format!("{file_name}.chest"));
+        let log_path = settings
+            .value_log
+            .as_ref()
+            .map(|value_log| value_log.path().to_owned());
+        let written = Self::write_files(base_dir, file_name, table, options, settings);
+        if written.is_err() {
+            // Most likely out of disk space, the partial files would only take up more of it
+            let _ = fs.remove_file(&data_file_path);
+            let _ = fs.remove_file(&data_file_path.with_extension("index-tmp"));
+            if let Some(log_path) = log_path {
+                let _ = fs.remove_file(&log_path);
+            }
+        }
+        written
+    }
+    fn write_files(
+        base_dir: PathBuf,
+        file_name: String,
+        mut table: Peekable<impl Iterator<Item = DungeonResult<(String, TimeStampedValue)>>>,
+        options: &ChestOptions,
+        settings: TableSettings,
+    ) -> DungeonResult<Self> {
+        let TableSettings {
+            drop_tombstones,
+            compacted,
+            mut value_log,
+            last_sequence,
+            control,
+            range_tombstones,
+        } = settings;
         let mut index = Index::new();
+        index.compacted = compacted;
+        index.last_sequence = last_sequence;
+        if !drop_tombstones {
+            index.range_tombstones = range_tombstones.clone();
+        }
 
         let full_data_file_path = base_dir.join(format!("{file_name}.chest"));
-        let mut w = BufWriter::new(
-            std::fs::File::create(full_data_file_path)
-                .map_err(|_| DungeonError::new("Could not create data file"))?,
-        );
+        let fs = options.file_system.clone();
+        let data_file = fs
+            .create(&full_data_file_path)
+            .map_err(|_| DungeonError::new("Could not create data file"))?;
+        let mut w = BufWriter::new(match control {
+            Some(control) => Box::new(Throttled::new(data_file, control)),
+            None => data_file,
+        });
         let mut current_offset = 0;
 
-        while let Some((key, value)) = table.next() {
-            if let Some((next_key, next_val)) = table.next() {
-                // Check if the next value uses the same key as the current. That can happen when
-                // merging two SSTables.
-                if next_key == key {
-                    match value.timestamp.cmp(&next_val.timestamp) {
-                        std::cmp::Ordering::Less => {
-                            if next_val.value != Value::Invalid {
-                                current_offset = Self::write_and_index(
-                                    &mut w,
-                                    next_key,
-                                    &next_val,
-                                    &mut index,
-                                    current_offset,
-                                )?;
-                            }
-                        }
-                        std::cmp::Ordering::Equal | std::cmp::Ordering::Greater => {
-                            if value.value != Value::Invalid {
-                                current_offset = Self::write_and_index(
-                                    &mut w,
-                                    key,
-                                    &value,
-                                    &mut index,
-                                    current_offset,
-                                )?;
-                            }
-                        }
-                    };
-                } else {
-                    current_offset =
-                        Self::write_and_index(&mut w, key, &value, &mut index, current_offset)?;
-
-                    current_offset = Self::write_and_index(
-                        &mut w,
-                        next_key,
-                        &next_val,
-                        &mut index,
-                        current_offset,
-                    )?;
+        while let Some(entry) = table.next() {
+            // A record that can't be read stops the write, rather than leaving it out of the table
+            let (key, value) = entry?;
+            // The same key shows up once per source table when merging SSTables, and once per
+            // version when keeping history
+            let mut versions = vec![value];
+            while let Some(Ok((_, next_val))) =
+                table.next_if(|next| matches!(next, Ok((next_key, _)) if *next_key == key))
+            {
+                versions.push(next_val);
+            }
+            // Kept versions stay as they were written, so reads can resolve them in order
+            sort_versions(&mut versions);
+            let deleted = deleted_at(&range_tombstones, &key);
+            if versions
+                .last()
+                .is_some_and(|oldest| oldest.timestamp < deleted)
+            {
+                versions.push(TimeStampedValue::tombstone(deleted));
+                sort_versions(&mut versions);
+            }
+            // Merge operands are applied onto the versions they are dropped with
+            if versions.len() > 1 && versions.iter().any(|v| v.kind == RecordKind::Merge) {
+                versions = versions
+                    .into_iter()
+                    .map(|version| value_log::resolve(fs.as_ref(), &base_dir, version))
+                    .collect::<DungeonResult<_>>()?;
+            }
+            options.history.retain(&mut versions);
+            if drop_tombstones && versions.len() == 1 && versions[0].is_tombstone() {
+                continue;
+            }
+            if let Some(value_log) = &mut value_log {
+                for version in &mut versions {
+                    value_log.externalize(version)?;
                 }
-            } else {
-                current_offset =
-                    Self::write_and_index(&mut w, key, &value, &mut index, current_offset)?;
+            }
+            let mut versions = versions.into_iter();
+            if let Some(latest) = versions.next() {
+                current_offset = Self::write_and_index(
+                    &mut w,
+                    key.clone(),
+                    &latest,
+                    &mut index,
+                    current_offset,
+                )?;
+            }
+            for older in versions {
+                index.observe_timestamp(older.timestamp);
+                index.reference(&older);
+                let (segment, next_offset) =
+                    Self::write_entry(&mut w, &key, &older, current_offset)?;
+                index.history.entry(key.clone()).or_default().push(segment);
+                current_offset = next_offset;
             }
         }
-        let full_index_file_path = base_dir.join(format!("{file_name}.index"));
-        std::fs::write(
-            full_index_file_path,
-            to_vec(&index).map_err(|_| DungeonError::new("Could not parse data to bytes"))?,
-        )
-        .map_err(|_| DungeonError::new("Could not save index"))?;
+        let trailer = TableTrailer {
+            range_tombstones: index.range_tombstones.clone(),
+            last_sequence: index.last_sequence,
+        };
+        Self::write_trailer(&mut w, &trailer)?;
+        w.flush()
+            .map_err(|_| DungeonError::new("Could not write to data file"))?;
+        drop(w);
+        // The index is only saved once every record and value it points at is durable
+        if let Some(value_log) = value_log {
+            value_log.finish()?;
+        }
+        fs.sync(&full_data_file_path)
+            .map_err(|_| DungeonError::new("Could not sync data file"))?;
+        index.save(fs.as_ref(), &base_dir.join(format!("{file_name}.index")))?;
 
         Ok(Self {
+            mmap: Self::map_data_file(fs.as_ref(), &full_data_file_path, options.read_mode),
             base_dir,
             index,
             file_name,
+            fs,
         })
     }
-    fn write_entry<W: Write + Seek>(w: &mut W, entry: &TimeStampedValue) -> DungeonResult<usize> {
+    /// Writes `key` followed by the record, returning the segment of the record and the offset
+    /// right after it. The key is never read back through the index, it is there so indexes can
+    /// be rebuilt from the data file alone
+    fn write_entry<W: Write>(
+        w: &mut W,
+        key: &str,
+        entry: &TimeStampedValue,
+        current_offset: usize,
+    ) -> DungeonResult<(DocumentSegment, usize)> {
+        let encoded_key = to_vec(key).map_err(|_| DungeonError::new("Could not parse key"))?;
         let parsed = to_vec(entry).map_err(|_| DungeonError::new("Could not parse value"))?;
+        w.write_all(&encoded_key)
+            .map_err(|_| DungeonError::new("Could not write to data file"))?;
         w.write_all(&parsed)
             .map_err(|_| DungeonError::new("Could not write to data file"))?;
 
-        Ok(parsed.len())
+        let offset = current_offset + encoded_key.len();
+        Ok(((offset, parsed.len()).into(), offset + parsed.len()))
+    }
+    fn write_trailer<W: Write>(w: &mut W, trailer: &TableTrailer) -> DungeonResult<()> {
+        let parsed = to_vec(trailer).map_err(|_| DungeonError::new("Could not parse trailer"))?;
+        w.write_all(&[TRAILER_MARKER])
+            .and_then(|_| w.write_all(&parsed))
+            .map_err(|_| DungeonError::new("Could not write to data file"))
     }
-    fn write_and_index<W: Write + Seek>(
+    fn write_and_index<W: Write>(
         w: &mut W,
         key: String,
         entry: &TimeStampedValue,
         index: &mut Index,
-        mut current_offset: usize,
+        current_offset: usize,
     ) -> DungeonResult<usize> {
-        let length = Self::write_entry(w, entry)?;
-        index.insert(key, (current_offset, length).into());
-        current_offset += length;
-        Ok(current_offset)
+        let (segment, next_offset) = Self::write_entry(w, &key, entry, current_offset)?;
+        if let (Some(sketch), false) = (&mut index.sketch, entry.is_tombstone()) {
+            if !is_index_key(&key) {
+                sketch.insert(&key);
+            }
+        }
+        index.insert(key, segment);
+        index.observe_timestamp(entry.timestamp);
+        index.reference(entry);
+        Ok(next_offset)
     }
-    pub fn from_file(base_dir: PathBuf, file_name: String) -> DungeonResult<Self> {
-        let result_index = Index::from_file(base_dir.join(format!("{}.index", file_name)))?;
+    pub fn from_file(
+        base_dir: PathBuf,
+        file_name: String,
+        options: &ChestOptions,
+    ) -> DungeonResult<Self> {
+        let fs = options.file_system.clone();
+        let result_index =
+            Index::from_file(fs.as_ref(), &base_dir.join(format!("{}.index", file_name)))?;
+        let data_file_path = base_dir.join(format!("{}.chest", file_name));
         Ok(Self {
             index: result_index,
+            mmap: Self::map_data_file(fs.as_ref(), &data_file_path, options.read_mode),
             base_dir,
             file_name,
+            fs,
         })
     }
-    fn read_segment(&self, segment: DocumentSegment) -> DungeonResult<TimeStampedValue> {
-        let data_file_path = self.base_dir.join(format!("{}.chest", self.file_name));
-        let mut r = BufReader::new(
-            std::fs::File::open(data_file_path)
-                .map_err(|_| DungeonError::new("Could not read data file"))?,
-        );
-        r.seek(io::SeekFrom::Start(segment.offset as u64))
-            .map_err(|_| DungeonError::new("Could not access correct data location in sstable"))?;
-        let mut buff = vec![0; segment.length];
-        r.read_exact(&mut buff)
+    /// Maps the data file when `read_mode` asks for it. Any failure while mapping is not an error,
+    /// the table just keeps using buffered reads
+    fn map_data_file(
+        fs: &dyn FileSystem,
+        data_file_path: &Path,
+        read_mode: ReadMode,
+    ) -> Option<MappedFile> {
+        if read_mode != ReadMode::Mmap {
+            return None;
+        }
+        fs.map(data_file_path)
+    }
+    /// Reads the encoded record of a segment, borrowing it straight from the mapping when there
+    /// is one
+    pub(crate) fn read_raw(&self, segment: DocumentSegment) -> DungeonResult<Cow<'_, [u8]>> {
+        if let Some(mmap) = &self.mmap {
+            let buff = (**mmap)
+                .as_ref()
+                .get(segment.offset..segment.offset + segment.length)
+                .ok_or(DungeonError::new(
+                    "Could not access correct data location in sstable",
+                ))?;
+            return Ok(Cow::Borrowed(buff));
+        }
+        let buff = self
+            .fs
+            .read_at(
+                &self.get_data_file_path(),
+                segment.offset as u64,
+                segment.length,
+            )
             .map_err(|_| DungeonError::new("Could not read data file"))?;
+        Ok(Cow::Owned(buff))
+    }
+    pub(crate) fn read_segment(&self, segment: DocumentSegment) -> DungeonResult<TimeStampedValue> {
+        let buff = self.read_raw(segment)?;
         let value: TimeStampedValue =
             from_slice(&buff).map_err(|_| DungeonError::new("Could not parse value"))?;
         Ok(value)
     }
+    /// Same as `read_segment`, reading the value back from its value log
+    fn read_value(&self, segment: DocumentSegment) -> DungeonResult<TimeStampedValue> {
+        value_log::resolve(
+            self.fs.as_ref(),
+            &self.base_dir,
+            self.read_segment(segment)?,
+        )
+    }
     pub fn get(&self, key: &str) -> DungeonResult<Option<TimeStampedValue>> {
         if let Some(segment) = self.index.get(key) {
-            return Ok(self.read_segment(segment).map(Some).unwrap_or(None));
+            let latest = self.read_value(segment)?;
+            // A merge operand may apply on top of older versions kept in this same table
+            if latest.kind == RecordKind::Merge && self.index.history.contains_key(key) {
+                return Ok(resolve_versions(self.get_versions(key)?).into_iter().next());
+            }
+            return Ok(Some(latest));
         }
         Ok(None)
     }
+    /// Every version of `key` kept in this table, newest first
+    pub fn get_versions(&self, key: &str) -> DungeonResult<Vec<TimeStampedValue>> {
+        let Some(latest) = self.index.get(key) else {
+            return Ok(Vec::new());
+        };
+        let history = self.index.history.get(key).into_iter().flatten();
+        std::iter::once(&latest)
+            .chain(history)
+            .map(|segment| self.read_value(*segment))
+            .collect()
+    }
 
+    /// Estimator of the live keys in this table. Tables written before it was kept in the index
+    /// count every key
+    pub fn sketch(&self) -> HyperLogLog {
+        if let Some(sketch) = &self.index.sketch {
+            return sketch.clone();
+        }
+        let mut sketch = HyperLogLog::default();
+        for key in self.index.table.keys() {
+            sketch.insert(key);
+        }
+        sketch
+    }
+    /// Bytes taken by the records of the keys in `range`, including older versions
+    pub fn size_of_range(&self, range: impl RangeBounds<String>) -> u64 {
+        self.index
+            .table
+            .range(range)
+            .map(|(key, segment)| {
+                let history = self.index.history.get(key).into_iter().flatten();
+                std::iter::once(segment)
+                    .chain(history)
+                    .map(|segment| segment.length as u64)
+                    .sum::<u64>()
+            })
+            .sum()
+    }
+    /// Size of the data file, 0 when it can't be read
+    pub fn data_size(&self) -> u64 {
+        self.fs.file_size(&self.get_data_file_path()).unwrap_or(0)
+    }
     pub fn get_data_file_path(&self) -> PathBuf {
         self.base_dir.join(format!("{}.chest", self.file_name))
     }
     pub fn get_index_file_path(&self) -> PathBuf {
         self.base_dir.join(format!("{}.index", self.file_name))
     }
+    /// Removes the index first, a data file left without its index is ignored when opening
     pub fn delete_self(&self) -> DungeonResult<()> {
-        std::fs::remove_file(self.get_data_file_path())
-            .map_err(|_| DungeonError::new("Could not delete data file"))?;
-        std::fs::remove_file(self.get_index_file_path())
+        self.fs
+            .remove_file(&self.get_index_file_path())
             .map_err(|_| DungeonError::new("Could not delete index file"))?;
+        self.fs
+            .remove_file(&self.get_data_file_path())
+            .map_err(|_| DungeonError::new("Could not delete data file"))?;
         Ok(())
     }
     fn segment_reader_fn(
@@ -211,15 +603,167 @@ impl SSTable {
     ) -> impl Fn((String, DocumentSegment)) -> DungeonResult<(String, TimeStampedValue)> + '_ {
         |(key, segment)| Ok((key, self.read_segment(segment)?))
     }
-    /// Merges two sstables using the k-way merge algorithm
-    pub fn merge(&mut self, other: &mut Self, new_file_name: String) -> DungeonResult<Self> {
-        let self_index = std::mem::take(&mut self.index);
-        let other_index = std::mem::take(&mut other.index);
-        let self_values = self_index.flat_map(self.segment_reader_fn());
-        let other_values = other_index.flat_map(other.segment_reader_fn());
+    /// Reads the data file from the start, returning every key and record that decodes along with
+    /// its segment, the trailer if it made it to disk, and the amount of trailing bytes that don't
+    pub fn scan_data_file(
+        fs: &dyn FileSystem,
+        data_file_path: &Path,
+    ) -> DungeonResult<ScannedDataFile> {
+        let data = fs
+            .read(data_file_path)
+            .map_err(|_| DungeonError::new("Could not read data file"))?;
+        let mut cursor = io::Cursor::new(data.as_slice());
+        let mut entries = Vec::new();
+        let mut trailer = None;
+        let mut decoded_len = 0;
+        while decoded_len < data.len() {
+            if data[decoded_len] == TRAILER_MARKER {
+                cursor.set_position(decoded_len as u64 + 1);
+                if let Ok(decoded) = from_read::<_, TableTrailer>(&mut cursor) {
+                    trailer = Some(decoded);
+                    decoded_len = cursor.position() as usize;
+                }
+                break;
+            }
+            let Ok(key) = from_read::<_, String>(&mut cursor) else {
+                break;
+            };
+            let offset = cursor.position() as usize;
+            let Ok(record) = from_read::<_, TimeStampedValue>(&mut cursor) else {
+                break;
+            };
+            decoded_len = cursor.position() as usize;
+            entries.push((key, record, (offset, decoded_len - offset).into()));
+        }
+        Ok(ScannedDataFile {
+            entries,
+            trailer,
+            lost_bytes: data.len() - decoded_len,
+        })
+    }
+    /// Merges sstables using the k-way merge algorithm. Values in value logs are not read, the
+    /// merged table points at them too. `settings` says what to do besides merging
+    pub(crate) fn merge(
+        tables: &mut [Self],
+        new_file_name: String,
+        options: &ChestOptions,
+        mut settings: TableSettings,
+    ) -> DungeonResult<Self> {
+        let base_dir = tables
+            .first()
+            .ok_or(DungeonError::new("Could not merge without sstables"))?
+            .base_dir
+            .clone();
+        let indexes: Vec<Index> = tables
+            .iter_mut()
+            .map(|table| std::mem::take(&mut table.index))
+            .collect();
+        settings.compacted = tables.iter().map(|table| table.file_name.clone()).collect();
+        settings.last_sequence = indexes
+            .iter()
+            .map(|index| index.last_sequence)
+            .max()
+            .unwrap_or(0);
+        settings.range_tombstones = indexes
+            .iter()
+            .flat_map(|index| index.range_tombstones.iter().cloned())
+            .collect();
+        settings.value_log = Self::value_log_for(&base_dir, &new_file_name, options);
+        // Read errors come out first, so a table is never written without some of its inputs
+        let merged = tables
+            .iter()
+            .zip(indexes)
+            .map(|(table, index)| index.into_entries().map(table.segment_reader_fn()))
+            .kmerge_by(|a, b| match (a, b) {
+                (Ok(a), Ok(b)) => a < b,
+                (Err(_), _) => true,
+                (Ok(_), Err(_)) => false,
+            });
+        Self::write_table(
+            base_dir,
+            new_file_name,
+            merged.peekable(),
+            options,
+            settings,
+        )
+    }
+}
 
-        let merged = kmerge(vec![Either::Right(self_values), Either::Left(other_values)]);
+/// Builds an sstable outside of a running chest, for example from an offline job, to be added
+/// later through `Chest::ingest_external`. Keys must be written in strictly increasing order
+pub struct SSTableWriter {
+    index_file_path: PathBuf,
+    fs: Arc<dyn FileSystem>,
+    w: BufWriter<Box<dyn Write + Send>>,
+    index: Index,
+    current_offset: usize,
+    last_timestamp: u128,
+}
 
-        Self::new(self.base_dir.clone(), new_file_name, merged.peekable())
+impl SSTableWriter {
+    /// Starts writing `<file_name>.chest` and `<file_name>.index` inside `dir_path`
+    pub fn create(dir_path: &str, file_name: &str) -> DungeonResult<Self> {
+        Self::with_file_system(Arc::new(OsFileSystem), dir_path, file_name)
+    }
+    /// Same as `create`, writing through `fs`
+    pub fn with_file_system(
+        fs: Arc<dyn FileSystem>,
+        dir_path: &str,
+        file_name: &str,
+    ) -> DungeonResult<Self> {
+        let base_dir = PathBuf::from(dir_path);
+        let w = BufWriter::new(
+            fs.create(&base_dir.join(format!("{file_name}.chest")))
+                .map_err(|_| DungeonError::new("Could not create data file"))?,
+        );
+        Ok(Self {
+            index_file_path: base_dir.join(format!("{file_name}.index")),
+            fs,
+            w,
+            index: Index::new(),
+            current_offset: 0,
+            last_timestamp: 0,
+        })
+    }
+    pub fn put(&mut self, key: &str, value: Value) -> DungeonResult<()> {
+        let timestamp = self.tick();
+        self.append(key, TimeStampedValue::new(value, timestamp))
+    }
+    /// Writes a tombstone, hiding any older value of `key` once ingested
+    pub fn delete(&mut self, key: &str) -> DungeonResult<()> {
+        let timestamp = self.tick();
+        self.append(key, TimeStampedValue::tombstone(timestamp))
+    }
+    fn tick(&mut self) -> u128 {
+        self.last_timestamp = wall_clock_nanos().max(self.last_timestamp + 1);
+        self.last_timestamp
+    }
+    fn append(&mut self, key: &str, record: TimeStampedValue) -> DungeonResult<()> {
+        if let Some((last_key, _)) = self.index.table.last_key_value() {
+            if key <= last_key.as_str() {
+                return Err(DungeonError::new(
+                    "Keys must be written in increasing order",
+                ));
+            }
+        }
+        self.current_offset = SSTable::write_and_index(
+            &mut self.w,
+            key.to_owned(),
+            &record,
+            &mut self.index,
+            self.current_offset,
+        )?;
+        Ok(())
+    }
+    /// Writes the index, returning its path
+    pub fn finish(mut self) -> DungeonResult<PathBuf> {
+        self.w
+            .flush()
+            .map_err(|_| DungeonError::new("Could not write to data file"))?;
+        self.fs
+            .sync(&self.index_file_path.with_extension("chest"))
+            .map_err(|_| DungeonError::new("Could not sync data file"))?;
+        self.index.save(self.fs.as_ref(), &self.index_file_path)?;
+        Ok(self.index_file_path)
     }
 }
@@ -0,0 +1,88 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Snapshot of the counters and gauges of a chest, returned by `Chest::stats`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChestStats {
+    pub memtable_entries: usize,
+    /// Encoded size of the keys and records in the memtable
+    pub memtable_bytes: usize,
+    /// Every sstable, newest first. They all live in a single level
+    pub sstables: Vec<SSTableStats>,
+    /// Reads answered by the filter without touching the memtable or any sstable
+    pub filter_negatives: u64,
+    /// Reads the filter let through
+    pub filter_positives: u64,
+    /// Reads the filter let through for keys that were never written
+    pub filter_false_positives: u64,
+    pub flushes: u64,
+    pub flush_time: Duration,
+    pub compactions: u64,
+    pub compaction_time: Duration,
+    /// Data file bytes written by flushes
+    pub flushed_bytes: u64,
+    /// Data file bytes written by compactions
+    pub compacted_bytes: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SSTableStats {
+    pub name: String,
+    pub keys: usize,
+    pub data_bytes: u64,
+    pub index_bytes: u64,
+}
+
+impl ChestStats {
+    pub fn sstable_bytes(&self) -> u64 {
+        self.sstables
+            .iter()
+            .map(|sstable| sstable.data_bytes + sstable.index_bytes)
+            .sum()
+    }
+    /// Data file bytes written for every byte flushed from the memtable
+    pub fn write_amplification(&self) -> f64 {
+        if self.flushed_bytes == 0 {
+            return 0.0;
+        }
+        (self.flushed_bytes + self.compacted_bytes) as f64 / self.flushed_bytes as f64
+    }
+}
+
+/// Counters updated while the chest runs. Atomic so reads can update them through `&self`
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub filter_negatives: AtomicU64,
+    pub filter_positives: AtomicU64,
+    pub filter_false_positives: AtomicU64,
+    pub flushes: AtomicU64,
+    pub flush_nanos: AtomicU64,
+    pub compactions: AtomicU64,
+    pub compaction_nanos: AtomicU64,
+    pub flushed_bytes: AtomicU64,
+    pub compacted_bytes: AtomicU64,
+}
+
+impl Metrics {
+    pub fn add(counter: &AtomicU64, amount: u64) {
+        counter.fetch_add(amount, Ordering::Relaxed);
+    }
+    pub fn add_time(counter: &AtomicU64, elapsed: Duration) {
+        Self::add(counter, elapsed.as_nanos() as u64);
+    }
+    /// Fills the counters of `stats`
+    pub fn fill(&self, stats: &mut ChestStats) {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        stats.filter_negatives = load(&self.filter_negatives);
+        stats.filter_positives = load(&self.filter_positives);
+        stats.filter_false_positives = load(&self.filter_false_positives);
+        stats.flushes = load(&self.flushes);
+        stats.flush_time = Duration::from_nanos(load(&self.flush_nanos));
+        stats.compactions = load(&self.compactions);
+        stats.compaction_time = Duration::from_nanos(load(&self.compaction_nanos));
+        stats.flushed_bytes = load(&self.flushed_bytes);
+        stats.compacted_bytes = load(&self.compacted_bytes);
+    }
+}
@@ -1,373 +1,2231 @@
-use std::os::unix::fs::MetadataExt;
+use std::{
+    collections::BTreeMap,
+    ops::Deref,
+    path::Path,
+    sync::{Arc, LazyLock},
+};
 
 use cuid::cuid2;
+use errors::ErrorKind;
 use rmp_serde::to_vec;
+use serde::Serialize;
 
-use crate::{filter::bloom::BloomFilter, value::Value};
+use crate::{
+    change_feed::{ChangeEvent, Operation, WriteBatch},
+    dump::DumpFormat,
+    encryption::KeySource,
+    fault_injection::{FaultyFileSystem, Rng},
+    file_system::{MemoryFileSystem, OsFileSystem},
+    filter::bloom::BloomFilter,
+    inspect,
+    iterator::ChestIterator,
+    options::{ChestOptions, History, KeyCharset, ReadMode},
+    repair::repair,
+    secondary_index::IndexDefinition,
+    ss_table::{DocumentSegment, TableTrailer},
+    value::Value,
+};
 
 use super::*;
 
-fn ensure_dir_exists(dir_path: &PathBuf) -> std::io::Result<()> {
-    if !(dir_path.exists() || !dir_path.is_dir()) {
-        std::fs::create_dir(dir_path)?;
+/// Every chest test runs once per read mode, so the mmap reader is held to the same behavior as
+/// the buffered one
+const READ_MODES: [ReadMode; 2] = [ReadMode::Buffered, ReadMode::Mmap];
+
+/// Every test chest lives here, so the tests never touch the disk
+static TEST_FS: LazyLock<MemoryFileSystem> = LazyLock::new(MemoryFileSystem::new);
+
+fn get_test_dir() -> PathBuf {
+    let path = PathBuf::from("/dungeon-tests").join(format!("chest-{}", cuid2()));
+    TEST_FS.create_dir_all(&path).unwrap();
+    path
+}
+
+fn test_options() -> ChestOptions {
+    ChestOptions {
+        file_system: Arc::new(TEST_FS.clone()),
+        ..Default::default()
     }
-    Ok(())
 }
 
-fn get_test_tempdir() -> PathBuf {
-    let dungeon_tests_dir = std::env::temp_dir().join("dungeon-tests");
-    ensure_dir_exists(&dungeon_tests_dir).unwrap();
-    let path = dungeon_tests_dir.join(format!("chest-{}", cuid2()));
-    ensure_dir_exists(&path).unwrap();
-    path
+fn create_writer(dir_path: &Path, file_name: &str) -> SSTableWriter {
+    SSTableWriter::with_file_system(
+        Arc::new(TEST_FS.clone()),
+        dir_path.to_str().unwrap(),
+        file_name,
+    )
+    .unwrap()
 }
 
-#[test]
-fn memtable_set_get() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
+fn open_chest(
+    chest_dir: &Path,
+    flush_size: usize,
+    max_sstable_count: usize,
+    read_mode: ReadMode,
+) -> Chest {
+    Chest::with_options(
         chest_dir.to_str().unwrap(),
-        1024,
-        8,
+        ChestOptions {
+            flush_size,
+            max_sstable_count,
+            read_mode,
+            ..test_options()
+        },
         Box::new(BloomFilter::default()),
     )
-    .unwrap();
-    chest
-        .set(
-            "name",
-            TimeStampedValue::new(Value::String("John Doe".to_owned())),
+    .unwrap()
+}
+
+#[test]
+fn memtable_set_get() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1024, 8, read_mode);
+        chest
+            .set("name", Value::String("John Doe".to_owned()))
+            .unwrap();
+        assert_eq!(
+            chest.get("name").unwrap().unwrap().value,
+            Value::String("John Doe".to_owned())
+        );
+    }
+}
+
+#[test]
+fn test_flush() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 2, 8, read_mode);
+        chest
+            .set("name", Value::String("John Doe".to_owned()))
+            .unwrap();
+        assert_eq!(chest.len(), 1);
+        chest.set("age", Value::Integer(5)).unwrap();
+        assert_eq!(chest.len(), 0);
+    }
+}
+#[test]
+fn test_read_from_sstable() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 2, 8, read_mode);
+        chest.set("foo", Value::String("bar".to_string())).unwrap();
+        chest
+            .set("foo2", Value::String("bar2".to_string()))
+            .unwrap();
+        assert_eq!(chest.len(), 0);
+        assert_eq!(
+            chest.get("foo").unwrap().unwrap().value,
+            Value::String("bar".to_owned())
+        );
+        assert_eq!(
+            chest.get("foo2").unwrap().unwrap().value,
+            Value::String("bar2".to_owned())
+        );
+    }
+}
+
+#[test]
+fn test_reinitialize_chest() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1024, 8, read_mode);
+
+        chest.set("foo", Value::String("bar".to_owned())).unwrap();
+        drop(chest);
+
+        let chest2 = open_chest(&chest_dir, 1024, 8, read_mode);
+        assert_eq!(
+            chest2.get("foo").unwrap().unwrap().value,
+            Value::String("bar".to_owned())
+        );
+    }
+}
+#[test]
+fn test_merge_sstables() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1, 8, read_mode);
+        chest.set("foo", Value::String("bar".to_string())).unwrap();
+        chest.set("foo", Value::String("barz".to_string())).unwrap();
+
+        let mut tables: Vec<SSTable> = chest
+            .sstables
+            .iter()
+            .map(|sstable| sstable.0.clone())
+            .collect();
+
+        let file_name = chest.next_sstable_name();
+        let merged = SSTable::merge(
+            &mut tables,
+            file_name,
+            &chest.options,
+            TableSettings::default(),
         )
         .unwrap();
-    assert_eq!(
-        chest.get("name").unwrap().unwrap().value,
-        Value::String("John Doe".to_owned())
-    );
+        assert_eq!(
+            merged.get("foo").unwrap().unwrap().value,
+            Value::String("barz".to_owned())
+        );
+    }
 }
 
 #[test]
-fn test_flush() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
-        chest_dir.to_str().unwrap(),
-        2,
-        8,
-        Box::new(BloomFilter::default()),
-    )
-    .unwrap();
-    chest
-        .set(
-            "name",
-            TimeStampedValue::new(Value::String("John Doe".to_owned())),
+fn test_merge_sstables_on_limit() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1, 1, read_mode);
+        chest.set("foo", Value::Integer(1)).unwrap();
+        chest.set("bar", Value::Integer(2)).unwrap();
+        assert_eq!(chest.sstables.len(), 1);
+        assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(1));
+        assert_eq!(chest.get("bar").unwrap().unwrap().value, Value::Integer(2));
+    }
+}
+#[test]
+fn test_overwrite_on_merge() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1, 1, read_mode);
+        chest.set("foo", Value::Integer(1)).unwrap();
+        chest.set("foo", Value::Integer(6)).unwrap();
+        assert_eq!(chest.sstables.len(), 1);
+        assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(6));
+        chest.set("foo", Value::Integer(4)).unwrap();
+        assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(4));
+    }
+}
+#[test]
+fn merging_delete_old_sstables() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1, 1, read_mode);
+        chest.set("foo", Value::Integer(1)).unwrap();
+        chest.set("bar", Value::Integer(2)).unwrap();
+        drop(chest);
+        let chest = open_chest(&chest_dir, 1, 1, read_mode);
+        assert_eq!(chest.sstables.len(), 1);
+        assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(1));
+        assert_eq!(chest.get("bar").unwrap().unwrap().value, Value::Integer(2));
+    }
+}
+
+#[test]
+fn test_delete_value() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 4, 1, read_mode);
+        chest.set("count", Value::Integer(0)).unwrap();
+        chest.set("count", Value::Integer(1)).unwrap();
+        assert_eq!(
+            chest.get("count").unwrap().unwrap().value,
+            Value::Integer(1)
+        );
+        chest.delete("count").unwrap();
+        assert_eq!(chest.get("count").unwrap(), None);
+    }
+}
+
+#[test]
+fn test_delete_from_sstable() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1, 1, read_mode);
+        chest.set("count", Value::Integer(0)).unwrap();
+        assert_eq!(
+            chest.get("count").unwrap().unwrap().value,
+            Value::Integer(0)
+        );
+        chest.delete("count").unwrap();
+        assert_eq!(chest.get("count").unwrap(), None);
+    }
+}
+
+#[test]
+fn test_clean_sstable() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1, 1, read_mode);
+        chest.set("foo", Value::Integer(0)).unwrap();
+        chest.set("foo", Value::Integer(1)).unwrap();
+        assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(1));
+        chest.set("bar", Value::Float(1.5)).unwrap();
+        chest.set("bar", Value::Float(3.5)).unwrap();
+        assert_eq!(chest.sstables.len(), 1);
+        let expected_size = to_vec("foo").unwrap().len()
+            + to_vec(&TimeStampedValue::new(Value::Integer(1), 0))
+                .unwrap()
+                .len()
+            + to_vec("bar").unwrap().len()
+            + to_vec(&TimeStampedValue::new(Value::Float(3.5), 0))
+                .unwrap()
+                .len()
+            // The trailer, after its marker
+            + 1
+            + to_vec(&TableTrailer::default()).unwrap().len();
+        let table = &chest.sstables.iter().next().unwrap().0;
+        let data_file_path = table.get_data_file_path();
+        let file_size = TEST_FS.file_size(&data_file_path).unwrap();
+        assert_eq!(expected_size as u64, file_size);
+    }
+}
+#[test]
+fn keys_are_sorted() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1, 1, read_mode);
+        chest.set("grape", Value::Integer(0)).unwrap();
+        chest.set("apple", Value::Integer(1)).unwrap();
+        chest.set("peach", Value::Integer(2)).unwrap();
+        chest.set("orange", Value::Integer(3)).unwrap();
+        let mut table = chest.sstables.clone().into_iter().next().unwrap().0;
+        assert_eq!(table.index.next().unwrap().0, "apple".to_owned());
+        assert_eq!(table.index.next().unwrap().0, "grape".to_owned());
+        assert_eq!(table.index.next().unwrap().0, "orange".to_owned());
+        assert_eq!(table.index.next().unwrap().0, "peach".to_owned());
+    }
+}
+
+#[test]
+fn dead_value_cancel() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1, 64, read_mode);
+        chest.set("foo", Value::Integer(0)).unwrap();
+        chest.delete("foo").unwrap();
+        assert_eq!(chest.sstables.len(), 2);
+        let first = chest.sstables.pop_first().unwrap().0;
+        let second = chest.sstables.pop_first().unwrap().0;
+        assert_eq!(first.index.table.len(), 1);
+        assert_eq!(second.index.table.len(), 1);
+        let settings = TableSettings {
+            drop_tombstones: true,
+            ..Default::default()
+        };
+        let merged = SSTable::merge(
+            &mut [first, second],
+            "merged".to_owned(),
+            &chest.options,
+            settings,
         )
         .unwrap();
-    assert_eq!(chest.len(), 1);
-    chest
-        .set("age", TimeStampedValue::new(Value::Integer(5)))
+        assert_eq!(merged.index.table.len(), 0);
+    }
+}
+
+#[test]
+fn mmap_reads_from_mapping() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 1, 8, ReadMode::Mmap);
+    chest.set("foo", Value::Integer(1)).unwrap();
+    let table = &chest.sstables.iter().next().unwrap().0;
+    // The mapping stays valid after the file is gone, while a buffered read would fail
+    TEST_FS.remove_file(&table.get_data_file_path()).unwrap();
+    assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(1));
+}
+
+#[test]
+fn null_is_not_a_tombstone() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 2, 8, read_mode);
+        chest.set("foo", Value::Null).unwrap();
+        assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Null);
+        chest
+            .set(
+                "bar",
+                Value::List(vec![Value::Bytes(vec![1, 2]), Value::Timestamp(10)]),
+            )
+            .unwrap();
+        assert_eq!(chest.len(), 0);
+        assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Null);
+        assert_eq!(
+            chest.get("bar").unwrap().unwrap().value,
+            Value::List(vec![Value::Bytes(vec![1, 2]), Value::Timestamp(10)])
+        );
+    }
+}
+
+#[test]
+fn merge_operands_combine() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 2, 8, read_mode);
+        chest.set("count", Value::Integer(1)).unwrap();
+        chest.merge("count", Value::Integer(2)).unwrap();
+        assert_eq!(
+            chest.get("count").unwrap().unwrap().value,
+            Value::Integer(3)
+        );
+        // Flushes an operand with nothing older in the memtable
+        chest.merge("count", Value::Integer(4)).unwrap();
+        chest.set("other", Value::Null).unwrap();
+        assert_eq!(chest.len(), 0);
+        assert_eq!(
+            chest.get("count").unwrap().unwrap().value,
+            Value::Integer(7)
+        );
+        chest
+            .merge("tags", Value::List(vec![Value::Integer(1)]))
+            .unwrap();
+        assert_eq!(
+            chest.get("tags").unwrap().unwrap().value,
+            Value::List(vec![Value::Integer(1)])
+        );
+    }
+}
+
+#[test]
+fn merge_operands_survive_compaction() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1, 1, read_mode);
+        chest.set("count", Value::Integer(1)).unwrap();
+        chest.merge("count", Value::Integer(2)).unwrap();
+        chest.merge("count", Value::Integer(3)).unwrap();
+        assert_eq!(chest.sstables.len(), 1);
+        assert_eq!(
+            chest.get("count").unwrap().unwrap().value,
+            Value::Integer(6)
+        );
+        chest.delete("count").unwrap();
+        chest.merge("count", Value::Integer(5)).unwrap();
+        assert_eq!(
+            chest.get("count").unwrap().unwrap().value,
+            Value::Integer(5)
+        );
+    }
+}
+
+/// Encodings used before record kinds existed, to check tables written back then are migrated
+#[derive(Serialize)]
+enum LegacyValue {
+    Integer(i64),
+    Invalid,
+}
+#[derive(Serialize)]
+struct LegacyRecord {
+    timestamp: u128,
+    value: LegacyValue,
+}
+#[derive(Serialize)]
+struct LegacyIndex {
+    table: BTreeMap<String, DocumentSegment>,
+}
+
+/// Index of version 1 tables, whose records weren't preceded by their key
+#[derive(Serialize)]
+struct UnkeyedIndex {
+    table: BTreeMap<String, DocumentSegment>,
+    version: u32,
+}
+
+#[test]
+fn migrate_unkeyed_sstables() {
+    let chest_dir = get_test_dir();
+    let encoded = to_vec(&TimeStampedValue::new(Value::Integer(5), 1)).unwrap();
+    let table = BTreeMap::from([("foo".to_owned(), (0, encoded.len()).into())]);
+    TEST_FS.write(&chest_dir.join("1.chest"), &encoded).unwrap();
+    TEST_FS
+        .write(
+            &chest_dir.join("1.index"),
+            &to_vec(&UnkeyedIndex { table, version: 1 }).unwrap(),
+        )
         .unwrap();
-    assert_eq!(chest.len(), 0);
+
+    let chest = open_chest(&chest_dir, 1024, 8, ReadMode::Buffered);
+    assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(5));
+    let table = &chest.sstables.iter().next().unwrap().0;
+    let scanned = SSTable::scan_data_file(&*TEST_FS, &table.get_data_file_path()).unwrap();
+    assert_eq!(scanned.entries.len(), 1);
+    assert_eq!(scanned.entries[0].0, "foo");
+    assert!(scanned.trailer.is_some());
+    assert_eq!(scanned.lost_bytes, 0);
+}
+
+#[test]
+fn migrate_legacy_sstables() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut data = Vec::new();
+        let mut table = BTreeMap::new();
+        for (key, value) in [
+            ("count", LegacyValue::Integer(3)),
+            ("removed", LegacyValue::Invalid),
+        ] {
+            let encoded = to_vec(&LegacyRecord {
+                timestamp: 1,
+                value,
+            })
+            .unwrap();
+            table.insert(key.to_owned(), (data.len(), encoded.len()).into());
+            data.extend(encoded);
+        }
+        TEST_FS.write(&chest_dir.join("1.chest"), &data).unwrap();
+        TEST_FS
+            .write(
+                &chest_dir.join("1.index"),
+                &to_vec(&LegacyIndex { table }).unwrap(),
+            )
+            .unwrap();
+
+        let chest = open_chest(&chest_dir, 1024, 8, read_mode);
+        let table = &chest.sstables.iter().next().unwrap().0;
+        assert_eq!(table.index.version, FORMAT_VERSION);
+        assert_eq!(table.file_name, "1");
+        let removed = table.get("removed").unwrap().unwrap();
+        assert_eq!(removed.kind, RecordKind::Delete);
+        assert_eq!(
+            chest.get("count").unwrap().unwrap().value,
+            Value::Integer(3)
+        );
+        assert_eq!(chest.get("removed").unwrap(), None);
+        assert!(!TEST_FS.is_dir(&chest_dir.join("migration")));
+    }
 }
+
 #[test]
-fn test_read_from_sstable() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
+fn clock_survives_restart() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1024, 8, read_mode);
+        // Pushes the clock far ahead of the wall clock, as if the wall clock went backwards
+        // before the restart
+        chest.clock.observe(u128::MAX / 2);
+        chest.set("foo", Value::Integer(1)).unwrap();
+        let first = chest.get("foo").unwrap().unwrap().timestamp;
+        drop(chest);
+
+        let mut chest = open_chest(&chest_dir, 1024, 8, read_mode);
+        chest.set("foo", Value::Integer(2)).unwrap();
+        let second = chest.get("foo").unwrap().unwrap().timestamp;
+        assert!(second > first);
+        assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(2));
+    }
+}
+
+fn open_chest_with_history(chest_dir: &Path, flush_size: usize, history: History) -> Chest {
+    Chest::with_options(
         chest_dir.to_str().unwrap(),
-        2,
-        8,
+        ChestOptions {
+            flush_size,
+            max_sstable_count: 2,
+            history,
+            ..test_options()
+        },
         Box::new(BloomFilter::default()),
     )
-    .unwrap();
-    chest
-        .set(
-            "foo",
-            TimeStampedValue::new(Value::String("bar".to_string())),
-        )
-        .unwrap();
-    chest
-        .set(
-            "foo2",
-            TimeStampedValue::new(Value::String("bar2".to_string())),
-        )
-        .unwrap();
-    assert_eq!(chest.len(), 0);
-    assert_eq!(
-        chest.get("foo").unwrap().unwrap().value,
-        Value::String("bar".to_owned())
+    .unwrap()
+}
+
+#[test]
+fn history_keeps_versions() {
+    for flush_size in [1, 2, 1024] {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest_with_history(&chest_dir, flush_size, History::Versions(3));
+        let mut timestamps = Vec::new();
+        for i in 0..5 {
+            chest.set("foo", Value::Integer(i)).unwrap();
+            timestamps.push(chest.get("foo").unwrap().unwrap().timestamp);
+            chest.set("other", Value::Integer(i)).unwrap();
+        }
+        drop(chest);
+
+        let chest = open_chest_with_history(&chest_dir, flush_size, History::Versions(3));
+        let values: Vec<_> = chest
+            .history("foo")
+            .unwrap()
+            .into_iter()
+            .map(|version| version.value)
+            .collect();
+        assert!(values.starts_with(&[Value::Integer(4), Value::Integer(3), Value::Integer(2)]));
+        assert_eq!(
+            chest.get_at("foo", timestamps[3]).unwrap().unwrap().value,
+            Value::Integer(3)
+        );
+        assert_eq!(chest.get_at("foo", timestamps[0] - 1).unwrap(), None);
+        assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(4));
+    }
+}
+
+#[test]
+fn history_reads_past_deletes_and_merges() {
+    for flush_size in [1, 1024] {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest_with_history(&chest_dir, flush_size, History::Versions(8));
+        chest.set("count", Value::Integer(1)).unwrap();
+        chest.merge("count", Value::Integer(2)).unwrap();
+        let merged_at = chest.get("count").unwrap().unwrap().timestamp;
+        chest.merge("count", Value::Integer(4)).unwrap();
+        assert_eq!(
+            chest.get("count").unwrap().unwrap().value,
+            Value::Integer(7)
+        );
+        chest.delete("count").unwrap();
+        assert_eq!(chest.get("count").unwrap(), None);
+        assert_eq!(
+            chest.get_at("count", merged_at).unwrap().unwrap().value,
+            Value::Integer(3)
+        );
+        let history = chest.history("count").unwrap();
+        assert!(history[0].is_tombstone());
+        assert_eq!(history[1].value, Value::Integer(7));
+    }
+}
+
+#[test]
+fn history_disabled_keeps_latest() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest_with_history(&chest_dir, 1, History::Disabled);
+    for i in 0..4 {
+        chest.set("foo", Value::Integer(i)).unwrap();
+    }
+    let history = chest.history("foo").unwrap();
+    assert!(history.len() <= 2);
+    assert_eq!(history[0].value, Value::Integer(3));
+}
+
+#[test]
+fn checkpoint_opens_standalone() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let checkpoint_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 2, 8, read_mode);
+        for i in 0..5 {
+            chest.set(&format!("key{i}"), Value::Integer(i)).unwrap();
+        }
+        chest.checkpoint(checkpoint_dir.to_str().unwrap()).unwrap();
+        chest.set("key0", Value::Integer(100)).unwrap();
+        // A checkpoint never writes into a directory with files in it
+        assert!(chest.checkpoint(checkpoint_dir.to_str().unwrap()).is_err());
+
+        let checkpoint = open_chest(&checkpoint_dir, 2, 8, read_mode);
+        for i in 0..5 {
+            assert_eq!(
+                checkpoint.get(&format!("key{i}")).unwrap().unwrap().value,
+                Value::Integer(i)
+            );
+        }
+    }
+}
+
+#[test]
+fn backup_copies_new_tables() {
+    let chest_dir = get_test_dir();
+    let backup_dir = get_test_dir();
+    let backup_path = backup_dir.to_str().unwrap();
+    let mut chest = open_chest(&chest_dir, 1, 2, ReadMode::Buffered);
+    chest.set("foo", Value::Integer(1)).unwrap();
+    chest.set("bar", Value::Integer(2)).unwrap();
+    let first = chest.backup(backup_path).unwrap();
+    assert_eq!(first.copied_files, 4);
+    assert_eq!(chest.backup(backup_path).unwrap().copied_files, 0);
+
+    // Merges the newest table away, so the backup drops it too
+    chest.set("foo", Value::Integer(3)).unwrap();
+    let second = chest.backup(backup_path).unwrap();
+    assert_eq!(second.copied_files, 2);
+    assert_eq!(second.removed_files, 2);
+    drop(chest);
+
+    let backup = open_chest(&backup_dir, 1, 2, ReadMode::Buffered);
+    assert_eq!(backup.get("foo").unwrap().unwrap().value, Value::Integer(3));
+    assert_eq!(backup.get("bar").unwrap().unwrap().value, Value::Integer(2));
+}
+
+#[test]
+fn export_and_import() {
+    for format in [DumpFormat::JsonLines, DumpFormat::MessagePack] {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 2, 8, ReadMode::Buffered);
+        chest.set("int", Value::Integer(1)).unwrap();
+        chest.set("bytes", Value::Bytes(vec![0, 255])).unwrap();
+        chest
+            .set("list", Value::List(vec![Value::Null, Value::Timestamp(7)]))
+            .unwrap();
+        chest.set("removed", Value::Boolean(true)).unwrap();
+        chest.delete("removed").unwrap();
+        chest.merge("int", Value::Integer(2)).unwrap();
+        let mut dump = Vec::new();
+        assert_eq!(chest.export(&mut dump, format).unwrap(), 3);
+
+        let imported_dir = get_test_dir();
+        let mut imported = open_chest(&imported_dir, 2, 8, ReadMode::Buffered);
+        imported.set("int", Value::Integer(100)).unwrap();
+        assert_eq!(imported.import(dump.as_slice(), format).unwrap(), 3);
+        assert_eq!(imported.len(), 0);
+        let entries: Vec<_> = imported.scan().map(|entry| entry.unwrap()).collect();
+        let expected: Vec<_> = chest.scan().map(|entry| entry.unwrap()).collect();
+        assert_eq!(entries.len(), 3);
+        for ((key, value), (expected_key, expected_value)) in entries.iter().zip(&expected) {
+            assert_eq!(key, expected_key);
+            assert_eq!(value.value, expected_value.value);
+        }
+        assert_eq!(
+            imported.get("int").unwrap().unwrap().value,
+            Value::Integer(3)
+        );
+    }
+}
+
+#[test]
+fn import_keeps_last_repeated_key() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 1024, 8, ReadMode::Buffered);
+    let dump = concat!(
+        "{\"key\":\"b\",\"value\":{\"Integer\":1}}\n",
+        "{\"key\":\"a\",\"value\":{\"Integer\":2}}\n",
+        "\n",
+        "{\"key\":\"b\",\"value\":{\"Integer\":3}}\n",
     );
     assert_eq!(
-        chest.get("foo2").unwrap().unwrap().value,
-        Value::String("bar2".to_owned())
+        chest
+            .import(dump.as_bytes(), DumpFormat::JsonLines)
+            .unwrap(),
+        3
     );
+    assert_eq!(chest.get("a").unwrap().unwrap().value, Value::Integer(2));
+    assert_eq!(chest.get("b").unwrap().unwrap().value, Value::Integer(3));
+    assert!(chest
+        .import("not json\n".as_bytes(), DumpFormat::JsonLines)
+        .is_err());
 }
 
 #[test]
-fn test_reinitialize_chest() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
+fn ingest_external_sstables() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let external_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 1024, 8, read_mode);
+        chest.set("a", Value::Integer(1)).unwrap();
+        chest.set("z", Value::Integer(1)).unwrap();
+
+        let mut first = create_writer(&external_dir, "first");
+        first.put("b", Value::Integer(2)).unwrap();
+        first.delete("c").unwrap();
+        assert!(first.put("b", Value::Integer(3)).is_err());
+        let first = first.finish().unwrap();
+        let mut second = create_writer(&external_dir, "second");
+        second.put("d", Value::Integer(4)).unwrap();
+        let second = second.finish().unwrap();
+
+        chest.ingest_external(&[&first, &second]).unwrap();
+        assert!(!TEST_FS.is_file(&first));
+        assert!(!TEST_FS.is_dir(&chest_dir.join("ingest")));
+        chest.set("d", Value::Integer(5)).unwrap();
+        drop(chest);
+
+        let chest = open_chest(&chest_dir, 1024, 8, read_mode);
+        assert_eq!(chest.get("a").unwrap().unwrap().value, Value::Integer(1));
+        assert_eq!(chest.get("b").unwrap().unwrap().value, Value::Integer(2));
+        assert_eq!(chest.get("c").unwrap(), None);
+        assert_eq!(chest.get("d").unwrap().unwrap().value, Value::Integer(5));
+    }
+}
+
+#[test]
+fn ingest_external_rejects_invalid_tables() {
+    let chest_dir = get_test_dir();
+    let external_dir = get_test_dir();
+    let mut first = create_writer(&external_dir, "first");
+    first.put("a", Value::Integer(1)).unwrap();
+    first.put("c", Value::Integer(1)).unwrap();
+    let first = first.finish().unwrap();
+    let mut second = create_writer(&external_dir, "second");
+    second.put("b", Value::Integer(2)).unwrap();
+    let second = second.finish().unwrap();
+
+    let mut chest = open_chest(&chest_dir, 1024, 8, ReadMode::Buffered);
+    assert!(chest.ingest_external(&[&first, &second]).is_err());
+    // Written after the external table was built, so the table would hide a newer value
+    chest.set("b", Value::Integer(3)).unwrap();
+    assert!(chest.ingest_external(&[&second]).is_err());
+    assert_eq!(chest.get("b").unwrap().unwrap().value, Value::Integer(3));
+    assert!(TEST_FS.is_file(&second));
+}
+
+#[test]
+fn ingest_external_checks_keys_and_values() {
+    let chest_dir = get_test_dir();
+    let external_dir = get_test_dir();
+    let mut chest = Chest::with_options(
         chest_dir.to_str().unwrap(),
-        1024,
-        8,
+        ChestOptions {
+            max_key_size: 8,
+            max_value_size: 16,
+            key_charset: KeyCharset::AsciiAlphanumeric,
+            ..test_options()
+        },
         Box::new(BloomFilter::default()),
     )
     .unwrap();
+    let reserved = format!("{}by_user", secondary_index::INDEX_KEY_PREFIX);
+    let cases = [
+        ("muchtoolong", Value::Null, ErrorKind::KeyTooLarge),
+        ("a:b", Value::Null, ErrorKind::InvalidKey),
+        ("", Value::Null, ErrorKind::InvalidKey),
+        ("a", Value::String("x".repeat(16)), ErrorKind::ValueTooLarge),
+        (reserved.as_str(), Value::Null, ErrorKind::InvalidKey),
+    ];
+    for (i, (key, value, kind)) in cases.into_iter().enumerate() {
+        let mut writer = create_writer(&external_dir, &format!("table{i}"));
+        writer.put(key, value).unwrap();
+        let table = writer.finish().unwrap();
+        assert_eq!(chest.ingest_external(&[&table]).unwrap_err().kind, kind);
+        assert!(TEST_FS.is_file(&table));
+    }
+    assert!(chest.sstables.is_empty());
 
-    chest
-        .set(
-            "foo",
-            TimeStampedValue::new(Value::String("bar".to_owned())),
-        )
+    // Tombstones have no value to check
+    let mut writer = create_writer(&external_dir, "valid");
+    writer.put("a", Value::Integer(1)).unwrap();
+    writer.delete("b").unwrap();
+    let table = writer.finish().unwrap();
+    chest.ingest_external(&[&table]).unwrap();
+    assert_eq!(chest.get("a").unwrap().unwrap().value, Value::Integer(1));
+}
+
+#[test]
+fn uncommitted_ingestion_is_dropped() {
+    let chest_dir = get_test_dir();
+    let staging_dir = chest_dir.join("ingest");
+    TEST_FS.create_dir_all(&staging_dir).unwrap();
+    let mut writer = create_writer(&staging_dir, "1");
+    writer.put("a", Value::Integer(1)).unwrap();
+    writer.finish().unwrap();
+
+    let chest = open_chest(&chest_dir, 1024, 8, ReadMode::Buffered);
+    assert_eq!(chest.get("a").unwrap(), None);
+    assert!(!TEST_FS.is_dir(&staging_dir));
+}
+
+#[test]
+fn repair_rebuilds_and_quarantines() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 2, 8, ReadMode::Buffered);
+    for i in 0..6 {
+        chest.set(&format!("key{i}"), Value::Integer(i)).unwrap();
+    }
+    let mut tables = chest.sstables.iter().map(|sstable| sstable.0.clone());
+    let missing_index = tables.next().unwrap();
+    let corrupt_index = tables.next().unwrap();
+    let garbage = tables.next().unwrap();
+    drop(tables);
+    drop(chest);
+    TEST_FS
+        .remove_file(&missing_index.get_index_file_path())
         .unwrap();
+    TEST_FS
+        .write(&corrupt_index.get_index_file_path(), b"not an index")
+        .unwrap();
+    TEST_FS
+        .write(&garbage.get_data_file_path(), b"garbage")
+        .unwrap();
+    // Cut halfway through the last record, so only the first one is recovered
+    let data_file_path = missing_index.get_data_file_path();
+    let data = TEST_FS.read(&data_file_path).unwrap();
+    let scanned = SSTable::scan_data_file(&*TEST_FS, &data_file_path).unwrap();
+    let (_, _, last) = scanned.entries.last().unwrap();
+    TEST_FS
+        .write(&data_file_path, &data[..last.offset() + last.length() - 2])
+        .unwrap();
+
+    let report = repair(chest_dir.to_str().unwrap(), &test_options()).unwrap();
+    assert_eq!(report.rebuilt.len(), 2);
+    assert_eq!(report.quarantined.len(), 2);
+    assert_eq!(report.recovered_entries, 3);
+    assert!(report.lost_bytes > 0);
+    assert!(TEST_FS.is_file(&chest_dir.join("REPAIR_REPORT")));
+
+    let chest = open_chest(&chest_dir, 2, 8, ReadMode::Buffered);
+    let recovered = (0..6)
+        .filter(|i| chest.get(&format!("key{i}")).unwrap().is_some())
+        .count();
+    assert_eq!(recovered, 3);
     drop(chest);
+    assert!(repair(chest_dir.to_str().unwrap(), &test_options())
+        .unwrap()
+        .is_clean());
+}
+
+#[test]
+fn inspect_sstable() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 4, 8, ReadMode::Buffered);
+    chest.set("b", Value::Integer(1)).unwrap();
+    chest.delete("c").unwrap();
+    chest.merge("a", Value::Integer(2)).unwrap();
+    chest.set("d", Value::Integer(3)).unwrap();
+    let table = chest.sstables.iter().next().unwrap().0.clone();
+    let index_file_path = table.get_index_file_path();
+
+    let entries = inspect::dump(&index_file_path, &test_options()).unwrap();
+    let keys: Vec<_> = entries.iter().map(|entry| entry.key.as_str()).collect();
+    assert_eq!(keys, ["a", "b", "c", "d"]);
+    assert_eq!(entries[1].record.as_ref().unwrap().value, Value::Integer(1));
+
+    let stats = inspect::stats(&table.get_data_file_path(), &test_options()).unwrap();
+    assert_eq!(stats.entries, 4);
+    assert_eq!(stats.tombstones, 1);
+    assert_eq!(stats.merge_operands, 1);
+    assert_eq!(stats.key_range, Some(("a".to_owned(), "d".to_owned())));
+    assert!(stats.is_valid());
+
+    // Overwrites the record of "b" with bytes that don't decode
+    let segment = table.index.get("b").unwrap();
+    let mut data = TEST_FS.read(&table.get_data_file_path()).unwrap();
+    data[segment.offset()..segment.offset() + segment.length()].fill(0xc1);
+    TEST_FS.write(&table.get_data_file_path(), &data).unwrap();
+    let stats = inspect::stats(&index_file_path, &test_options()).unwrap();
+    assert_eq!(stats.corrupt_keys, ["b"]);
+}
 
-    let chest2 = Chest::new(
+#[test]
+fn dir_is_locked_while_open() {
+    let chest_dir = get_test_dir();
+    let chest = open_chest(&chest_dir, 1024, 8, ReadMode::Buffered);
+    let second = Chest::with_options(
         chest_dir.to_str().unwrap(),
-        1024,
-        8,
+        test_options(),
         Box::new(BloomFilter::default()),
-    )
-    .unwrap();
-    assert_eq!(
-        chest2.get("foo").unwrap().unwrap().value,
-        Value::String("bar".to_owned())
     );
+    assert!(second.is_err());
+    drop(chest);
+    open_chest(&chest_dir, 1024, 8, ReadMode::Buffered);
 }
+
 #[test]
-fn test_merge_sstables() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
-        chest_dir.to_str().unwrap(),
-        1,
-        8,
-        Box::new(BloomFilter::default()),
-    )
-    .unwrap();
-    chest
-        .set(
-            "foo",
-            TimeStampedValue::new(Value::String("bar".to_string())),
+fn os_file_system_reopens_and_locks() {
+    let chest_dir = std::env::temp_dir().join(format!("dungeon-tests-{}", cuid2()));
+    let options = ChestOptions {
+        flush_size: 1,
+        file_system: Arc::new(OsFileSystem),
+        ..Default::default()
+    };
+    let open = || {
+        Chest::with_options(
+            chest_dir.to_str().unwrap(),
+            options.clone(),
+            Box::new(BloomFilter::default()),
         )
-        .unwrap();
-    chest
-        .set(
-            "foo",
-            TimeStampedValue::new(Value::String("barz".to_string())),
+    };
+    let mut chest = open().unwrap();
+    chest.set("foo", Value::Integer(1)).unwrap();
+    assert!(open().is_err());
+    drop(chest);
+    let chest = open().unwrap();
+    assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(1));
+    drop(chest);
+    std::fs::remove_dir_all(&chest_dir).unwrap();
+}
+
+#[test]
+fn read_only_observes_writer() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut writer = open_chest(&chest_dir, 1, 2, read_mode);
+        writer.set("foo", Value::Integer(1)).unwrap();
+        let mut reader = Chest::with_options(
+            chest_dir.to_str().unwrap(),
+            ChestOptions {
+                read_only: true,
+                read_mode,
+                ..test_options()
+            },
+            Box::new(BloomFilter::default()),
         )
         .unwrap();
+        assert_eq!(reader.get("foo").unwrap().unwrap().value, Value::Integer(1));
+        assert!(reader.set("foo", Value::Integer(2)).is_err());
+        assert!(reader.delete("foo").is_err());
 
-    let mut iter_chest_sstables = chest.sstables.iter().cloned();
-    let mut table1 = iter_chest_sstables.next().unwrap();
-    let mut table2 = iter_chest_sstables.next().unwrap();
+        writer.set("bar", Value::Integer(2)).unwrap();
+        // Merges the table holding "bar" away
+        writer.set("foo", Value::Integer(3)).unwrap();
+        reader.refresh().unwrap();
+        assert_eq!(reader.get("foo").unwrap().unwrap().value, Value::Integer(3));
+        assert_eq!(reader.get("bar").unwrap().unwrap().value, Value::Integer(2));
+        assert_eq!(reader.sstables.len(), writer.sstables.len());
+        assert!(writer.refresh().is_err());
+        drop(reader);
+        drop(writer);
+    }
+}
 
-    let merged = table1
-        .0
-        .merge(&mut table2.0, generate_sstable_name())
-        .unwrap();
+#[test]
+fn stats_track_reads_and_writes() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 2, 1, ReadMode::Buffered);
+    chest.set("a", Value::Integer(1)).unwrap();
+    let stats = chest.stats();
+    assert_eq!(stats.memtable_entries, 1);
+    assert!(stats.memtable_bytes > 0);
+    assert_eq!(stats.flushes, 0);
+
+    chest.set("b", Value::Integer(2)).unwrap();
+    chest.set("c", Value::Integer(3)).unwrap();
+    chest.set("d", Value::Integer(4)).unwrap();
+    chest.get("a").unwrap();
+    chest.get("missing").unwrap();
+    let stats = chest.stats();
+    assert_eq!(stats.memtable_entries, 0);
+    assert_eq!(stats.flushes, 2);
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.sstables.len(), 1);
+    assert_eq!(stats.sstables[0].keys, 4);
+    assert_eq!(stats.sstables[0].data_bytes, stats.compacted_bytes);
+    assert!(stats.write_amplification() > 1.0);
     assert_eq!(
-        merged.get("foo").unwrap().unwrap().value,
-        Value::String("barz".to_owned())
+        stats.filter_negatives + stats.filter_positives,
+        2,
+        "every read goes through the filter"
     );
+    assert_eq!(stats.filter_false_positives, stats.filter_positives - 1);
 }
 
 #[test]
-fn test_merge_sstables_on_limit() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
-        chest_dir.to_str().unwrap(),
-        1,
-        1,
-        Box::new(BloomFilter::default()),
-    )
-    .unwrap();
-    chest
-        .set("foo", TimeStampedValue::new(Value::Integer(1)))
-        .unwrap();
+fn count_and_estimate_keys() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 64, 4, ReadMode::Buffered);
+    assert!(chest.is_empty());
+    assert_eq!(chest.count().unwrap(), 0);
+    for i in 0..500 {
+        chest.set(&format!("key{i:03}"), Value::Integer(i)).unwrap();
+    }
+    for i in 0..100 {
+        chest.delete(&format!("key{i:03}")).unwrap();
+    }
+    assert!(!chest.is_empty());
+    assert_eq!(chest.count().unwrap(), 400);
+    let estimate = chest.approximate_len() as f64;
+    // Deletes flushed in another table than their value are still counted
+    assert!((360.0..=550.0).contains(&estimate));
+
+    let total = chest.approximate_size(..);
+    let half = chest.approximate_size("key000".to_owned().."key250".to_owned());
+    assert!(half > 0 && half < total);
+    assert_eq!(chest.approximate_size("x".to_owned()..), 0);
+}
+
+/// Live keys of `chest` with their integer values
+fn read_integers(chest: &Chest) -> BTreeMap<String, i64> {
     chest
-        .set("bar", TimeStampedValue::new(Value::Integer(2)))
-        .unwrap();
-    assert_eq!(chest.sstables.len(), 1);
-    assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(1));
-    assert_eq!(chest.get("bar").unwrap().unwrap().value, Value::Integer(2));
+        .scan()
+        .map(|entry| {
+            let (key, record) = entry.unwrap();
+            let Value::Integer(value) = record.value else {
+                panic!("Unexpected value for {key}");
+            };
+            (key, value)
+        })
+        .collect()
+}
+
+/// Runs random sets, deletes, merges and flushes against a model until the file system crashes,
+/// then cuts the power and checks that the reopened chest holds what the model had at the last
+/// flush, or at the one that was running when it crashed. With the write ahead log every write
+/// that returned must be there, and the one that failed may be
+fn check_crash_recovery(seed: u64, wal: bool) {
+    let fs = FaultyFileSystem::new(seed);
+    let mut rng = Rng::new(seed.wrapping_add(1));
+    let options = ChestOptions {
+        flush_size: 1024,
+        max_sstable_count: 3,
+        file_system: Arc::new(fs.clone()),
+        // Half the runs move every value to value logs
+        value_log_threshold: (seed % 2 == 1).then_some(0),
+        wal,
+        ..Default::default()
+    };
+    let open = || {
+        Chest::with_options("/chest", options.clone(), Box::new(BloomFilter::default())).unwrap()
+    };
+    let mut chest = open();
+    let mut model = BTreeMap::new();
+    let mut flushed = BTreeMap::new();
+    let mut failed = None;
+    fs.crash_after(rng.below(400));
+    for _ in 0..200 {
+        let key = format!("key{}", rng.below(8));
+        let operand = rng.below(100) as i64;
+        let mut next = model.clone();
+        let op = rng.below(8);
+        let result = match op {
+            0..=3 => {
+                next.insert(key.clone(), operand);
+                chest.set(&key, Value::Integer(operand))
+            }
+            4 => {
+                next.remove(&key);
+                chest.delete(&key)
+            }
+            5 | 6 => {
+                *next.entry(key.clone()).or_insert(0) += operand;
+                chest.merge(&key, Value::Integer(operand))
+            }
+            _ => chest.flush(),
+        };
+        if result.is_err() {
+            failed = Some(next);
+            break;
+        }
+        model = next;
+        if op == 7 {
+            flushed = model.clone();
+        }
+    }
+    // Flushes on drop unless the file system already crashed
+    drop(chest);
+    fs.power_loss();
+
+    let chest = open();
+    let recovered = read_integers(&chest);
+    let recovered_ok = if wal {
+        recovered == model || failed.as_ref() == Some(&recovered)
+    } else {
+        recovered == flushed || recovered == model
+    };
+    assert!(
+        recovered_ok,
+        "seed {seed}: recovered {recovered:?}, flushed {flushed:?}, model {model:?}"
+    );
 }
+
 #[test]
-fn test_overwrite_on_merge() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
-        chest_dir.to_str().unwrap(),
-        1,
-        1,
-        Box::new(BloomFilter::default()),
-    )
-    .unwrap();
-    chest
-        .set("foo", TimeStampedValue::new(Value::Integer(1)))
-        .unwrap();
-    chest
-        .set("foo", TimeStampedValue::new(Value::Integer(6)))
-        .unwrap();
-    assert_eq!(chest.sstables.len(), 1);
-    assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(6));
-    chest
-        .set("foo", TimeStampedValue::new(Value::Integer(4)))
-        .unwrap();
-    assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(4));
+fn crash_recovery_matches_model() {
+    for seed in 0..200 {
+        check_crash_recovery(seed, false);
+    }
 }
+
 #[test]
-fn merging_delete_old_sstables() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
+fn crash_recovery_keeps_logged_writes() {
+    for seed in 0..200 {
+        check_crash_recovery(seed, true);
+    }
+}
+
+const FIRST_KEY: &str = "1:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const SECOND_KEY: &str = "2:1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+fn open_encrypted(chest_dir: &Path, keys: &str) -> DungeonResult<Chest> {
+    open_encrypted_with(chest_dir, keys, false)
+}
+
+fn open_encrypted_with(
+    chest_dir: &Path,
+    keys: &str,
+    allow_unencrypted: bool,
+) -> DungeonResult<Chest> {
+    let key_file = chest_dir.with_extension("keys");
+    TEST_FS.write(&key_file, keys.as_bytes()).unwrap();
+    Chest::with_options(
         chest_dir.to_str().unwrap(),
-        1,
-        1,
+        ChestOptions {
+            flush_size: 2,
+            max_sstable_count: 2,
+            encryption: Some(KeySource::File(key_file)),
+            allow_unencrypted,
+            ..test_options()
+        },
         Box::new(BloomFilter::default()),
     )
-    .unwrap();
-    chest
-        .set("foo", TimeStampedValue::new(Value::Integer(1)))
-        .unwrap();
-    chest
-        .set("bar", TimeStampedValue::new(Value::Integer(2)))
-        .unwrap();
+}
+
+/// Whether `needle` shows up in any file of `chest_dir` as stored
+fn stored_files_contain(chest_dir: &Path, needle: &[u8]) -> bool {
+    TEST_FS.read_dir(chest_dir).unwrap().iter().any(|path| {
+        TEST_FS
+            .read(path)
+            .unwrap()
+            .windows(needle.len())
+            .any(|window| window == needle)
+    })
+}
+
+#[test]
+fn encrypted_chest_round_trip() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_encrypted(&chest_dir, FIRST_KEY).unwrap();
+    for i in 0..5 {
+        chest
+            .set(&format!("secret{i}"), Value::String("plaintext".to_owned()))
+            .unwrap();
+    }
+    drop(chest);
+    assert!(!stored_files_contain(&chest_dir, b"plaintext"));
+    assert!(!stored_files_contain(&chest_dir, b"secret"));
+
+    assert!(open_encrypted(&chest_dir, SECOND_KEY).is_err());
+    let chest = open_encrypted(&chest_dir, FIRST_KEY).unwrap();
+    assert_eq!(
+        chest.get("secret3").unwrap().unwrap().value,
+        Value::String("plaintext".to_owned())
+    );
+}
+
+#[test]
+fn encryption_keys_rotate_through_compaction() {
+    let chest_dir = get_test_dir();
+    // Starts unencrypted, the tables get encrypted as they are rewritten
+    let mut chest = open_chest(&chest_dir, 2, 2, ReadMode::Buffered);
+    chest.set("a", Value::Integer(1)).unwrap();
+    chest.set("b", Value::Integer(2)).unwrap();
+    drop(chest);
+
+    assert!(open_encrypted(&chest_dir, FIRST_KEY).is_err());
+    let mut chest = open_encrypted_with(&chest_dir, FIRST_KEY, true).unwrap();
+    assert_eq!(chest.get("a").unwrap().unwrap().value, Value::Integer(1));
+    chest.set("c", Value::Integer(3)).unwrap();
+    drop(chest);
+
+    let keys = format!("{SECOND_KEY}\n{FIRST_KEY}");
+    let mut chest = open_encrypted_with(&chest_dir, &keys, true).unwrap();
+    for i in 0..8 {
+        chest.set(&format!("key{i}"), Value::Integer(i)).unwrap();
+    }
+    for (key, value) in [("a", 1), ("b", 2), ("c", 3), ("key7", 7)] {
+        assert_eq!(
+            chest.get(key).unwrap().unwrap().value,
+            Value::Integer(value)
+        );
+    }
+    // Rewrites whatever compaction left unencrypted or under the old key
+    chest.compact_range("", "~").unwrap();
     drop(chest);
-    let chest = Chest::new(
+    // Every table was merged into ones written with the new key
+    let chest = open_encrypted(&chest_dir, SECOND_KEY).unwrap();
+    assert_eq!(chest.get("a").unwrap().unwrap().value, Value::Integer(1));
+}
+
+#[test]
+fn compaction_stops_at_unreadable_records() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_encrypted(&chest_dir, FIRST_KEY).unwrap();
+    for key in ["a", "b", "c", "d"] {
+        chest.set(key, Value::Integer(1)).unwrap();
+    }
+    assert_eq!(chest.sstables.len(), 2);
+    let oldest = chest.sstables.iter().last().unwrap().0.get_data_file_path();
+    let mut data = TEST_FS.read(&oldest).unwrap();
+    *data.last_mut().unwrap() ^= 1;
+    TEST_FS.write(&oldest, &data).unwrap();
+
+    assert!(chest.compact_range("", "~").is_err());
+    assert_eq!(chest.sstables.len(), 2);
+    assert!(chest
+        .sstables
+        .iter()
+        .all(|sstable| TEST_FS.is_file(&sstable.0.get_data_file_path())));
+    assert_eq!(chest.get("c").unwrap().unwrap().value, Value::Integer(1));
+    assert!(chest.get("a").is_err());
+}
+
+fn open_with_value_log(chest_dir: &Path, max_sstable_count: usize, read_mode: ReadMode) -> Chest {
+    Chest::with_options(
         chest_dir.to_str().unwrap(),
-        1,
-        1,
+        ChestOptions {
+            flush_size: 2,
+            max_sstable_count,
+            read_mode,
+            value_log_threshold: Some(64),
+            ..test_options()
+        },
         Box::new(BloomFilter::default()),
     )
-    .unwrap();
-    assert_eq!(chest.sstables.len(), 1);
-    assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(1));
-    assert_eq!(chest.get("bar").unwrap().unwrap().value, Value::Integer(2));
+    .unwrap()
+}
+
+fn large_value(fill: char) -> Value {
+    Value::String(fill.to_string().repeat(200))
+}
+
+/// Whether `needle` shows up in any file of `chest_dir` with the `extension`
+fn files_contain(chest_dir: &Path, extension: &str, needle: &[u8]) -> bool {
+    TEST_FS
+        .read_dir(chest_dir)
+        .unwrap()
+        .iter()
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some(extension))
+        .any(|path| {
+            TEST_FS
+                .read(path)
+                .unwrap()
+                .windows(needle.len())
+                .any(|window| window == needle)
+        })
 }
 
 #[test]
-fn test_delete_value() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
+fn large_values_live_in_value_logs() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_with_value_log(&chest_dir, 2, read_mode);
+        for i in 0..6 {
+            chest.set(&format!("large{i}"), large_value('x')).unwrap();
+            chest.set(&format!("small{i}"), Value::Integer(i)).unwrap();
+        }
+        chest
+            .merge("large0", Value::String("!".to_owned()))
+            .unwrap();
+        chest
+            .merge("large0", Value::String("?".to_owned()))
+            .unwrap();
+        let xs = "x".repeat(200);
+        assert!(files_contain(&chest_dir, "vlog", xs.as_bytes()));
+        assert!(!files_contain(&chest_dir, "chest", xs.as_bytes()));
+        drop(chest);
+
+        let chest = open_with_value_log(&chest_dir, 2, read_mode);
+        assert_eq!(
+            chest.get("large0").unwrap().unwrap().value,
+            Value::String(format!("{xs}!?"))
+        );
+        assert_eq!(
+            chest.get("large5").unwrap().unwrap().value,
+            large_value('x')
+        );
+        assert_eq!(
+            chest.get("small5").unwrap().unwrap().value,
+            Value::Integer(5)
+        );
+        assert_eq!(chest.count().unwrap(), 12);
+    }
+}
+
+#[test]
+fn backup_follows_collected_value_logs() {
+    let chest_dir = get_test_dir();
+    let backup_dir = get_test_dir();
+    let backup_path = backup_dir.to_str().unwrap();
+    let mut chest = open_with_value_log(&chest_dir, 1, ReadMode::Buffered);
+    for fill in ['a', 'b'] {
+        for i in 0..2 {
+            chest.set(&format!("key{i}"), large_value(fill)).unwrap();
+        }
+    }
+    chest.backup(backup_path).unwrap();
+    // The table keeps its name but points at a new log
+    let collected = chest.collect_value_logs(0.0).unwrap();
+    assert!(!collected.rewritten_tables.is_empty());
+    let report = chest.backup(backup_path).unwrap();
+    assert_eq!(report.copied_files, 3);
+    drop(chest);
+
+    let backup = open_with_value_log(&backup_dir, 1, ReadMode::Buffered);
+    for i in 0..2 {
+        assert_eq!(
+            backup.get(&format!("key{i}")).unwrap().unwrap().value,
+            large_value('b')
+        );
+    }
+}
+
+#[test]
+fn value_log_collection_reclaims_space() {
+    let chest_dir = get_test_dir();
+    // Every flush is merged into the single table, dropping the overwritten values
+    let mut chest = open_with_value_log(&chest_dir, 1, ReadMode::Buffered);
+    for fill in ['a', 'b', 'c', 'd'] {
+        for i in 0..4 {
+            chest.set(&format!("key{i}"), large_value(fill)).unwrap();
+        }
+    }
+    chest.set("small", Value::Integer(1)).unwrap();
+    chest.flush().unwrap();
+    let logs_before = value_log::log_names(TEST_FS.deref(), &chest_dir).unwrap();
+
+    let report = chest.collect_value_logs(0.5).unwrap();
+    assert!(report.reclaimed_bytes > 0);
+    assert!(!report.removed_logs.is_empty());
+    let logs_after = value_log::log_names(TEST_FS.deref(), &chest_dir).unwrap();
+    assert!(logs_after.len() < logs_before.len());
+    assert!(!files_contain(
+        &chest_dir,
+        "vlog",
+        "a".repeat(200).as_bytes()
+    ));
+    for i in 0..4 {
+        assert_eq!(
+            chest.get(&format!("key{i}")).unwrap().unwrap().value,
+            large_value('d')
+        );
+    }
+    // Nothing left to collect
+    let report = chest.collect_value_logs(0.5).unwrap();
+    assert!(report.removed_logs.is_empty() && report.rewritten_tables.is_empty());
+
+    let backup_dir = get_test_dir();
+    chest.backup(backup_dir.to_str().unwrap()).unwrap();
+    drop(chest);
+    let backup = open_with_value_log(&backup_dir, 1, ReadMode::Buffered);
+    assert_eq!(backup.get("key3").unwrap().unwrap().value, large_value('d'));
+    let chest = open_with_value_log(&chest_dir, 1, ReadMode::Buffered);
+    assert_eq!(chest.get("key0").unwrap().unwrap().value, large_value('d'));
+}
+
+fn open_indexed(chest_dir: &Path, flush_size: usize) -> Chest {
+    Chest::with_options(
         chest_dir.to_str().unwrap(),
-        4,
-        1,
+        ChestOptions {
+            flush_size,
+            max_sstable_count: 2,
+            indexes: vec![IndexDefinition::field("by_user", "session:", "user")],
+            ..test_options()
+        },
         Box::new(BloomFilter::default()),
     )
-    .unwrap();
+    .unwrap()
+}
+
+fn session(user: &str) -> Value {
+    Value::Map(BTreeMap::from([(
+        "user".to_owned(),
+        Value::String(user.to_owned()),
+    )]))
+}
+
+fn sessions_of(chest: &Chest, user: &str) -> Vec<String> {
     chest
-        .set("count", TimeStampedValue::new(Value::Integer(0)))
-        .unwrap();
+        .index_scan("by_user", &Value::String(user.to_owned()))
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect()
+}
+
+#[test]
+fn secondary_index_follows_writes() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_indexed(&chest_dir, 3);
+    chest.set("session:1", session("alice")).unwrap();
+    chest.set("session:2", session("bob")).unwrap();
+    chest.set("session:3", session("alice")).unwrap();
+    chest.set("other", session("alice")).unwrap();
+    assert_eq!(sessions_of(&chest, "alice"), ["session:1", "session:3"]);
+
+    chest.set("session:1", session("bob")).unwrap();
+    chest.delete("session:3").unwrap();
+    let moved = Value::Map(BTreeMap::from([(
+        "user".to_owned(),
+        Value::String("carol".to_owned()),
+    )]));
+    chest.merge("session:2", moved).unwrap();
+    assert!(sessions_of(&chest, "alice").is_empty());
+    assert_eq!(sessions_of(&chest, "bob"), ["session:1"]);
+    assert_eq!(sessions_of(&chest, "carol"), ["session:2"]);
+
+    // Entries never show up as keys
+    assert_eq!(chest.count().unwrap(), 3);
+    assert!(chest
+        .scan()
+        .all(|entry| !entry.unwrap().0.contains("index")));
+    let reserved = format!("{}by_user", secondary_index::INDEX_KEY_PREFIX);
+    assert!(chest.set(&reserved, Value::Null).is_err());
+    drop(chest);
+
+    let chest = open_indexed(&chest_dir, 3);
+    assert_eq!(sessions_of(&chest, "bob"), ["session:1"]);
+    assert!(chest.index_scan("missing", &Value::Null).is_err());
+}
+
+#[test]
+fn ingest_maintains_secondary_indexes() {
+    let chest_dir = get_test_dir();
+    let external_dir = get_test_dir();
+    let mut chest = open_indexed(&chest_dir, 16);
+    chest.set("session:1", session("bob")).unwrap();
+    chest.flush().unwrap();
+    let mut writer = create_writer(&external_dir, "sessions");
+    writer.put("session:1", session("carol")).unwrap();
+    writer.put("session:2", session("carol")).unwrap();
+    let external = writer.finish().unwrap();
+    chest.ingest_external(&[&external]).unwrap();
+    assert_eq!(sessions_of(&chest, "carol"), ["session:1", "session:2"]);
+    assert!(sessions_of(&chest, "bob").is_empty());
+    drop(chest);
+
+    let chest = open_indexed(&chest_dir, 16);
+    assert_eq!(sessions_of(&chest, "carol"), ["session:1", "session:2"]);
+}
+
+#[test]
+fn import_maintains_secondary_indexes() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_indexed(&chest_dir, 16);
+    chest.set("session:1", session("bob")).unwrap();
+    let dump = concat!(
+        "{\"key\":\"session:1\",\"value\":{\"Map\":{\"user\":{\"String\":\"alice\"}}}}\n",
+        "{\"key\":\"session:2\",\"value\":{\"Map\":{\"user\":{\"String\":\"alice\"}}}}\n",
+    );
     chest
-        .set("count", TimeStampedValue::new(Value::Integer(1)))
+        .import(dump.as_bytes(), DumpFormat::JsonLines)
         .unwrap();
+    assert_eq!(sessions_of(&chest, "alice"), ["session:1", "session:2"]);
+    assert!(sessions_of(&chest, "bob").is_empty());
+    drop(chest);
+
+    let chest = open_indexed(&chest_dir, 16);
+    assert_eq!(sessions_of(&chest, "alice"), ["session:1", "session:2"]);
+}
+
+#[test]
+fn secondary_index_builds_over_existing_data() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 64, 4, ReadMode::Buffered);
+    for i in 0..600 {
+        let user = if i % 3 == 0 { "alice" } else { "bob" };
+        chest
+            .set(&format!("session:{i:03}"), session(user))
+            .unwrap();
+    }
+    drop(chest);
+
+    let mut chest = open_indexed(&chest_dir, 64);
+    assert!(chest.index_scan("by_user", &Value::Null).is_err());
+    // Written while building, behind and ahead of the build
+    chest.set("session:000", session("carol")).unwrap();
+    chest.set("session:599", session("carol")).unwrap();
+    assert!(chest.build_indexes(usize::MAX).unwrap());
+    assert_eq!(sessions_of(&chest, "alice").len(), 199);
+    assert_eq!(sessions_of(&chest, "carol"), ["session:000", "session:599"]);
+    drop(chest);
+
+    // Ready right away once built, and dropped when not declared anymore
+    let chest = open_indexed(&chest_dir, 64);
+    assert_eq!(sessions_of(&chest, "bob").len(), 399);
+    drop(chest);
+    let chest = open_chest(&chest_dir, 64, 4, ReadMode::Buffered);
+    let prefix = secondary_index::INDEX_KEY_PREFIX.to_owned();
+    let mut entries = chest.keys_in(prefix.clone()..);
+    assert!(entries.all(|key| !key.starts_with(&prefix) || chest.get(key).unwrap().is_none()));
+}
+
+#[test]
+fn subscribers_receive_committed_writes() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 4, 4, ReadMode::Buffered);
+    chest.set("user:1", Value::Integer(1)).unwrap();
+    let events = chest.subscribe("user:");
+    chest.set("user:1", Value::Integer(2)).unwrap();
+    chest.set("other", Value::Integer(3)).unwrap();
+    chest.merge("user:1", Value::Integer(5)).unwrap();
+    let mut batch = WriteBatch::new();
+    batch
+        .delete("user:1")
+        .set("user:2", Value::Integer(1))
+        .merge("user:2", Value::Integer(4));
+    chest.write_batch(batch).unwrap();
+    assert_eq!(chest.sequence(), 7);
+
+    let received: Vec<ChangeEvent> = events.try_iter().collect();
+    let summary: Vec<_> = received
+        .iter()
+        .map(|event| {
+            (
+                event.sequence,
+                event.key.as_str(),
+                event.operation,
+                event.old.clone(),
+                event.new.clone(),
+            )
+        })
+        .collect();
     assert_eq!(
-        chest.get("count").unwrap().unwrap().value,
-        Value::Integer(1)
+        summary,
+        [
+            (
+                2,
+                "user:1",
+                Operation::Set,
+                Some(Value::Integer(1)),
+                Some(Value::Integer(2))
+            ),
+            (
+                4,
+                "user:1",
+                Operation::Merge,
+                Some(Value::Integer(2)),
+                Some(Value::Integer(7))
+            ),
+            (
+                5,
+                "user:1",
+                Operation::Delete,
+                Some(Value::Integer(7)),
+                None
+            ),
+            (6, "user:2", Operation::Set, None, Some(Value::Integer(1))),
+            (
+                7,
+                "user:2",
+                Operation::Merge,
+                Some(Value::Integer(1)),
+                Some(Value::Integer(5))
+            ),
+        ]
     );
-    chest.delete("count").unwrap();
-    assert_eq!(chest.get("count").unwrap(), None);
+    assert!(received
+        .windows(2)
+        .all(|pair| pair[0].timestamp < pair[1].timestamp));
+
+    // Nothing is sent once the receiver is gone, and a rejected batch sends nothing
+    drop(events);
+    chest.set("user:3", Value::Integer(1)).unwrap();
+    assert!(chest.subscribers.is_empty());
+    let events = chest.subscribe("");
+    let mut batch = WriteBatch::new();
+    let reserved = format!("{}by_user", secondary_index::INDEX_KEY_PREFIX);
+    batch.set("user:4", Value::Null).set(&reserved, Value::Null);
+    assert!(chest.write_batch(batch).is_err());
+    assert!(chest.get("user:4").unwrap().is_none());
+    assert!(events.try_recv().is_err());
 }
 
 #[test]
-fn test_delete_from_sstable() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
+fn write_ahead_log_recovers_unflushed_writes() {
+    let fs = FaultyFileSystem::new(7);
+    let options = ChestOptions {
+        flush_size: 1024,
+        file_system: Arc::new(fs.clone()),
+        wal: true,
+        wal_retention: 1,
+        ..Default::default()
+    };
+    let open = || {
+        Chest::with_options("/chest", options.clone(), Box::new(BloomFilter::default())).unwrap()
+    };
+    let mut chest = open();
+    chest.set("a", Value::Integer(1)).unwrap();
+    chest.set("b", Value::Integer(2)).unwrap();
+    chest.merge("a", Value::Integer(3)).unwrap();
+    // Nothing gets flushed on drop
+    fs.crash_after(0);
+    drop(chest);
+    fs.power_loss();
+
+    let mut chest = open();
+    assert_eq!(chest.sequence(), 3);
+    assert_eq!(
+        read_integers(&chest),
+        BTreeMap::from([("a".to_owned(), 4), ("b".to_owned(), 2)])
+    );
+    let events = chest.subscribe_from("a", 2).unwrap();
+    chest.set("a", Value::Integer(5)).unwrap();
+    let received: Vec<_> = events
+        .try_iter()
+        .map(|event| (event.sequence, event.new))
+        .collect();
+    assert_eq!(
+        received,
+        [(3, Some(Value::Integer(4))), (4, Some(Value::Integer(5)))]
+    );
+
+    // Flushed segments past the retention are gone
+    chest.flush().unwrap();
+    chest.set("b", Value::Integer(6)).unwrap();
+    chest.flush().unwrap();
+    assert!(chest.subscribe_from("", 4).is_err());
+    assert_eq!(chest.subscribe_from("", 5).unwrap().try_iter().count(), 1);
+    drop(chest);
+    let chest = open();
+    assert_eq!(chest.sequence(), 5);
+    assert_eq!(read_integers(&chest)["b"], 6);
+}
+
+fn open_compacting(chest_dir: &Path, background_compaction: bool) -> Chest {
+    Chest::with_options(
+        chest_dir.to_str().unwrap(),
+        ChestOptions {
+            flush_size: 8,
+            max_sstable_count: 2,
+            background_compaction,
+            compaction_rate: Some(1 << 20),
+            ..test_options()
+        },
+        Box::new(BloomFilter::default()),
+    )
+    .unwrap()
+}
+
+#[test]
+fn background_compaction_keeps_reads_consistent() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_compacting(&chest_dir, true);
+    let mut model = BTreeMap::new();
+    let mut rng = Rng::new(3);
+    for i in 0..400 {
+        let key = format!("key{}", rng.below(40));
+        if rng.below(4) == 0 {
+            chest.delete(&key).unwrap();
+            model.remove(&key);
+        } else {
+            chest.set(&key, Value::Integer(i)).unwrap();
+            model.insert(key, i);
+        }
+        if i % 50 == 0 {
+            assert_eq!(read_integers(&chest), model);
+        }
+    }
+    assert_eq!(read_integers(&chest), model);
+    assert!(chest.stats().compactions > 0);
+    drop(chest);
+
+    let chest = open_compacting(&chest_dir, true);
+    assert_eq!(read_integers(&chest), model);
+    let names: Vec<String> = chest
+        .stats()
+        .sstables
+        .into_iter()
+        .map(|sstable| sstable.name)
+        .collect();
+    let data_files = TEST_FS
+        .read_dir(&chest_dir)
+        .unwrap()
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "chest"))
+        .count();
+    assert_eq!(data_files, names.len(), "merged tables are removed");
+}
+
+#[test]
+fn paused_compaction_and_compact_range() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_compacting(&chest_dir, false);
+    chest.set("a", Value::Integer(0)).unwrap();
+    chest.flush().unwrap();
+    chest.pause_compaction();
+    for i in 1..5 {
+        chest.set(&format!("key{i}"), Value::Integer(i)).unwrap();
+        chest.delete("a").unwrap();
+        chest.flush().unwrap();
+    }
+    assert_eq!(chest.stats().sstables.len(), 5);
+    assert_eq!(chest.stats().compactions, 0);
+
+    chest.resume_compaction();
+    chest.set("key5", Value::Integer(5)).unwrap();
+    chest.flush().unwrap();
+    assert_eq!(chest.stats().sstables.len(), 2);
+
+    // Only the newest table holds key5, and nothing older is merged with it
+    chest.compact_range("key5", "key6").unwrap();
+    assert_eq!(chest.stats().sstables.len(), 2);
+    // The oldest table holds a, so everything is merged and its tombstone dropped
+    chest.compact_range("a", "b").unwrap();
+    let stats = chest.stats();
+    assert_eq!(stats.sstables.len(), 1);
+    assert_eq!(stats.sstables[0].keys, 5);
+    assert!(chest.get("a").unwrap().is_none());
+    assert_eq!(chest.get("key3").unwrap().unwrap().value, Value::Integer(3));
+}
+
+#[test]
+fn compact_range_runs_while_paused() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_compacting(&chest_dir, false);
+    chest.pause_compaction();
+    for i in 0..4 {
+        chest.set(&format!("key{i}"), Value::Integer(i)).unwrap();
+        chest.flush().unwrap();
+    }
+    assert_eq!(chest.stats().sstables.len(), 4);
+    chest.compact_range("key0", "key9").unwrap();
+    assert_eq!(chest.stats().sstables.len(), 1);
+    assert_eq!(chest.stats().compactions, 1);
+
+    // Flushes still leave the tables alone
+    chest.set("key4", Value::Integer(4)).unwrap();
+    chest.flush().unwrap();
+    chest.set("key5", Value::Integer(5)).unwrap();
+    chest.flush().unwrap();
+    assert_eq!(chest.stats().sstables.len(), 3);
+    assert_eq!(read_integers(&chest).len(), 6);
+}
+
+#[test]
+fn disk_quota_degrades_chest() {
+    let chest_dir = get_test_dir();
+    let quota = 4096;
+    let mut chest = Chest::with_options(
         chest_dir.to_str().unwrap(),
-        1,
-        1,
+        ChestOptions {
+            flush_size: 8,
+            disk_quota: Some(quota),
+            ..test_options()
+        },
         Box::new(BloomFilter::default()),
     )
     .unwrap();
-    chest
-        .set("count", TimeStampedValue::new(Value::Integer(0)))
-        .unwrap();
-    assert_eq!(
-        chest.get("count").unwrap().unwrap().value,
-        Value::Integer(0)
-    );
-    chest.delete("count").unwrap();
-    assert_eq!(chest.get("count").unwrap(), None);
+    let mut written = 0;
+    let err = loop {
+        match chest.set(&format!("key{written:04}"), large_value('q')) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.kind, ErrorKind::QuotaExceeded);
+    assert!(written > 8);
+    assert!(chest.is_degraded());
+    assert!(chest.disk_usage().unwrap() <= quota);
+    assert_eq!(chest.count().unwrap(), written);
+    assert!(chest.delete("key0000").is_err());
+
+    // Writes go through again once they fit
+    chest.options.disk_quota = None;
+    chest.delete("key0000").unwrap();
+    assert!(!chest.is_degraded());
 }
 
 #[test]
-fn test_clean_sstable() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
+fn import_and_ingest_respect_disk_quota() {
+    let chest_dir = get_test_dir();
+    let external_dir = get_test_dir();
+    let mut chest = Chest::with_options(
         chest_dir.to_str().unwrap(),
-        1,
-        1,
+        ChestOptions {
+            disk_quota: Some(4096),
+            ..test_options()
+        },
         Box::new(BloomFilter::default()),
     )
     .unwrap();
-    chest
-        .set("foo", TimeStampedValue::new(Value::Integer(0)))
-        .unwrap();
-    chest
-        .set("foo", TimeStampedValue::new(Value::Integer(1)))
-        .unwrap();
-    assert_eq!(chest.get("foo").unwrap().unwrap().value, Value::Integer(1));
-    chest
-        .set("bar", TimeStampedValue::new(Value::Float(1.5)))
-        .unwrap();
-    chest
-        .set("bar", TimeStampedValue::new(Value::Float(3.5)))
-        .unwrap();
-    assert_eq!(chest.sstables.len(), 1);
-    let expected_size = to_vec(&TimeStampedValue::new(Value::Integer(1)))
+    let mut dump = Vec::new();
+    let mut writer = create_writer(&external_dir, "large");
+    for i in 0..32 {
+        let key = format!("key{i:02}");
+        let entry = DumpEntry {
+            key: key.clone(),
+            value: large_value('q'),
+        };
+        DumpFormat::JsonLines
+            .write_entry(&mut dump, &entry)
+            .unwrap();
+        writer.put(&key, large_value('q')).unwrap();
+    }
+    let table = writer.finish().unwrap();
+
+    let err = chest
+        .import(dump.as_slice(), DumpFormat::JsonLines)
+        .unwrap_err();
+    assert_eq!(err.kind, ErrorKind::QuotaExceeded);
+    let err = chest.ingest_external(&[&table]).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::QuotaExceeded);
+    assert!(TEST_FS.is_file(&table));
+    assert!(chest.sstables.is_empty());
+    assert!(!chest.is_degraded());
+
+    chest.options.disk_quota = None;
+    chest.ingest_external(&[&table]).unwrap();
+    assert_eq!(chest.count().unwrap(), 32);
+}
+
+#[test]
+fn full_disk_keeps_memtable_and_cleans_up() {
+    let fs = FaultyFileSystem::new(11);
+    let options = ChestOptions {
+        flush_size: 4,
+        file_system: Arc::new(fs.clone()),
+        ..Default::default()
+    };
+    let open = || {
+        Chest::with_options("/chest", options.clone(), Box::new(BloomFilter::default())).unwrap()
+    };
+    let mut chest = open();
+    for i in 0..3 {
+        chest.set(&format!("key{i}"), Value::Integer(i)).unwrap();
+    }
+    fs.fill_disk(true);
+    assert!(chest.set("key3", Value::Integer(3)).is_err());
+    assert!(chest.is_degraded());
+    let err = chest.set("key4", Value::Integer(4)).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::Degraded);
+    // The failed flushes left nothing behind, and nothing was lost
+    assert!(fs
+        .read_dir(Path::new("/chest"))
         .unwrap()
-        .len()
-        + to_vec(&TimeStampedValue::new(Value::Float(3.5)))
-            .unwrap()
-            .len();
-    let table = &chest.sstables.iter().next().unwrap().0;
-    let data_file_path = table.get_data_file_path();
-    let metadata = std::fs::metadata(data_file_path).unwrap();
-    let file_size = metadata.size();
-    assert_eq!(expected_size as u64, file_size);
+        .iter()
+        .all(|path| {
+            path.extension()
+                .is_none_or(|ext| ext != "chest" && ext != "index-tmp")
+        }));
+    assert_eq!(read_integers(&chest).len(), 4);
+
+    fs.fill_disk(false);
+    chest.set("key4", Value::Integer(4)).unwrap();
+    assert!(!chest.is_degraded());
+    drop(chest);
+    assert_eq!(read_integers(&open()).len(), 5);
 }
+
 #[test]
-fn keys_are_sorted() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
+fn writes_respect_size_limits_and_key_charset() {
+    let chest_dir = get_test_dir();
+    let mut chest = Chest::with_options(
         chest_dir.to_str().unwrap(),
-        1,
-        1,
+        ChestOptions {
+            max_key_size: 8,
+            max_value_size: 16,
+            key_charset: KeyCharset::AsciiAlphanumeric,
+            ..test_options()
+        },
         Box::new(BloomFilter::default()),
     )
     .unwrap();
-    chest
-        .set("grape", TimeStampedValue::new(Value::Integer(0)))
-        .unwrap();
-    chest
-        .set("apple", TimeStampedValue::new(Value::Integer(1)))
-        .unwrap();
-    chest
-        .set("peach", TimeStampedValue::new(Value::Integer(2)))
-        .unwrap();
-    chest
-        .set("orange", TimeStampedValue::new(Value::Integer(3)))
+    let kind = |result: DungeonResult<()>| result.unwrap_err().kind;
+    assert_eq!(
+        kind(chest.set("muchtoolong", Value::Null)),
+        ErrorKind::KeyTooLarge
+    );
+    assert_eq!(kind(chest.delete("")), ErrorKind::InvalidKey);
+    assert_eq!(kind(chest.set("a:b", Value::Null)), ErrorKind::InvalidKey);
+    let large = Value::String("x".repeat(16));
+    assert_eq!(
+        kind(chest.set("a", large.clone())),
+        ErrorKind::ValueTooLarge
+    );
+    assert_eq!(kind(chest.merge("a", large)), ErrorKind::ValueTooLarge);
+    // A batch with a single invalid write writes nothing
+    let mut batch = WriteBatch::new();
+    batch
+        .set("ok", Value::Integer(1))
+        .set("not ok", Value::Integer(2));
+    assert_eq!(kind(chest.write_batch(batch)), ErrorKind::InvalidKey);
+    assert_eq!(chest.count().unwrap(), 0);
+
+    chest.set("a1", Value::String("x".repeat(4))).unwrap();
+    chest.delete("a1").unwrap();
+
+    let mut dump = Vec::new();
+    let entry = DumpEntry {
+        key: "longerthan8".to_owned(),
+        value: Value::Integer(1),
+    };
+    DumpFormat::JsonLines
+        .write_entry(&mut dump, &entry)
         .unwrap();
-    let mut table = chest.sstables.clone().into_iter().next().unwrap().0;
-    assert_eq!(table.index.next().unwrap().0, "apple".to_owned());
-    assert_eq!(table.index.next().unwrap().0, "grape".to_owned());
-    assert_eq!(table.index.next().unwrap().0, "orange".to_owned());
-    assert_eq!(table.index.next().unwrap().0, "peach".to_owned());
+    let err = chest
+        .import(dump.as_slice(), DumpFormat::JsonLines)
+        .unwrap_err();
+    assert_eq!(err.kind, ErrorKind::KeyTooLarge);
+}
+
+/// Keys `iter` goes through calling `step` until it runs out
+fn walk<'a>(
+    iter: &mut ChestIterator<'a>,
+    step: fn(&mut ChestIterator<'a>) -> DungeonResult<bool>,
+) -> Vec<String> {
+    let mut keys = Vec::new();
+    while step(iter).unwrap() {
+        keys.push(iter.key().unwrap().to_owned());
+    }
+    keys
 }
 
 #[test]
-fn dead_value_cancel() {
-    let chest_dir = get_test_tempdir();
-    let mut chest = Chest::new(
+fn iterator_walks_both_ways() {
+    for read_mode in READ_MODES {
+        let chest_dir = get_test_dir();
+        let mut chest = open_chest(&chest_dir, 3, 8, read_mode);
+        for key in ["b", "d", "f", "h", "j", "l"] {
+            chest.set(key, Value::String(key.to_owned())).unwrap();
+        }
+        // Spread over sstables and the memtable, with deletes hiding older records
+        chest.delete("d").unwrap();
+        chest.set("a", Value::Null).unwrap();
+        chest.delete("l").unwrap();
+        chest.set("f", Value::Integer(6)).unwrap();
+
+        let mut iter = chest.iter();
+        assert!(!iter.valid());
+        assert_eq!(
+            walk(&mut iter, ChestIterator::next),
+            ["a", "b", "f", "h", "j"]
+        );
+        assert!(!iter.valid());
+        assert_eq!(
+            walk(&mut iter, ChestIterator::prev),
+            ["j", "h", "f", "b", "a"]
+        );
+
+        assert!(iter.seek("c").unwrap());
+        assert_eq!(iter.key(), Some("f"));
+        assert_eq!(iter.value().unwrap().value, Value::Integer(6));
+        assert!(iter.prev().unwrap());
+        assert_eq!(iter.key(), Some("b"));
+        assert!(iter.seek_for_prev("i").unwrap());
+        assert_eq!(iter.key(), Some("h"));
+        assert!(iter.seek_for_prev("h").unwrap());
+        assert_eq!(iter.key(), Some("h"));
+        assert!(!iter.seek("k").unwrap());
+        assert!(!iter.seek_for_prev("0").unwrap());
+        assert!(iter.seek_to_last().unwrap());
+        assert_eq!(iter.key(), Some("j"));
+    }
+}
+
+#[test]
+fn iterator_reads_snapshots_and_skips_index_entries() {
+    let chest_dir = get_test_dir();
+    let mut chest = Chest::with_options(
         chest_dir.to_str().unwrap(),
-        1,
-        64,
+        ChestOptions {
+            flush_size: 2,
+            history: History::Versions(4),
+            indexes: vec![IndexDefinition::field("by_user", "session:", "user")],
+            ..test_options()
+        },
         Box::new(BloomFilter::default()),
     )
     .unwrap();
-    chest
-        .set("foo", TimeStampedValue::new(Value::Integer(0)))
-        .unwrap();
-    chest.delete("foo").unwrap();
+    chest.set("session:1", session("alice")).unwrap();
+    chest.set("session:2", session("bob")).unwrap();
+    let snapshot = chest.snapshot();
+    chest.delete("session:1").unwrap();
+    chest.set("session:3", session("carol")).unwrap();
+    chest.set("session:2", session("dave")).unwrap();
+
+    assert_eq!(
+        walk(&mut chest.iter(), ChestIterator::next),
+        ["session:2", "session:3"]
+    );
+    let mut iter = chest.iter_at(snapshot);
+    assert_eq!(
+        walk(&mut iter, ChestIterator::prev),
+        ["session:2", "session:1"]
+    );
+    assert!(iter.seek("session:2").unwrap());
+    assert_eq!(iter.value().unwrap().value, session("bob"));
+}
+
+#[test]
+fn delete_range_hides_keys_until_compacted() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_compacting(&chest_dir, false);
+    let mut model = BTreeMap::new();
+    for tenant in 1..=3 {
+        for i in 0..20 {
+            let key = format!("tenant:{tenant}:{i:02}");
+            chest.set(&key, Value::Integer(i)).unwrap();
+            model.insert(key, i);
+        }
+    }
+    chest.set("tenant:2:05", Value::Integer(50)).unwrap();
+    model.insert("tenant:2:05".to_owned(), 50);
+    chest.delete_range("tenant:2:", "tenant:2;").unwrap();
+    model.retain(|key, _| !key.starts_with("tenant:2:"));
+    assert_eq!(read_integers(&chest), model);
+    assert!(chest.get("tenant:2:05").unwrap().is_none());
+    let mut iter = chest.iter();
+    assert!(iter.seek("tenant:2:").unwrap());
+    assert_eq!(iter.key(), Some("tenant:3:00"));
+
+    // Writes after the delete are visible, merges start over
+    chest.set("tenant:2:01", Value::Integer(1)).unwrap();
+    chest.merge("tenant:2:02", Value::Integer(7)).unwrap();
+    model.insert("tenant:2:01".to_owned(), 1);
+    model.insert("tenant:2:02".to_owned(), 7);
+    assert_eq!(read_integers(&chest), model);
+    let err = chest.delete_range("b", "a").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidKey);
+    drop(chest);
+
+    let mut chest = open_compacting(&chest_dir, false);
+    assert_eq!(read_integers(&chest), model);
+    chest.compact_range("", "~").unwrap();
+    assert_eq!(read_integers(&chest), model);
+    assert_eq!(chest.sstables.len(), 1);
+    let index = &chest.sstables.first().unwrap().0.index;
+    assert!(index.range_tombstones.is_empty());
+    assert_eq!(
+        index.table.len(),
+        model.len(),
+        "covered records are dropped"
+    );
+}
+
+#[test]
+fn ingest_rejects_tables_older_than_range_deletes() {
+    let chest_dir = get_test_dir();
+    let external_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 1024, 8, ReadMode::Buffered);
+    let mut stale = create_writer(&external_dir, "stale");
+    stale.put("m", Value::Integer(1)).unwrap();
+    let stale = stale.finish().unwrap();
+    chest.delete_range("a", "z").unwrap();
+    chest.flush().unwrap();
+
+    assert!(chest.ingest_external(&[&stale]).is_err());
+    let mut fresh = create_writer(&external_dir, "fresh");
+    fresh.put("m", Value::Integer(2)).unwrap();
+    let fresh = fresh.finish().unwrap();
+    chest.ingest_external(&[&fresh]).unwrap();
+    assert_eq!(chest.get("m").unwrap().unwrap().value, Value::Integer(2));
+}
+
+#[test]
+fn repair_quarantines_leftovers_of_merged_tables() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 2, 8, ReadMode::Buffered);
+    chest.set("a", Value::Integer(1)).unwrap();
+    chest.set("b", Value::Integer(1)).unwrap();
+    chest.delete("a").unwrap();
+    chest.set("c", Value::Integer(1)).unwrap();
+    let oldest = chest.sstables.iter().last().unwrap().0.clone();
+    let data = TEST_FS.read(&oldest.get_data_file_path()).unwrap();
+    chest.compact_range("", "~").unwrap();
+    assert!(chest.get("a").unwrap().is_none());
+    drop(chest);
+    // As if the merge was interrupted after removing the index of one of its inputs
+    TEST_FS.write(&oldest.get_data_file_path(), &data).unwrap();
+
+    let report = repair(chest_dir.to_str().unwrap(), &test_options()).unwrap();
+    assert!(report.rebuilt.is_empty());
+    assert_eq!(report.quarantined, [format!("{}.chest", oldest.file_name)]);
+    let chest = open_chest(&chest_dir, 2, 8, ReadMode::Buffered);
+    assert!(chest.get("a").unwrap().is_none());
+    assert_eq!(chest.get("b").unwrap().unwrap().value, Value::Integer(1));
+}
+
+#[test]
+fn repair_keeps_range_deletes() {
+    let chest_dir = get_test_dir();
+    let mut chest = open_chest(&chest_dir, 2, 8, ReadMode::Buffered);
+    chest.set("a1", Value::Integer(1)).unwrap();
+    chest.set("a2", Value::Integer(2)).unwrap();
+    chest.delete_range("a", "b").unwrap();
+    chest.set("z", Value::Integer(3)).unwrap();
     assert_eq!(chest.sstables.len(), 2);
-    let mut first = chest.sstables.pop_first().unwrap().0;
-    let mut second = chest.sstables.pop_first().unwrap().0;
-    assert_eq!(first.index.table.len(), 1);
-    assert_eq!(second.index.table.len(), 1);
-    let merged = first.merge(&mut second, "merged".to_owned()).unwrap();
-    assert_eq!(merged.index.table.len(), 0);
+    let newest = chest.sstables.first().unwrap().0.get_index_file_path();
+    drop(chest);
+    TEST_FS.write(&newest, b"not an index").unwrap();
+
+    let report = repair(chest_dir.to_str().unwrap(), &test_options()).unwrap();
+    assert_eq!(report.rebuilt.len(), 1);
+    let chest = open_chest(&chest_dir, 2, 8, ReadMode::Buffered);
+    assert!(chest.get("a1").unwrap().is_none());
+    assert!(chest.get("a2").unwrap().is_none());
+    assert_eq!(chest.get("z").unwrap().unwrap().value, Value::Integer(3));
+}
+
+#[test]
+fn repair_keeps_flushed_sequence() {
+    let fs = FaultyFileSystem::new(13);
+    let options = ChestOptions {
+        flush_size: 1024,
+        file_system: Arc::new(fs.clone()),
+        wal: true,
+        ..Default::default()
+    };
+    let open = || {
+        Chest::with_options("/chest", options.clone(), Box::new(BloomFilter::default())).unwrap()
+    };
+    let mut chest = open();
+    chest.set("a", Value::Integer(1)).unwrap();
+    chest.flush().unwrap();
+    chest.merge("a", Value::Integer(3)).unwrap();
+    drop(chest);
+    // Names are ticks of the clock, the table flushed on drop is the last one
+    let newest = fs
+        .read_dir(Path::new("/chest"))
+        .unwrap()
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "index"))
+        .max()
+        .unwrap();
+    fs.write(&newest, b"not an index").unwrap();
+
+    let report = repair("/chest", &options).unwrap();
+    assert_eq!(report.rebuilt.len(), 1);
+    // The merge is in the rebuilt table, replaying it from the log would apply it twice
+    let chest = open();
+    assert_eq!(chest.sequence(), 2);
+    assert_eq!(chest.get("a").unwrap().unwrap().value, Value::Integer(4));
+}
+
+#[test]
+fn delete_range_in_batches_history_and_indexes() {
+    let fs = FaultyFileSystem::new(11);
+    let options = ChestOptions {
+        flush_size: 1024,
+        file_system: Arc::new(fs.clone()),
+        wal: true,
+        history: History::Versions(4),
+        ..Default::default()
+    };
+    let open = || {
+        Chest::with_options("/chest", options.clone(), Box::new(BloomFilter::default())).unwrap()
+    };
+    let mut chest = open();
+    chest.set("a:1", Value::Integer(1)).unwrap();
+    chest.set("a:2", Value::Integer(2)).unwrap();
+    chest.flush().unwrap();
+    let before = chest.snapshot();
+    let events = chest.subscribe("a:");
+    let other = chest.subscribe("b");
+    let mut batch = WriteBatch::new();
+    batch
+        .set("a:3", Value::Integer(3))
+        .delete_range("a", "a;")
+        .merge("a:2", Value::Integer(5));
+    chest.write_batch(batch).unwrap();
+    let received: Vec<_> = events
+        .try_iter()
+        .map(|event| (event.key, event.operation, event.new, event.end))
+        .collect();
+    assert_eq!(
+        received,
+        [
+            (
+                "a:3".to_owned(),
+                Operation::Set,
+                Some(Value::Integer(3)),
+                None
+            ),
+            (
+                "a".to_owned(),
+                Operation::DeleteRange,
+                None,
+                Some("a;".to_owned())
+            ),
+            (
+                "a:2".to_owned(),
+                Operation::Merge,
+                Some(Value::Integer(5)),
+                None
+            ),
+        ]
+    );
+    assert_eq!(other.try_iter().count(), 0);
+
+    // The range delete is replayed from the log
+    fs.crash_after(0);
+    drop(chest);
+    fs.power_loss();
+    let chest = open();
+    assert_eq!(
+        read_integers(&chest),
+        BTreeMap::from([("a:2".to_owned(), 5)])
+    );
+    assert_eq!(
+        chest.get_at("a:1", before).unwrap().unwrap().value,
+        Value::Integer(1)
+    );
+    let versions: Vec<bool> = chest
+        .history("a:2")
+        .unwrap()
+        .iter()
+        .map(TimeStampedValue::is_tombstone)
+        .collect();
+    assert_eq!(versions, [false, true, false]);
+    drop(chest);
+
+    // Index entries of the covered keys are left behind, and skipped
+    let chest_dir = get_test_dir();
+    let mut chest = open_indexed(&chest_dir, 3);
+    chest.set("session:1", session("alice")).unwrap();
+    chest.set("session:2", session("alice")).unwrap();
+    chest.delete_range("session:", "session;").unwrap();
+    chest.set("session:1", session("bob")).unwrap();
+    assert!(sessions_of(&chest, "alice").is_empty());
+    assert_eq!(sessions_of(&chest, "bob"), ["session:1"]);
 }
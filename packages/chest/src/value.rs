@@ -1,31 +1,222 @@
-use std::time::UNIX_EPOCH;
+use std::{collections::BTreeMap, fmt::Display};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{secondary_index::is_index_key, value_log::ValuePointer};
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Value {
     Integer(i64),
     Float(f64),
     String(String),
     Boolean(bool),
-    Invalid,
+    Null,
+    Bytes(#[serde(with = "serde_bytes")] Vec<u8>),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+    /// Nanoseconds since the unix epoch
+    Timestamp(u128),
+}
+
+impl Value {
+    /// Applies a merge operand on top of this value. Numbers are added, strings, bytes and lists
+    /// are concatenated and maps are joined with the operand winning on repeated keys. Operands
+    /// of any other type just replace the value
+    pub fn merge(self, operand: Value) -> Value {
+        match (self, operand) {
+            (Value::Integer(v), Value::Integer(op)) => Value::Integer(v.wrapping_add(op)),
+            (Value::Float(v), Value::Float(op)) => Value::Float(v + op),
+            (Value::String(mut v), Value::String(op)) => {
+                v.push_str(&op);
+                Value::String(v)
+            }
+            (Value::Bytes(mut v), Value::Bytes(op)) => {
+                v.extend(op);
+                Value::Bytes(v)
+            }
+            (Value::List(mut v), Value::List(op)) => {
+                v.extend(op);
+                Value::List(v)
+            }
+            (Value::Map(mut v), Value::Map(op)) => {
+                v.extend(op);
+                Value::Map(v)
+            }
+            (_, op) => op,
+        }
+    }
+    fn merges_with(&self, operand: &Value) -> bool {
+        matches!(
+            (self, operand),
+            (Value::Integer(_), Value::Integer(_))
+                | (Value::Float(_), Value::Float(_))
+                | (Value::String(_), Value::String(_))
+                | (Value::Bytes(_), Value::Bytes(_))
+                | (Value::List(_), Value::List(_))
+                | (Value::Map(_), Value::Map(_))
+        )
+    }
+    /// Strings nested in lists and maps are quoted, so `["a, b"]` can't be mistaken for `["a", "b"]`
+    fn fmt_nested(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::String(v) => write!(f, "{v:?}"),
+            other => write!(f, "{other}"),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Integer(v) => write!(f, "{v}"),
+            Value::Float(v) => write!(f, "{v}"),
+            Value::String(v) => write!(f, "{v}"),
+            Value::Boolean(v) => write!(f, "{v}"),
+            Value::Null => write!(f, "null"),
+            Value::Bytes(v) => {
+                write!(f, "0x")?;
+                for byte in v {
+                    write!(f, "{byte:02x}")?;
+                }
+                Ok(())
+            }
+            Value::List(v) => {
+                write!(f, "[")?;
+                for (i, item) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    item.fmt_nested(f)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(v) => {
+                write!(f, "{{")?;
+                for (i, (key, item)) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{key:?}: ")?;
+                    item.fmt_nested(f)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Timestamp(v) => write!(f, "timestamp({v})"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordKind {
+    /// The record value replaces anything older
+    #[default]
+    Put,
+    /// The key was deleted, the record value is meaningless
+    Delete,
+    /// The record value is an operand for `Value::merge` over the older value of the key
+    Merge,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct TimeStampedValue {
     pub timestamp: u128,
     pub value: Value,
+    pub kind: RecordKind,
+    /// Where the value lives when it was moved to a value log, `value` is `Null` then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pointer: Option<ValuePointer>,
 }
 
 impl TimeStampedValue {
-    pub fn new(value: Value) -> Self {
-        let current_time = std::time::SystemTime::now();
-        let ellapsed = current_time.duration_since(UNIX_EPOCH).unwrap();
+    pub fn new(value: Value, timestamp: u128) -> Self {
+        Self {
+            timestamp,
+            value,
+            kind: RecordKind::Put,
+            pointer: None,
+        }
+    }
+    pub fn tombstone(timestamp: u128) -> Self {
+        Self {
+            timestamp,
+            value: Value::Null,
+            kind: RecordKind::Delete,
+            pointer: None,
+        }
+    }
+    pub fn merge_operand(operand: Value, timestamp: u128) -> Self {
         Self {
-            timestamp: ellapsed.as_nanos(),
+            timestamp,
+            value: operand,
+            kind: RecordKind::Merge,
+            pointer: None,
+        }
+    }
+    pub fn is_tombstone(&self) -> bool {
+        self.kind == RecordKind::Delete
+    }
+    /// Combines this record with an older record of the same key into a single one. The result
+    /// only stays a merge operand when both are operands of a type that can still be combined
+    /// with whatever comes before them. Values moved to a value log must be read back first
+    pub fn merge_onto(self, older: TimeStampedValue) -> TimeStampedValue {
+        if self.kind != RecordKind::Merge {
+            return self;
+        }
+        let (value, kind) = match older.kind {
+            RecordKind::Put => (older.value.merge(self.value), RecordKind::Put),
+            RecordKind::Delete => (self.value, RecordKind::Put),
+            RecordKind::Merge if older.value.merges_with(&self.value) => {
+                (older.value.merge(self.value), RecordKind::Merge)
+            }
+            RecordKind::Merge => (self.value, RecordKind::Put),
+        };
+        Self {
+            timestamp: self.timestamp,
             value,
+            kind,
+            pointer: None,
         }
     }
+    /// Value seen by readers once there is nothing older left to merge with
+    pub fn into_visible(self) -> Option<TimeStampedValue> {
+        match self.kind {
+            RecordKind::Delete => None,
+            RecordKind::Put => Some(self),
+            RecordKind::Merge => Some(Self {
+                kind: RecordKind::Put,
+                ..self
+            }),
+        }
+    }
+}
+
+/// Deletes every key in `start..end` written before `timestamp`, see `Chest::delete_range`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RangeTombstone {
+    pub start: String,
+    pub end: String,
+    pub timestamp: u128,
+}
+
+impl RangeTombstone {
+    /// Index entries are never covered, `Chest::index_scan` skips the ones left behind
+    pub fn covers(&self, key: &str) -> bool {
+        self.start.as_str() <= key && key < self.end.as_str() && !is_index_key(key)
+    }
+}
+
+/// Timestamp of the latest of `tombstones` covering `key`, 0 when none does. The records of `key`
+/// older than it are deleted
+pub(crate) fn deleted_at<'a>(
+    tombstones: impl IntoIterator<Item = &'a RangeTombstone>,
+    key: &str,
+) -> u128 {
+    tombstones
+        .into_iter()
+        .filter(|tombstone| tombstone.covers(key))
+        .map(|tombstone| tombstone.timestamp)
+        .max()
+        .unwrap_or(0)
 }
 
 impl Ord for TimeStampedValue {
@@ -35,7 +226,29 @@ impl Ord for TimeStampedValue {
 }
 impl PartialOrd for TimeStampedValue {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.timestamp.cmp(&other.timestamp))
+        Some(self.cmp(other))
     }
 }
 impl Eq for TimeStampedValue {}
+
+/// Sorts the versions of a key newest first, dropping copies of the same version
+pub(crate) fn sort_versions(versions: &mut Vec<TimeStampedValue>) {
+    versions.sort_by_key(|version| std::cmp::Reverse(version.timestamp));
+    versions.dedup_by_key(|version| version.timestamp);
+}
+
+/// Sorts the versions of a key newest first, applying every merge operand on top of the version
+/// before it
+pub(crate) fn resolve_versions(mut versions: Vec<TimeStampedValue>) -> Vec<TimeStampedValue> {
+    sort_versions(&mut versions);
+    let mut resolved: Vec<TimeStampedValue> = Vec::with_capacity(versions.len());
+    for version in versions.into_iter().rev() {
+        let version = match resolved.last() {
+            Some(older) => version.merge_onto(older.clone()),
+            None => version,
+        };
+        resolved.push(version);
+    }
+    resolved.reverse();
+    resolved
+}
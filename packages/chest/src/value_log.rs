@@ -0,0 +1,153 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use errors::{DungeonError, DungeonResult};
+use rmp_serde::{from_slice, to_vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file_system::{sync_parent, FileSystem},
+    value::{RecordKind, TimeStampedValue, Value},
+};
+
+pub const VALUE_LOG_EXTENSION: &str = "vlog";
+
+/// Where a value moved out of its sstable lives
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValuePointer {
+    /// Name of the value log, without its extension
+    pub log: String,
+    pub offset: u64,
+    pub length: usize,
+}
+
+/// What `Chest::collect_value_logs` reclaimed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValueLogReport {
+    /// Tables rewritten to move their values out of mostly dead logs
+    pub rewritten_tables: Vec<String>,
+    pub removed_logs: Vec<String>,
+    pub reclaimed_bytes: u64,
+}
+
+pub fn log_path(dir: &Path, log: &str) -> PathBuf {
+    dir.join(log).with_extension(VALUE_LOG_EXTENSION)
+}
+
+/// Reads the value `pointer` points at in the logs of `dir`
+pub fn read(fs: &dyn FileSystem, dir: &Path, pointer: &ValuePointer) -> DungeonResult<Value> {
+    let raw = fs
+        .read_at(&log_path(dir, &pointer.log), pointer.offset, pointer.length)
+        .map_err(|_| DungeonError::new("Could not read value log"))?;
+    from_slice(&raw).map_err(|_| DungeonError::new("Could not parse value log entry"))
+}
+
+/// Puts the value of `record` back in place when it was moved to a value log
+pub fn resolve(
+    fs: &dyn FileSystem,
+    dir: &Path,
+    mut record: TimeStampedValue,
+) -> DungeonResult<TimeStampedValue> {
+    if let Some(pointer) = record.pointer.take() {
+        record.value = read(fs, dir, &pointer)?;
+    }
+    Ok(record)
+}
+
+/// Names of the logs in `dir`
+pub fn log_names(fs: &dyn FileSystem, dir: &Path) -> DungeonResult<Vec<String>> {
+    let files = fs
+        .read_dir(dir)
+        .map_err(|_| DungeonError::new("Could not read files"))?;
+    Ok(files
+        .into_iter()
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some(VALUE_LOG_EXTENSION))
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_owned()))
+        .collect())
+}
+
+/// Adds up the bytes every log is referenced by in `references`, one map per sstable
+pub fn live_bytes<'a>(
+    references: impl IntoIterator<Item = &'a BTreeMap<String, u64>>,
+) -> BTreeMap<String, u64> {
+    let mut live = BTreeMap::new();
+    for table_references in references {
+        for (log, bytes) in table_references {
+            *live.entry(log.clone()).or_default() += bytes;
+        }
+    }
+    live
+}
+
+/// Appends the values of a table that are larger than a threshold to a new value log. The log is
+/// only created once the first value goes into it
+pub struct ValueLogWriter {
+    name: String,
+    path: PathBuf,
+    fs: Arc<dyn FileSystem>,
+    threshold: usize,
+    w: Option<BufWriter<Box<dyn Write + Send>>>,
+    offset: u64,
+}
+
+impl ValueLogWriter {
+    pub fn new(fs: Arc<dyn FileSystem>, dir: &Path, name: String, threshold: usize) -> Self {
+        Self {
+            path: log_path(dir, &name),
+            name,
+            fs,
+            threshold,
+            w: None,
+            offset: 0,
+        }
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Moves the value of `record` to the log when it encodes to more than the threshold
+    pub fn externalize(&mut self, record: &mut TimeStampedValue) -> DungeonResult<()> {
+        if record.pointer.is_some() || record.kind == RecordKind::Delete {
+            return Ok(());
+        }
+        let encoded =
+            to_vec(&record.value).map_err(|_| DungeonError::new("Could not parse value"))?;
+        if encoded.len() <= self.threshold {
+            return Ok(());
+        }
+        let w = match &mut self.w {
+            Some(w) => w,
+            None => self.w.insert(BufWriter::new(
+                self.fs
+                    .create(&self.path)
+                    .map_err(|_| DungeonError::new("Could not create value log"))?,
+            )),
+        };
+        w.write_all(&encoded)
+            .map_err(|_| DungeonError::new("Could not write to value log"))?;
+        record.value = Value::Null;
+        record.pointer = Some(ValuePointer {
+            log: self.name.clone(),
+            offset: self.offset,
+            length: encoded.len(),
+        });
+        self.offset += encoded.len() as u64;
+        Ok(())
+    }
+    /// Makes the log durable. Must happen before an index pointing into it is saved
+    pub fn finish(self) -> DungeonResult<()> {
+        let Some(mut w) = self.w else {
+            return Ok(());
+        };
+        w.flush()
+            .map_err(|_| DungeonError::new("Could not write to value log"))?;
+        drop(w);
+        self.fs
+            .sync(&self.path)
+            .and_then(|_| sync_parent(self.fs.as_ref(), &self.path))
+            .map_err(|_| DungeonError::new("Could not sync value log"))
+    }
+}
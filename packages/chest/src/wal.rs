@@ -0,0 +1,145 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use errors::{DungeonError, DungeonResult};
+use rmp_serde::{from_slice, to_vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    change_feed::ChangeEvent,
+    file_system::{sync_parent, FileSystem},
+    value::TimeStampedValue,
+};
+
+/// Segments of the write ahead log live here, inside the chest dir
+pub const WAL_DIR: &str = "wal";
+const WAL_EXTENSION: &str = "wal";
+
+/// A write as it was committed, with the record that goes into the memtable
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct WalEntry {
+    pub event: ChangeEvent,
+    pub record: TimeStampedValue,
+}
+
+/// Write ahead log, split in segments named after the sequence number of their first write. A
+/// new segment starts after every flush. Every commit is a frame of its encoded length followed
+/// by its entries
+#[derive(Debug)]
+pub(crate) struct Wal {
+    dir: PathBuf,
+    fs: Arc<dyn FileSystem>,
+    /// Segment commits are appended to, only created with its first commit
+    current: PathBuf,
+    /// Flushed segments kept around for `Chest::subscribe_from`
+    retention: usize,
+}
+
+impl Wal {
+    /// Starts a new segment for the writes from `next_sequence` on
+    pub fn open(
+        fs: Arc<dyn FileSystem>,
+        chest_dir: &Path,
+        next_sequence: u64,
+        retention: usize,
+    ) -> DungeonResult<Self> {
+        let dir = chest_dir.join(WAL_DIR);
+        if !fs.is_dir(&dir) {
+            fs.create_dir_all(&dir)
+                .map_err(|_| DungeonError::new("Could not create wal dir"))?;
+        }
+        let current = segment_path(&dir, next_sequence);
+        // Only a commit that never made it can be left in there, or the sequence would be past it
+        if fs.is_file(&current) {
+            fs.remove_file(&current)
+                .map_err(|_| DungeonError::new("Could not remove wal segment"))?;
+        }
+        Ok(Self {
+            current,
+            dir,
+            fs,
+            retention,
+        })
+    }
+    /// Makes `entries` durable as a single commit
+    pub fn append(&mut self, entries: &[WalEntry]) -> DungeonResult<()> {
+        let encoded =
+            to_vec(entries).map_err(|_| DungeonError::new("Could not parse wal entry"))?;
+        let mut frame = Vec::with_capacity(4 + encoded.len());
+        frame.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&encoded);
+        let created = !self.fs.is_file(&self.current);
+        self.fs
+            .append(&self.current, &frame)
+            .and_then(|_| self.fs.sync(&self.current))
+            .map_err(|_| DungeonError::new("Could not write to wal"))?;
+        if created {
+            sync_parent(self.fs.as_ref(), &self.current)
+                .map_err(|_| DungeonError::new("Could not write to wal"))?;
+        }
+        Ok(())
+    }
+    /// Moves on to a new segment once a flush made every write before `next_sequence` durable,
+    /// removing the flushed segments past the retention
+    pub fn rotate(&mut self, next_sequence: u64) -> DungeonResult<()> {
+        self.current = segment_path(&self.dir, next_sequence);
+        let segments = segments(self.fs.as_ref(), &self.dir)?;
+        let flushed: Vec<&(u64, PathBuf)> = segments
+            .iter()
+            .filter(|(first, _)| *first < next_sequence)
+            .collect();
+        let removed = flushed.len().saturating_sub(self.retention);
+        for (_, path) in &flushed[..removed] {
+            self.fs
+                .remove_file(path)
+                .map_err(|_| DungeonError::new("Could not remove wal segment"))?;
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, first_sequence: u64) -> PathBuf {
+    dir.join(first_sequence.to_string())
+        .with_extension(WAL_EXTENSION)
+}
+
+/// Segments in `dir` with the sequence number they start at, oldest first
+fn segments(fs: &dyn FileSystem, dir: &Path) -> DungeonResult<Vec<(u64, PathBuf)>> {
+    let files = fs
+        .read_dir(dir)
+        .map_err(|_| DungeonError::new("Could not read wal dir"))?;
+    let mut segments: Vec<(u64, PathBuf)> = files
+        .into_iter()
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some(WAL_EXTENSION))
+        .filter_map(|path| Some((path.file_stem()?.to_str()?.parse().ok()?, path)))
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+/// Every entry kept in the log of `chest_dir`, oldest first. Reading a segment stops at the first
+/// commit that doesn't decode, which is the one that was being written during a crash
+pub(crate) fn read_entries(fs: &dyn FileSystem, chest_dir: &Path) -> DungeonResult<Vec<WalEntry>> {
+    let dir = chest_dir.join(WAL_DIR);
+    if !fs.is_dir(&dir) {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for (_, path) in segments(fs, &dir)? {
+        let data = fs
+            .read(&path)
+            .map_err(|_| DungeonError::new("Could not read wal segment"))?;
+        let mut rest = data.as_slice();
+        while let Some(len) = rest.get(..4) {
+            let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            let Some(Ok(commit)) = rest.get(4..4 + len).map(from_slice::<Vec<WalEntry>>) else {
+                break;
+            };
+            entries.extend(commit);
+            rest = &rest[4 + len..];
+        }
+    }
+    Ok(entries)
+}
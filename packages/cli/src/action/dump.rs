@@ -0,0 +1,44 @@
+use std::{fs::File, io};
+
+use chest::{dump::DumpFormat, filter::bloom::BloomFilter, Chest};
+use clap::ValueEnum;
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+pub enum Format {
+    /// One JSON object per line
+    #[default]
+    Jsonl,
+    /// MessagePack entries written back to back
+    Msgpack,
+}
+
+impl From<Format> for DumpFormat {
+    fn from(value: Format) -> Self {
+        match value {
+            Format::Jsonl => DumpFormat::JsonLines,
+            Format::Msgpack => DumpFormat::MessagePack,
+        }
+    }
+}
+
+fn open_chest(dir: &str) -> io::Result<Chest> {
+    Chest::new(dir, 512, 24, Box::new(BloomFilter::new(100_000, 0.01))).map_err(io::Error::other)
+}
+
+pub fn export(dir: String, file: String, format: Format) -> io::Result<()> {
+    let chest = open_chest(&dir)?;
+    let exported = chest
+        .export(File::create(file)?, format.into())
+        .map_err(io::Error::other)?;
+    println!("Exported {exported} entries");
+    Ok(())
+}
+
+pub fn import(dir: String, file: String, format: Format) -> io::Result<()> {
+    let mut chest = open_chest(&dir)?;
+    let imported = chest
+        .import(File::open(file)?, format.into())
+        .map_err(io::Error::other)?;
+    println!("Imported {imported} entries");
+    Ok(())
+}
@@ -1,2 +1,5 @@
 pub mod connect;
+pub mod dump;
 pub mod query;
+pub mod repair;
+pub mod sst;
@@ -0,0 +1,8 @@
+use std::io;
+
+pub fn repair(dir: String) -> io::Result<()> {
+    let report = chest::repair::repair(&dir, &chest::options::ChestOptions::default())
+        .map_err(io::Error::other)?;
+    print!("{report}");
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use std::{io, path::Path};
+
+use chest::{inspect, options::ChestOptions};
+
+pub fn dump(path: String) -> io::Result<()> {
+    for entry in
+        inspect::dump(Path::new(&path), &ChestOptions::default()).map_err(io::Error::other)?
+    {
+        println!("{entry}");
+    }
+    Ok(())
+}
+
+pub fn stats(path: String) -> io::Result<()> {
+    let stats =
+        inspect::stats(Path::new(&path), &ChestOptions::default()).map_err(io::Error::other)?;
+    print!("{stats}");
+    if !stats.is_valid() {
+        return Err(io::Error::other("Some segments don't decode"));
+    }
+    Ok(())
+}
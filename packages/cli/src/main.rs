@@ -1,7 +1,11 @@
 use std::io;
 
-use action::{connect::connect, query};
-use clap::{command, Parser, Subcommand};
+use action::{
+    connect::connect,
+    dump::{self, Format},
+    query, repair, sst,
+};
+use clap::{Parser, Subcommand};
 
 mod action;
 
@@ -17,6 +21,35 @@ enum Action {
     Query { url: String, query: String },
     #[command(about = "Connect to a given server address")]
     Connect { url: String },
+    #[command(about = "Write every key of a local chest directory to a dump file")]
+    Export {
+        dir: String,
+        file: String,
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    #[command(about = "Load a dump file into a local chest directory")]
+    Import {
+        dir: String,
+        file: String,
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+    },
+    #[command(about = "Rebuild damaged indexes of a local chest directory")]
+    Repair { dir: String },
+    #[command(about = "Inspect a single sstable")]
+    Sst {
+        #[command(subcommand)]
+        cmd: SstAction,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum SstAction {
+    #[command(about = "List every record of an sstable")]
+    Dump { path: String },
+    #[command(about = "Summarize an sstable and verify that every record decodes")]
+    Stats { path: String },
 }
 
 #[tokio::main]
@@ -30,6 +63,13 @@ async fn main() -> io::Result<()> {
             query::query(url, query_arg).await?;
         }
         Action::Connect { url } => connect(url).await?,
+        Action::Export { dir, file, format } => dump::export(dir, file, format)?,
+        Action::Import { dir, file, format } => dump::import(dir, file, format)?,
+        Action::Repair { dir } => repair::repair(dir)?,
+        Action::Sst { cmd } => match cmd {
+            SstAction::Dump { path } => sst::dump(path)?,
+            SstAction::Stats { path } => sst::stats(path)?,
+        },
     }
     Ok(())
 }
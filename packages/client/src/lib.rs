@@ -1,4 +1,4 @@
-use std::io::{self, ErrorKind};
+use std::io;
 
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
@@ -34,8 +34,7 @@ impl<A: ToSocketAddrs> Client<A> {
             let mut r = BufReader::new(conn);
             let mut input = Vec::new();
             r.read_until(b'\n', &mut input).await?;
-            let parsed = ServerResponse::from_vec(&input)
-                .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+            let parsed = ServerResponse::from_vec(&input).map_err(io::Error::other)?;
 
             Ok(parsed)
         } else {
@@ -49,6 +48,7 @@ impl<A: ToSocketAddrs> Client<A> {
 
 impl<A: ToSocketAddrs> Drop for Client<A> {
     fn drop(&mut self) {
-        let _ = self.disconnect();
+        // Dropping the stream closes the underlying socket
+        self.conn.take();
     }
 }
@@ -1,13 +1,35 @@
 use std::{error, fmt::Display};
 
+/// What went wrong, for the errors callers handle differently from the rest
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorKind {
+    #[default]
+    Other,
+    /// The write would take the chest past its disk quota
+    QuotaExceeded,
+    /// The chest rejects writes since a flush failed, most likely on a full disk
+    Degraded,
+    /// The key is longer than the chest allows
+    KeyTooLarge,
+    /// The encoded value is larger than the chest allows
+    ValueTooLarge,
+    /// The key is empty or has characters the chest doesn't allow
+    InvalidKey,
+}
+
 #[derive(Debug)]
 pub struct DungeonError {
     pub message: String,
+    pub kind: ErrorKind,
 }
 impl DungeonError {
     pub fn new(msg: &str) -> Self {
+        Self::with_kind(ErrorKind::Other, msg)
+    }
+    pub fn with_kind(kind: ErrorKind, msg: &str) -> Self {
         Self {
             message: msg.to_owned(),
+            kind,
         }
     }
 }
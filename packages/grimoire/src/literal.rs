@@ -1,5 +1,7 @@
+use std::collections::BTreeMap;
+
 use errors::{DungeonError, DungeonResult};
-use pest::Parser;
+use pest::{iterators::Pair, Parser};
 use query::ast::Literal;
 
 use crate::parser::{GrimoireParser, Rule};
@@ -8,18 +10,17 @@ pub fn parse_literal(input: &str) -> DungeonResult<Literal> {
     let ast = GrimoireParser::parse(Rule::literal, input)
         .map_err(|_| DungeonError::new("Could not parse literal"))?
         .next()
-        .ok_or(DungeonError::new("Could not parse literal"))?
+        .ok_or(DungeonError::new("Could not parse literal"))?;
+    literal_from_pair(ast)
+}
+
+fn literal_from_pair(pair: Pair<Rule>) -> DungeonResult<Literal> {
+    let ast = pair
         .into_inner()
         .next()
         .ok_or(DungeonError::new("Could not parse literal"))?;
     match ast.as_rule() {
-        Rule::string => Ok(Literal::String(
-            ast.into_inner()
-                .next()
-                .ok_or(DungeonError::new("Could not parse string"))?
-                .as_str()
-                .to_owned(),
-        )),
+        Rule::string => Ok(Literal::String(parse_string(ast)?)),
         Rule::boolean => {
             let inner_bool = ast
                 .into_inner()
@@ -45,12 +46,67 @@ pub fn parse_literal(input: &str) -> DungeonResult<Literal> {
                 .map_err(|_| DungeonError::new("Could not parse number"))?;
             Ok(Literal::Float(parsed))
         }
+        Rule::null => Ok(Literal::Null),
+        Rule::bytes => {
+            let digits = &ast.as_str()[2..];
+            if digits.len() % 2 != 0 {
+                return Err(DungeonError::new(
+                    "Bytes must have an even amount of hex digits",
+                ));
+            }
+            let parsed = (0..digits.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| DungeonError::new("Could not parse bytes"))?;
+            Ok(Literal::Bytes(parsed))
+        }
+        Rule::timestamp => {
+            let parsed: u128 = ast
+                .into_inner()
+                .next()
+                .ok_or(DungeonError::new("Could not parse timestamp"))?
+                .as_str()
+                .parse()
+                .map_err(|_| DungeonError::new("Could not parse timestamp"))?;
+            Ok(Literal::Timestamp(parsed))
+        }
+        Rule::list => Ok(Literal::List(
+            ast.into_inner()
+                .map(literal_from_pair)
+                .collect::<DungeonResult<_>>()?,
+        )),
+        Rule::map => {
+            let mut entries = BTreeMap::new();
+            for entry in ast.into_inner() {
+                let mut inner_entry = entry.into_inner();
+                let key = inner_entry
+                    .next()
+                    .ok_or(DungeonError::new("Could not get map key"))?;
+                let value = inner_entry
+                    .next()
+                    .ok_or(DungeonError::new("Could not get map value"))?;
+                entries.insert(parse_string(key)?, literal_from_pair(value)?);
+            }
+            Ok(Literal::Map(entries))
+        }
         _ => unreachable!(),
     }
 }
 
+fn parse_string(pair: Pair<Rule>) -> DungeonResult<String> {
+    Ok(pair
+        .into_inner()
+        .next()
+        .ok_or(DungeonError::new("Could not parse string"))?
+        .as_str()
+        .to_owned())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
     use query::ast::Literal;
 
     use super::parse_literal;
@@ -77,4 +133,46 @@ mod tests {
         assert_eq!(parse_literal("10.5").unwrap(), Literal::Float(10.5));
         assert_eq!(parse_literal("0.0").unwrap(), Literal::Float(0.0));
     }
+    #[test]
+    fn test_parse_null() {
+        assert_eq!(parse_literal("null").unwrap(), Literal::Null);
+    }
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(
+            parse_literal("0xdeadbeef").unwrap(),
+            Literal::Bytes(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(parse_literal("0x").unwrap(), Literal::Bytes(vec![]));
+        assert!(parse_literal("0xabc").is_err());
+    }
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(
+            parse_literal("timestamp(1718000000000000000)").unwrap(),
+            Literal::Timestamp(1718000000000000000)
+        );
+    }
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(
+            parse_literal(r#"[1, "a", [true]]"#).unwrap(),
+            Literal::List(vec![
+                Literal::Integer(1),
+                Literal::String("a".to_owned()),
+                Literal::List(vec![Literal::Boolean(true)])
+            ])
+        );
+        assert_eq!(parse_literal("[]").unwrap(), Literal::List(vec![]));
+    }
+    #[test]
+    fn test_parse_map() {
+        assert_eq!(
+            parse_literal(r#"{"name": "John", "age": 30}"#).unwrap(),
+            Literal::Map(BTreeMap::from([
+                ("name".to_owned(), Literal::String("John".to_owned())),
+                ("age".to_owned(), Literal::Integer(30)),
+            ]))
+        );
+    }
 }
@@ -53,6 +53,8 @@ pub fn parse_statement(input: &str) -> DungeonResult<Statement> {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
     use query::ast::{DeleteStmt, Expression, Literal, SetStmt, Statement};
 
     use super::parse_statement;
@@ -78,4 +80,21 @@ mod tests {
             })
         );
     }
+    #[test]
+    fn test_parse_set_map_statement() {
+        let parsed = parse_statement(r#"set user {"tags": ["a"], "avatar": null}"#).unwrap();
+        assert_eq!(
+            parsed,
+            Statement::Set(SetStmt {
+                key: "user".to_owned(),
+                value: Expression::Literal(Literal::Map(BTreeMap::from([
+                    (
+                        "tags".to_owned(),
+                        Literal::List(vec![Literal::String("a".to_owned())])
+                    ),
+                    ("avatar".to_owned(), Literal::Null),
+                ])))
+            })
+        );
+    }
 }
@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display};
 
 #[derive(Debug, PartialEq)]
 pub enum Literal {
@@ -7,6 +7,10 @@ pub enum Literal {
     Float(f64),
     Boolean(bool),
     Null,
+    Bytes(Vec<u8>),
+    List(Vec<Literal>),
+    Map(BTreeMap<String, Literal>),
+    Timestamp(u128),
 }
 impl Display for Literal {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -16,6 +20,34 @@ impl Display for Literal {
             Literal::Float(val) => write!(f, "{val}"),
             Literal::Boolean(val) => write!(f, "{val}"),
             Literal::Null => write!(f, "null"),
+            Literal::Bytes(val) => {
+                write!(f, "0x")?;
+                for byte in val {
+                    write!(f, "{byte:02x}")?;
+                }
+                Ok(())
+            }
+            Literal::List(val) => {
+                write!(f, "[")?;
+                for (i, item) in val.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Literal::Map(val) => {
+                write!(f, "{{")?;
+                for (i, (key, item)) in val.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{key:?}: {item}")?;
+                }
+                write!(f, "}}")
+            }
+            Literal::Timestamp(val) => write!(f, "timestamp({val})"),
         }
     }
 }
@@ -1,39 +1,49 @@
 mod value;
 
-use chest::{
-    value::{TimeStampedValue, Value},
-    Chest,
-};
-use errors::DungeonResult;
+use chest::{value::Value, Chest};
+use errors::{DungeonError, DungeonResult};
 use grimoire::parse;
-use query::ast::Statement;
+use query::ast::{Expression, Statement};
 use value::value_from_query;
 
 #[cfg(test)]
 mod tests;
 
-pub fn run_query(chest: &mut Chest, query: Statement) -> DungeonResult<Value> {
+#[derive(Debug, PartialEq)]
+pub enum QueryResult {
+    Value(Value),
+    /// The statement was applied and has nothing to return
+    Ok,
+    /// The key that was read isn't set
+    NotFound,
+}
+
+fn eval_expression(chest: &Chest, expr: Expression) -> DungeonResult<Option<Value>> {
+    match expr {
+        Expression::Literal(lit) => Ok(Some(value_from_query(lit))),
+        Expression::Get(expr) => Ok(chest.get(&expr.key)?.map(|v| v.value)),
+    }
+}
+
+pub fn run_query(chest: &mut Chest, query: Statement) -> DungeonResult<QueryResult> {
     match query {
-        Statement::Expr(expr) => match expr {
-            query::ast::Expression::Literal(lit) => Ok(value_from_query(lit)),
-            query::ast::Expression::Get(expr) => {
-                let found = chest.get(&expr.key)?.map_or(Value::Invalid, |v| v.value);
-                Ok(found)
-            }
-        },
+        Statement::Expr(expr) => {
+            Ok(eval_expression(chest, expr)?.map_or(QueryResult::NotFound, QueryResult::Value))
+        }
         Statement::Set(stmt) => {
-            let value = run_query(chest, Statement::Expr(stmt.value))?;
-            chest.set(&stmt.key, TimeStampedValue::new(value))?;
-            Ok(Value::Invalid)
+            let value = eval_expression(chest, stmt.value)?
+                .ok_or(DungeonError::new("Could not find the value to set"))?;
+            chest.set(&stmt.key, value)?;
+            Ok(QueryResult::Ok)
         }
         Statement::Delete(stmt) => {
             chest.delete(&stmt.key)?;
-            Ok(Value::Invalid)
+            Ok(QueryResult::Ok)
         }
     }
 }
 
-pub fn run_statement(chest: &mut Chest, input: &str) -> DungeonResult<Value> {
+pub fn run_statement(chest: &mut Chest, input: &str) -> DungeonResult<QueryResult> {
     let parsed = parse(input)?;
     run_query(chest, parsed)
 }
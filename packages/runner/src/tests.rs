@@ -8,7 +8,7 @@ use query::ast::{DeleteStmt, Expression, GetExpr, Literal, SetStmt};
 use super::*;
 
 fn ensure_dir_exists(dir_path: &PathBuf) -> std::io::Result<()> {
-    if !(dir_path.exists() || !dir_path.is_dir()) {
+    if !dir_path.is_dir() {
         std::fs::create_dir(dir_path)?;
     }
     Ok(())
@@ -37,7 +37,7 @@ fn test_eval_literal() {
         Statement::Expr(Expression::Literal(Literal::Integer(1))),
     )
     .unwrap();
-    assert_eq!(result, Value::Integer(1));
+    assert_eq!(result, QueryResult::Value(Value::Integer(1)));
 }
 
 #[test]
@@ -65,7 +65,7 @@ fn test_insert_value() {
         })),
     )
     .unwrap();
-    assert_eq!(found, Value::Integer(1));
+    assert_eq!(found, QueryResult::Value(Value::Integer(1)));
 }
 
 #[test]
@@ -93,7 +93,7 @@ fn test_delete_value() {
         })),
     )
     .unwrap();
-    assert_eq!(found, Value::Integer(0));
+    assert_eq!(found, QueryResult::Value(Value::Integer(0)));
     run_query(
         &mut chest,
         Statement::Delete(DeleteStmt {
@@ -109,5 +109,55 @@ fn test_delete_value() {
         })),
     )
     .unwrap();
-    assert_eq!(found, Value::Invalid);
+    assert_eq!(found, QueryResult::NotFound);
+}
+
+#[test]
+fn test_run_statement_with_list() {
+    let chest_dir = get_test_tempdir();
+    let mut chest = Chest::new(
+        chest_dir.to_str().unwrap(),
+        1024,
+        8,
+        Box::new(BloomFilter::default()),
+    )
+    .unwrap();
+    run_statement(&mut chest, "set items [1, null, 0x0aff]").unwrap();
+    let found = run_statement(&mut chest, "get items").unwrap();
+    assert_eq!(
+        found,
+        QueryResult::Value(Value::List(vec![
+            Value::Integer(1),
+            Value::Null,
+            Value::Bytes(vec![0x0a, 0xff])
+        ]))
+    );
+}
+
+#[test]
+fn test_null_is_not_delete() {
+    let chest_dir = get_test_tempdir();
+    let mut chest = Chest::new(
+        chest_dir.to_str().unwrap(),
+        1024,
+        8,
+        Box::new(BloomFilter::default()),
+    )
+    .unwrap();
+    assert_eq!(
+        run_statement(&mut chest, "set avatar null").unwrap(),
+        QueryResult::Ok
+    );
+    assert_eq!(
+        run_statement(&mut chest, "get avatar").unwrap(),
+        QueryResult::Value(Value::Null)
+    );
+    assert_eq!(
+        run_statement(&mut chest, "delete avatar").unwrap(),
+        QueryResult::Ok
+    );
+    assert_eq!(
+        run_statement(&mut chest, "get avatar").unwrap(),
+        QueryResult::NotFound
+    );
 }
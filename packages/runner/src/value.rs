@@ -7,6 +7,14 @@ pub fn value_from_query(lit: Literal) -> Value {
         Literal::Integer(v) => Value::Integer(v),
         Literal::Float(v) => Value::Float(v),
         Literal::Boolean(v) => Value::Boolean(v),
-        Literal::Null => Value::Invalid,
+        Literal::Null => Value::Null,
+        Literal::Bytes(v) => Value::Bytes(v),
+        Literal::List(v) => Value::List(v.into_iter().map(value_from_query).collect()),
+        Literal::Map(v) => Value::Map(
+            v.into_iter()
+                .map(|(key, item)| (key, value_from_query(item)))
+                .collect(),
+        ),
+        Literal::Timestamp(v) => Value::Timestamp(v),
     }
 }
@@ -26,19 +26,19 @@ impl std::fmt::Display for ServerError {
 #[derive(serde::Serialize, serde::Deserialize)]
 pub enum ServerResponse {
     Value(Value),
+    /// The query was applied and has nothing to return
+    Ok,
+    /// The key that was read isn't set
+    NotFound,
     Err(ServerError),
 }
 
 impl std::fmt::Display for ServerResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ServerResponse::Value(val) => match val {
-                Value::Integer(v) => write!(f, "{v}"),
-                Value::Float(v) => write!(f, "{v}"),
-                Value::String(v) => write!(f, "{v}"),
-                Value::Boolean(v) => write!(f, "{v}"),
-                Value::Invalid => write!(f, "invalid"),
-            },
+            ServerResponse::Value(val) => write!(f, "{val}"),
+            ServerResponse::Ok => write!(f, "OK"),
+            ServerResponse::NotFound => write!(f, "not found"),
             ServerResponse::Err(err) => std::fmt::Display::fmt(&err, f),
         }
     }
@@ -1,9 +1,6 @@
-use std::{
-    io::{self, ErrorKind},
-    sync::Arc,
-};
+use std::{io, sync::Arc};
 
-use chest::{filter::bloom::BloomFilter, Chest};
+use chest::{async_chest::AsyncChest, filter::bloom::BloomFilter, options::ChestOptions, Chest};
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
     net::{TcpListener, TcpStream, ToSocketAddrs},
@@ -12,18 +9,19 @@ use tokio::{
     task::JoinHandle,
 };
 
-use runner::run_statement;
+use errors::{DungeonError, ErrorKind};
+use runner::{run_statement, QueryResult};
 use server_value::{ServerError, ServerResponse};
 
 pub struct Server {
-    chest: Arc<Mutex<Chest>>,
+    chest: AsyncChest,
     shutdown: Arc<Mutex<bool>>,
 }
 
 impl Server {
     pub fn new(chest: Chest) -> Self {
         Self {
-            chest: Arc::new(Mutex::new(chest)),
+            chest: AsyncChest::new(chest),
             shutdown: Arc::new(Mutex::new(false)),
         }
     }
@@ -46,7 +44,7 @@ impl Server {
         Ok(())
     }
     async fn listen(
-        chest: Arc<Mutex<Chest>>,
+        chest: AsyncChest,
         socket: TcpListener,
         shutdown: Arc<Mutex<bool>>,
     ) -> io::Result<()> {
@@ -58,7 +56,7 @@ impl Server {
             drop(shutdown_lock);
             let (stream, _) = socket.accept().await?;
             let chest = chest.clone();
-            let _: JoinHandle<io::Result<()>> = tokio::spawn(async move {
+            let _handle: JoinHandle<io::Result<()>> = tokio::spawn(async move {
                 handle_connection(stream, chest).await?;
                 Ok(())
             });
@@ -66,7 +64,7 @@ impl Server {
     }
 }
 
-async fn handle_connection(mut stream: TcpStream, chest: Arc<Mutex<Chest>>) -> io::Result<()> {
+async fn handle_connection(mut stream: TcpStream, chest: AsyncChest) -> io::Result<()> {
     let (r, w) = stream.split();
     let mut r = BufReader::new(r);
     let mut w = BufWriter::new(w);
@@ -74,31 +72,58 @@ async fn handle_connection(mut stream: TcpStream, chest: Arc<Mutex<Chest>>) -> i
         let mut input = String::new();
         let _ = r.read_line(&mut input).await?;
         if input.trim() == "exit" {
-            let _ = drop(stream);
+            drop(stream);
             break;
         }
 
         if !input.trim().is_empty() {
-            let mut chest_lock = chest.lock().await;
-            let result = run_statement(&mut *chest_lock, input.trim())
-                .map(|v| ServerResponse::from_value(v))
-                .unwrap_or_else(|err| ServerResponse::from_error(ServerError::new(&err.message)));
-            let writable_result = result
-                .to_vec()
-                .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+            let statement = input.trim().to_owned();
+            // Statements may flush or merge sstables, which must not block the executor
+            let result = chest
+                .run(move |chest| run_statement(chest, &statement))
+                .await
+                .map(|result| match result {
+                    QueryResult::Value(val) => ServerResponse::from_value(val),
+                    QueryResult::Ok => ServerResponse::Ok,
+                    QueryResult::NotFound => ServerResponse::NotFound,
+                })
+                .unwrap_or_else(|err| ServerResponse::from_error(server_error(err)));
+            let writable_result = result.to_vec().map_err(io::Error::other)?;
             w.write_all(&writable_result).await?;
-            w.write("\n".as_bytes()).await?;
+            w.write_all("\n".as_bytes()).await?;
             w.flush().await?;
         }
     }
     Ok(())
 }
 
+fn server_error(err: DungeonError) -> ServerError {
+    match err.kind {
+        ErrorKind::QuotaExceeded | ErrorKind::Degraded => {
+            ServerError::new(&format!("Server is read only: {}", err.message))
+        }
+        ErrorKind::Other
+        | ErrorKind::KeyTooLarge
+        | ErrorKind::ValueTooLarge
+        | ErrorKind::InvalidKey => ServerError::new(&err.message),
+    }
+}
+
 impl Default for Server {
     fn default() -> Self {
         Self::new(
-            Chest::new(".chest", 512, 24, Box::new(BloomFilter::new(1024, 1.0)))
-                .expect("Could not create chest"),
+            Chest::with_options(
+                ".chest",
+                ChestOptions {
+                    flush_size: 512,
+                    max_sstable_count: 24,
+                    // Merges would otherwise hold every connection up
+                    background_compaction: true,
+                    ..Default::default()
+                },
+                Box::new(BloomFilter::new(1024, 1.0)),
+            )
+            .expect("Could not create chest"),
         )
     }
 }